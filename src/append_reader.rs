@@ -1,11 +1,20 @@
-use std::{collections::HashSet, path::Path, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
 
 use anyhow::{Context, Result};
+use bytes::Bytes;
 use flume::Sender;
 use futures_util::TryStreamExt;
-use pmtiles::{AsyncPmTilesReader, MmapBackend};
+use pmtiles::{AsyncPmTilesReader, Header, MmapBackend};
 
-use crate::{tile::Tile, writer::WriteTileMsg};
+use crate::{
+    tile::Tile,
+    validators::{SourceStore, ValidatorStore},
+    writer::WriteTileMsg,
+};
 
 pub type PmTilesReader = Arc<AsyncPmTilesReader<MmapBackend>>;
 
@@ -19,7 +28,7 @@ impl AppendReader {
             .await
             .with_context(|| {
                 format!(
-                    "Failed to create PMTiles reader when trying to append to {}. Does the file exist?",
+                    "Failed to open PMTiles archive {}. Does the file exist?",
                     input.display()
                 )
             })?;
@@ -28,6 +37,65 @@ impl AppendReader {
         })
     }
 
+    pub fn header(&self) -> &Header {
+        self.reader.get_header()
+    }
+
+    pub async fn metadata(&self) -> Result<String> {
+        Ok(self.reader.get_metadata().await?)
+    }
+
+    /// Returns the archive metadata as a JSON object. Metadata that isn't a JSON object is ignored.
+    pub async fn metadata_json(&self) -> Result<serde_json::Map<String, serde_json::Value>> {
+        match serde_json::from_str(&self.metadata().await?) {
+            Ok(serde_json::Value::Object(map)) => Ok(map),
+            _ => Ok(serde_json::Map::new()),
+        }
+    }
+
+    /// Returns every tile in the archive along with its stored (possibly compressed) size, in tile ID order.
+    /// The sizes come from the directory, so no tile data is read.
+    pub async fn get_tile_sizes(&self) -> Result<Vec<(Tile, usize)>> {
+        let entries = self
+            .reader
+            .clone()
+            .entries()
+            .try_collect::<Vec<_>>()
+            .await?;
+        let sizes = entries
+            .iter()
+            .flat_map(|e| e.iter_coords().map(|c| (c.into(), e.length as usize)))
+            .collect();
+        Ok(sizes)
+    }
+
+    /// Returns where the data of every tile is stored, as an offset into the tile data section and
+    /// a length. Tiles in a run, and tiles deduplicated by the writer, share the same data.
+    pub async fn get_tile_entries(&self) -> Result<HashMap<Tile, (u64, u32)>> {
+        let entries = self
+            .reader
+            .clone()
+            .entries()
+            .try_collect::<Vec<_>>()
+            .await?;
+        let tiles = entries
+            .iter()
+            .flat_map(|e| e.iter_coords().map(|c| (c.into(), (e.offset, e.length))))
+            .collect();
+        Ok(tiles)
+    }
+
+    /// Returns the decompressed data of a single tile.
+    pub async fn get_tile(&self, tile: &Tile) -> Result<Option<Vec<u8>>> {
+        let data = self.reader.get_tile_decompressed(tile.to_id()).await?;
+        Ok(data.map(|d| d.to_vec()))
+    }
+
+    /// Returns the data of a single tile as stored, possibly compressed.
+    pub async fn get_raw_tile(&self, tile: &Tile) -> Result<Option<Bytes>> {
+        Ok(self.reader.get_tile(tile.to_id()).await?)
+    }
+
     pub async fn get_tiles(&self) -> Result<HashSet<Tile>> {
         let entries = self
             .reader
@@ -43,7 +111,13 @@ impl AppendReader {
         Ok(tiles)
     }
 
-    pub async fn read_tiles(&self, output_tx: Sender<WriteTileMsg>) -> Result<usize> {
+    /// Sends every existing tile to the writer, along with its validators from `store` if any.
+    pub async fn read_tiles(
+        &self,
+        output_tx: Sender<WriteTileMsg>,
+        validators: &ValidatorStore,
+        sources: &SourceStore,
+    ) -> Result<usize> {
         let entries = self
             .reader
             .clone()
@@ -54,9 +128,12 @@ impl AppendReader {
         let mut last_index = 0;
         for (index, tile) in tile_iter.enumerate() {
             let data = self.reader.clone().get_tile(tile).await?;
+            let tile: Tile = tile.into();
             let msg = WriteTileMsg {
                 index,
-                tile: tile.into(),
+                validators: validators.get(&tile).cloned(),
+                source: sources.get(&tile),
+                tile,
                 data: data.map(|d| d.to_vec()),
             };
             output_tx.send(msg)?;
@@ -1,16 +1,225 @@
-use clap::Parser;
-use std::path::PathBuf;
+use anyhow::{Result, bail};
+use clap::{
+    ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+    error::ErrorKind,
+};
+use serde::Serialize;
+use std::{
+    ffi::OsString,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::JobConfig,
+    dem::DemEncoding,
+    metadata::parse_metadata_pair,
+    mock_server::MockFormat,
+    progress::ProgressMode,
+    raster::{Resampling, TranscodeFormat},
+};
 
 #[derive(Debug, Parser)]
 #[command(name = "tile-download-tool")]
 #[command(about = "Download XYZ tiles into a PMTiles archive")]
 pub struct Cli {
-    /// The URL template for tiles (e.g., https://example.com/tileset/{z}/{x}/{y}.png)
-    pub url: String,
+    #[command(subcommand)]
+    pub command: Command,
+
+    #[command(flatten)]
+    pub logging: LoggingArgs,
+}
+
+/// Options for log output, accepted before or after the subcommand
+#[derive(Debug, Args)]
+pub struct LoggingArgs {
+    /// Log more details: -v for every tile request, -vv for everything
+    #[arg(long, short, action = ArgAction::Count, global = true, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Log less: -q for warnings and errors only, -qq for errors only
+    #[arg(long, short, action = ArgAction::Count, global = true)]
+    pub quiet: u8,
+
+    /// Also write the log to this file. The file always includes the details of every tile request.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Download tiles into a PMTiles archive. This is the default when no subcommand is given.
+    Download(Box<DownloadArgs>),
+
+    /// Print the header, metadata and tile statistics of a PMTiles archive
+    Inspect(InspectArgs),
+
+    /// Check that a PMTiles archive contains every tile of a selection, and that the tiles are readable
+    Verify(VerifyArgs),
+
+    /// Merge multiple PMTiles archives into one
+    Merge(MergeArgs),
+
+    /// Copy a subset of a PMTiles archive, selected by zoom, bounding box or polygon, to a new archive
+    Extract(ExtractArgs),
+
+    /// Compare two PMTiles archives tile by tile
+    Diff(DiffArgs),
+
+    /// Serve PMTiles archives over HTTP with a map viewer, to preview them in a browser
+    Serve(ServeArgs),
+
+    /// Serve synthetic tiles for any z/x/y, with configurable latency and failures, for testing
+    #[command(hide = true)]
+    MockServer(MockServerArgs),
+}
+
+impl Cli {
+    /// Parses the command line. For backwards compatibility, arguments that don't start
+    /// with a subcommand (e.g. `tile-download-tool <url> <output>`) are treated as `download`.
+    /// With `--config`, the command line is parsed once for every selected job in the file.
+    pub fn parse_with_default() -> Result<Vec<Self>> {
+        Self::parse_with_default_from(std::env::args_os().collect())
+    }
+
+    pub fn parse_with_default_from(mut args: Vec<OsString>) -> Result<Vec<Self>> {
+        // Logging options may come before the subcommand
+        let mut pos = 1;
+        while let Some(arg) = args.get(pos).and_then(|a| a.to_str()) {
+            let is_logging_flag = arg.strip_prefix('-').is_some_and(|flags| {
+                !flags.is_empty() && flags.chars().all(|c| c == 'v' || c == 'q')
+            }) || matches!(arg, "--verbose" | "--quiet")
+                || arg.starts_with("--log-file=");
+            if is_logging_flag {
+                pos += 1;
+            } else if arg == "--log-file" {
+                pos += 2;
+            } else {
+                break;
+            }
+        }
+        let first = args.get(pos).and_then(|a| a.to_str()).unwrap_or("");
+        let cmd = Self::command();
+        let is_subcommand = cmd
+            .get_subcommands()
+            .any(|s| s.get_name() == first || s.get_all_aliases().any(|a| a == first));
+        let is_top_level_flag = matches!(first, "" | "-h" | "--help" | "-V" | "--version");
+        if !is_subcommand && !is_top_level_flag && first != "help" {
+            args.insert(pos, OsString::from("download"));
+        }
+
+        let Some(config) = option_values(&args, "--config").pop() else {
+            let cli = Self::parse_from(args);
+            cli.validate()?;
+            return Ok(vec![cli]);
+        };
+        let config = JobConfig::load(Path::new(&config))?;
+        let path = config.path().to_path_buf();
+        let names = option_values(&args, "--job")
+            .iter()
+            .flat_map(|names| names.split(','))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        config
+            .select(&names)?
+            .into_iter()
+            .map(|job| {
+                let (cmd, positionals) = job.apply(&path, Self::command())?;
+                let matches = match cmd.clone().try_get_matches_from(&args) {
+                    // The URLs and output come from the file unless they are on the command line
+                    Err(e)
+                        if e.kind() == ErrorKind::MissingRequiredArgument
+                            && !positionals.is_empty() =>
+                    {
+                        let positionals = positionals.iter().map(OsString::from);
+                        cmd.try_get_matches_from(args.iter().cloned().chain(positionals))
+                    }
+                    result => result,
+                };
+                let matches = match matches {
+                    Ok(matches) => matches,
+                    Err(e) => match job.explain(&path, &e) {
+                        Some(err) => return Err(err),
+                        None => e.exit(),
+                    },
+                };
+                let mut cli = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+                if let Command::Download(args) = &mut cli.command {
+                    args.job = job.name.into_iter().collect();
+                }
+                cli.validate()?;
+                Ok(cli)
+            })
+            .collect()
+    }
+
+    /// Checks combinations of options that clap can't express, before anything is downloaded.
+    fn validate(&self) -> Result<()> {
+        let Command::Download(args) = &self.command else {
+            return Ok(());
+        };
+        if args.composite && args.urls.len() < 2 {
+            bail!("--composite blends several URLs, but only one was given.");
+        }
+        if args.opacity.len() > args.urls.len() {
+            bail!(
+                "{} opacities were given for {} URLs. Give at most one opacity per URL.",
+                args.opacity.len(),
+                args.urls.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The values of an option in raw arguments, given as `--option value` or `--option=value`.
+fn option_values(args: &[OsString], option: &str) -> Vec<String> {
+    let args = args.iter().filter_map(|a| a.to_str()).collect::<Vec<_>>();
+    let mut values = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if *arg == option {
+            values.extend(args.get(i + 1).map(|v| v.to_string()));
+        } else if let Some(value) = arg.strip_prefix(option).and_then(|a| a.strip_prefix('=')) {
+            values.push(value.to_string());
+        }
+    }
+    values
+}
+
+#[derive(Debug, Args, Serialize)]
+pub struct DownloadArgs {
+    /// Where to get the tiles: a URL template (e.g., https://example.com/tileset/{z}/{x}/{y}.png), a local
+    /// directory (tiles/ or tiles/{z}/{x}/{y}.png), or an MBTiles or PMTiles file. If more than one is
+    /// given, they are tried in order for each tile until one has it.
+    #[arg(required = true, num_args = 1..)]
+    pub urls: Vec<String>,
 
     /// Output PMTiles file
     pub output: PathBuf,
 
+    /// Read options from a TOML or YAML job file. Options given on the command line override the file.
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Only run these jobs of the config file, in this order. May be repeated or comma-separated.
+    /// Defaults to every job.
+    #[arg(long, value_delimiter = ',', requires = "config")]
+    pub job: Vec<String>,
+
+    /// Instead of falling back between the URLs, download each tile from all of them and blend them
+    /// together, with the first URL at the bottom. Only for raster tiles.
+    /// Blending would mix the encoded heights of elevation tiles, so it can't be used with --dem.
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["tile_size", "fill_from_parent", "dem", "dem_convert"]
+    )]
+    pub composite: bool,
+
+    /// The opacity (0-1) of each URL when compositing, in the same order as the URLs. Defaults to 1.
+    #[arg(long, value_delimiter = ',', requires = "composite", value_parser = parse_opacity)]
+    pub opacity: Vec<f32>,
+
     /// Delete the output file if it already exists instead of throwing an error
     #[arg(long, short, default_value_t = false)]
     pub force: bool,
@@ -20,6 +229,12 @@ pub struct Cli {
     #[arg(long, short, default_value_t = false)]
     pub append: bool,
 
+    /// Re-download every tile of an existing PMTiles file, replacing it. Conditional requests are sent
+    /// using the ETag / Last-Modified values recorded by the previous run, and tiles that haven't
+    /// changed are reused from the existing file.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["append", "tile_list", "bbox"])]
+    pub refresh: bool,
+
     /// Name of the tileset (for PMTiles metadata)
     #[arg(long, short = 'n')]
     pub name: Option<String>,
@@ -32,6 +247,117 @@ pub struct Cli {
     #[arg(long, short = 'A')]
     pub attribution: Option<String>,
 
+    /// A JSON file containing an object of additional metadata fields (e.g. type, format, legend)
+    #[arg(long)]
+    pub metadata_file: Option<PathBuf>,
+
+    /// Additional metadata field in the format "key=value". Values are parsed as JSON if possible,
+    /// otherwise they are used as strings. May be repeated; overrides --metadata-file.
+    #[arg(long = "metadata", value_name = "KEY=VALUE", value_parser = parse_metadata_pair)]
+    pub metadata: Vec<(String, serde_json::Value)>,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub stats: TilestatsArgs,
+
+    /// Only download the maximum zoom level, and generate the lower zoom levels (down to the minimum zoom)
+    /// locally by downsampling. Only for raster tilesets.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["append", "refresh"])]
+    pub build_overviews: bool,
+
+    /// Generate tiles the source doesn't have (404 / 204 responses) from the nearest ancestor tile it does
+    /// have, by cropping and scaling up raster tiles, or rescaling vector tiles.
+    #[arg(long, default_value_t = false)]
+    pub fill_from_parent: bool,
+
+    /// The size of the output raster tiles. With 512, each output tile at zoom z is stitched together from
+    /// the four 256px source tiles at zoom z+1, so the zoom levels selected refer to the source and the
+    /// archive's zoom levels are one lower.
+    #[arg(long, default_value_t = 256, value_parser = parse_tile_size, conflicts_with_all = ["refresh", "fill_from_parent"])]
+    pub tile_size: u32,
+
+    /// Only keep these layers of vector tiles. May be repeated or comma-separated.
+    #[arg(long, value_delimiter = ',')]
+    pub keep_layers: Vec<String>,
+
+    /// Remove these layers from vector tiles. May be repeated or comma-separated.
+    #[arg(long, value_delimiter = ',')]
+    pub drop_layers: Vec<String>,
+
+    /// Remove these properties from vector tiles, either `key` for every layer or `layer/key` for a single
+    /// layer. May be repeated or comma-separated.
+    #[arg(long, value_delimiter = ',')]
+    pub drop_properties: Vec<String>,
+
+    /// The tiles are elevation (DEM) tiles in this encoding. Heights are checked for plausibility, the
+    /// `encoding` and `tileSize` metadata are set for use as a MapLibre raster-dem source, and lossy
+    /// formats are refused.
+    #[arg(long, value_enum)]
+    pub dem: Option<DemEncoding>,
+
+    /// Convert elevation tiles to this encoding. Converted tiles are written as PNG (or WebP with `--transcode webp`).
+    #[arg(long, value_enum, requires = "dem")]
+    pub dem_convert: Option<DemEncoding>,
+
+    /// Re-encode downloaded raster tiles in this format before writing them
+    #[arg(long, value_enum)]
+    pub transcode: Option<TranscodeFormat>,
+
+    /// The JPEG quality (1-100) used with `--transcode jpeg`. WebP tiles are always encoded losslessly.
+    #[arg(long, default_value_t = 85, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// The resampling filter used when generating raster tiles locally
+    #[arg(long, value_enum, default_value_t = Resampling::Bilinear)]
+    pub resampling: Resampling,
+
+    #[command(flatten)]
+    pub selection: TileSelectionArgs,
+
+    /// How to report progress: interactive bars, a plain status line every few seconds, or one JSON
+    /// object per line for every event
+    #[arg(long, value_enum, default_value_t = ProgressMode::Bars)]
+    pub progress: ProgressMode,
+
+    /// Limit the download concurrency
+    #[arg(long, default_value_t = 10)]
+    pub concurrency: usize,
+
+    /// Write a JSON summary of the run to this file: per-zoom tile counts and bytes, HTTP statuses,
+    /// retries, latency, wall time and the effective options
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Serve Prometheus metrics at http://[address]/metrics while downloading, for example 127.0.0.1:9100
+    #[arg(long, value_name = "ADDRESS")]
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+impl DownloadArgs {
+    /// The options for downloading `urls` into `output`, with the command line's defaults for
+    /// everything else.
+    pub fn new(urls: Vec<String>, output: PathBuf) -> Self {
+        // Placeholders for the required positional arguments, replaced below
+        let matches = Self::augment_args(clap::Command::new("download"))
+            .get_matches_from(["download", "url", "output"]);
+        let mut args = Self::from_arg_matches(&matches).expect("the defaults are valid");
+        args.urls = urls;
+        args.output = output;
+        args
+    }
+}
+
+/// Options for the statistics written to the metadata of an archive
+#[derive(Debug, Args, Serialize)]
+pub struct TilestatsArgs {
+    /// Also write a `tilestats` block (per-layer attribute statistics) for vector tilesets
+    #[arg(long, default_value_t = false)]
+    pub tilestats: bool,
+}
+
+/// Options that select which tiles to download (or expect in an archive)
+#[derive(Debug, Args, Serialize)]
+pub struct TileSelectionArgs {
     /// Maximum zoom level to download
     #[arg(long, short = 'z', default_value_t = 14)]
     pub maximum_zoom: u8,
@@ -51,8 +377,290 @@ pub struct Cli {
     /// A bounding box in the format "min_x,min_y,max_x,max_y" to filter the downloaded tiles by
     #[arg(long, short)]
     pub bbox: Option<String>,
+}
 
-    /// Limit the download concurrency
-    #[arg(long, default_value_t = 10)]
-    pub concurrency: usize,
+#[cfg(test)]
+impl TileSelectionArgs {
+    /// Every tile between two zoom levels.
+    pub fn zoom_range(minimum_zoom: u8, maximum_zoom: u8) -> Self {
+        Self {
+            maximum_zoom,
+            minimum_zoom,
+            tile_list: None,
+            tile_list_format: "z/x/y".to_string(),
+            bbox: None,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct InspectArgs {
+    /// The PMTiles archive to inspect
+    pub input: PathBuf,
+
+    /// Print the result as JSON instead of a human-readable table
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+
+    // If any of these are given, tiles missing from the archive compared to this selection are counted
+    #[command(flatten)]
+    pub selection: Option<TileSelectionArgs>,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// The PMTiles archive to verify
+    pub input: PathBuf,
+
+    #[command(flatten)]
+    pub selection: TileSelectionArgs,
+
+    /// The URL template (or directory, MBTiles or PMTiles file) the archive was downloaded from.
+    /// Required for --sample
+    #[arg(long)]
+    pub url: Option<String>,
+
+    /// Re-download this many randomly chosen tiles from --url and check that they match the archive
+    #[arg(long, default_value_t = 0, requires = "url")]
+    pub sample: usize,
+
+    /// Write the missing tiles to this file in "z/x/y" format, for use with `--tile-list` and `--append`
+    #[arg(long)]
+    pub missing_list: Option<PathBuf>,
+
+    /// Print the result as JSON instead of a human-readable summary
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct MergeArgs {
+    /// The PMTiles archives to merge
+    #[arg(required = true, num_args = 2..)]
+    pub inputs: Vec<PathBuf>,
+
+    /// Output PMTiles file
+    #[arg(long, short)]
+    pub output: PathBuf,
+
+    /// Delete the output file if it already exists instead of throwing an error
+    #[arg(long, short, default_value_t = false)]
+    pub force: bool,
+
+    /// Which tile to keep when a tile exists in more than one input
+    #[arg(long, value_enum, default_value_t = MergePolicy::First)]
+    pub policy: MergePolicy,
+
+    #[command(flatten)]
+    pub stats: TilestatsArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct ExtractArgs {
+    /// The PMTiles archive to extract tiles from
+    pub input: PathBuf,
+
+    /// Output PMTiles file
+    pub output: PathBuf,
+
+    /// Read options from a TOML or YAML job file. Options given on the command line override the file.
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Only run these jobs of the config file, in this order. May be repeated or comma-separated.
+    /// Defaults to every job.
+    #[arg(long, value_delimiter = ',', requires = "config")]
+    pub job: Vec<String>,
+
+    /// Delete the output file if it already exists instead of throwing an error
+    #[arg(long, short, default_value_t = false)]
+    pub force: bool,
+
+    /// Maximum zoom level to extract (defaults to the maximum zoom of the input)
+    #[arg(long, short = 'z')]
+    pub maximum_zoom: Option<u8>,
+
+    /// Minimum zoom level to extract (defaults to the minimum zoom of the input)
+    #[arg(long, short = 'Z')]
+    pub minimum_zoom: Option<u8>,
+
+    /// A bounding box in the format "min_x,min_y,max_x,max_y" to filter the extracted tiles by
+    #[arg(long, short)]
+    pub bbox: Option<String>,
+
+    /// A GeoJSON file containing a Polygon or MultiPolygon to filter the extracted tiles by
+    #[arg(long)]
+    pub polygon: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub stats: TilestatsArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// The old PMTiles archive
+    pub old: PathBuf,
+
+    /// The new PMTiles archive
+    pub new: PathBuf,
+
+    /// Write the added and modified tiles (from the new archive) to this PMTiles file
+    #[arg(long)]
+    pub patch: Option<PathBuf>,
+
+    /// Write the removed tiles to this file in "z/x/y" format
+    #[arg(long)]
+    pub removed_list: Option<PathBuf>,
+
+    /// Delete the patch file if it already exists instead of throwing an error
+    #[arg(long, short, default_value_t = false)]
+    pub force: bool,
+
+    /// Print the result as JSON instead of a human-readable table
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// The PMTiles archives to serve. With more than one, each is served under /{file name}/
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: SocketAddr,
+}
+
+#[derive(Debug, Args)]
+pub struct MockServerArgs {
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1:8081")]
+    pub listen: SocketAddr,
+
+    /// The format of the generated tiles
+    #[arg(long, value_enum, default_value_t = MockFormat::Png)]
+    pub format: MockFormat,
+
+    /// Delay every tile response by this many milliseconds
+    #[arg(long, default_value_t = 0)]
+    pub latency_ms: u64,
+
+    /// The fraction of tiles whose first requests fail, picked deterministically by coordinates
+    #[arg(long, default_value_t = 0.0)]
+    pub error_rate: f64,
+
+    /// How many requests for a failing tile fail before it is served
+    #[arg(long, default_value_t = 1)]
+    pub error_attempts: u32,
+
+    /// The status code of failed requests
+    #[arg(long, default_value_t = 503)]
+    pub error_status: u16,
+
+    /// Answer a burst of 429 Too Many Requests once every this many requests
+    #[arg(long)]
+    pub rate_limit_every: Option<u64>,
+
+    /// The number of requests in each 429 burst
+    #[arg(long, default_value_t = 1, requires = "rate_limit_every")]
+    pub rate_limit_burst: u64,
+
+    /// Send Retry-After with this many seconds on 429 and 503 responses
+    #[arg(long)]
+    pub retry_after: Option<u64>,
+
+    /// Serve blank tiles (transparent PNGs or empty vector tiles) for tiles centered in this
+    /// bounding box (min_x,min_y,max_x,max_y). Can be repeated.
+    #[arg(long)]
+    pub blank: Vec<String>,
+
+    /// Answer 404 for tiles above this zoom level
+    #[arg(long)]
+    pub max_zoom: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum MergePolicy {
+    /// The tile from the first input that has it
+    First,
+    /// The tile from the last input that has it
+    Last,
+    /// The tile with the most bytes
+    Largest,
+    /// The tile from the most recently downloaded input
+    Newest,
+}
+
+fn parse_opacity(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(opacity) if (0.0..=1.0).contains(&opacity) => Ok(opacity),
+        _ => Err("the opacity must be a number between 0 and 1".to_string()),
+    }
+}
+
+fn parse_tile_size(s: &str) -> Result<u32, String> {
+    match s {
+        "256" => Ok(256),
+        "512" => Ok(512),
+        _ => Err("the tile size must be 256 or 512".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Vec<Cli>> {
+        Cli::parse_with_default_from(args.iter().map(OsString::from).collect())
+    }
+
+    #[test]
+    fn checks_opacities_when_parsing() {
+        let urls = [
+            "tile-download-tool",
+            "a/{z}/{x}/{y}.png",
+            "b/{z}/{x}/{y}.png",
+        ];
+        let with = |opacity: &str| {
+            let mut args = urls.to_vec();
+            args.extend(["out.pmtiles", "--composite", "--opacity", opacity]);
+            parse(&args)
+        };
+        assert!(with("1,0.5").is_ok());
+        assert!(with("1,0.5,0.2").is_err());
+        assert!(
+            Cli::try_parse_from([
+                "tile-download-tool",
+                "download",
+                urls[1],
+                urls[2],
+                "out.pmtiles",
+                "--composite",
+                "--opacity",
+                "1.5"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn checks_composite_options_when_parsing() {
+        let a = "a/{z}/{x}/{y}.png";
+        let b = "b/{z}/{x}/{y}.png";
+        assert!(parse(&["tile-download-tool", a, b, "out.pmtiles", "--composite"]).is_ok());
+        assert!(parse(&["tile-download-tool", a, "out.pmtiles", "--composite"]).is_err());
+        // --dem-convert requires --dem, so this rules out both
+        let dem = [
+            "tile-download-tool",
+            "download",
+            a,
+            b,
+            "out.pmtiles",
+            "--composite",
+            "--dem",
+            "terrarium",
+        ];
+        assert!(Cli::try_parse_from(dem).is_err());
+    }
 }
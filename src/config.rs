@@ -0,0 +1,314 @@
+//! Job configuration files for `download --config`, in TOML or YAML.
+//!
+//! The keys are the names of the download options (`maximum_zoom` or `maximum-zoom`, `bbox`, ...),
+//! including the positional `urls` and `output`. Top-level keys apply to every job in the optional
+//! `jobs` array of tables, and each job may set a `name` to be selected with `--job`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::{ArgAction, Command, error::ContextKind, error::ContextValue, error::ErrorKind};
+use serde_json::Value;
+
+/// Options that only make sense on the command line
+const COMMAND_LINE_ONLY: &[&str] = &["config", "job"];
+
+pub struct JobConfig {
+    path: PathBuf,
+    jobs: Vec<Job>,
+}
+
+pub struct Job {
+    pub name: Option<String>,
+    /// (key in the file, option id, value)
+    options: Vec<(String, String, Value)>,
+}
+
+impl JobConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let value: Value = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&text)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            _ => bail!(
+                "Unknown config file format {}: expected .toml, .yaml or .yml",
+                path.display()
+            ),
+        };
+        let Value::Object(mut top) = value else {
+            bail!("{}: expected a table of options", path.display());
+        };
+
+        let jobs = top.remove("jobs");
+        let shared = top
+            .into_iter()
+            .map(|(key, value)| (key.clone(), option_id(&key), value))
+            .collect::<Vec<_>>();
+        let jobs = match jobs {
+            None => vec![Job {
+                name: None,
+                options: shared,
+            }],
+            Some(Value::Array(jobs)) => jobs
+                .into_iter()
+                .enumerate()
+                .map(|(i, job)| {
+                    let Value::Object(mut job) = job else {
+                        bail!("{}: jobs[{}]: expected a table", path.display(), i);
+                    };
+                    let name = match job.remove("name") {
+                        None => None,
+                        Some(Value::String(name)) => Some(name),
+                        Some(_) => bail!("{}: jobs[{}].name: expected a string", path.display(), i),
+                    };
+                    let mut options = shared.clone();
+                    for (key, value) in job {
+                        let id = option_id(&key);
+                        options.retain(|(_, shared_id, _)| *shared_id != id);
+                        options.push((format!("jobs[{}].{}", i, key), id, value));
+                    }
+                    Ok(Job { name, options })
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => bail!("{}: jobs: expected an array of tables", path.display()),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            jobs,
+        })
+    }
+
+    /// The jobs with these names, or every job if no names are given.
+    pub fn select(self, names: &[String]) -> Result<Vec<Job>> {
+        if names.is_empty() {
+            return Ok(self.jobs);
+        }
+        let mut jobs = self.jobs;
+        names
+            .iter()
+            .map(|name| {
+                let Some(i) = jobs.iter().position(|j| j.name.as_ref() == Some(name)) else {
+                    let available = jobs
+                        .iter()
+                        .filter_map(|j| j.name.clone())
+                        .collect::<Vec<_>>();
+                    bail!(
+                        "{}: no job named {} (available: {})",
+                        self.path.display(),
+                        name,
+                        available.join(", ")
+                    );
+                };
+                Ok(jobs.remove(i))
+            })
+            .collect()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Job {
+    /// Sets the options of the job as the defaults of the `download` subcommand, so options given on
+    /// the command line override them. Positional arguments can't have defaults; they are returned
+    /// instead, to be appended to the command line when it has none.
+    pub fn apply(&self, path: &Path, cmd: Command) -> Result<(Command, Vec<String>)> {
+        let download = cmd
+            .find_subcommand("download")
+            .expect("download subcommand exists");
+        let mut defaults = Vec::new();
+        let mut positionals = (Vec::new(), Vec::new());
+        for (key, id, value) in &self.options {
+            let Some(arg) = download.get_arguments().find(|a| a.get_id() == id.as_str()) else {
+                bail!("{}: {}: unknown option", path.display(), key);
+            };
+            if COMMAND_LINE_ONLY.contains(&id.as_str()) {
+                bail!(
+                    "{}: {}: can only be given on the command line",
+                    path.display(),
+                    key
+                );
+            }
+            let values =
+                arg_values(arg, value).with_context(|| format!("{}: {}", path.display(), key))?;
+            match id.as_str() {
+                "urls" => positionals.0 = values,
+                "output" => positionals.1 = values,
+                _ => defaults.push((id.clone(), values)),
+            }
+        }
+
+        let cmd = cmd.mut_subcommand("download", |mut download| {
+            for (id, values) in defaults {
+                download = download.mut_arg(id, |arg| arg.default_values(values));
+            }
+            download
+        });
+        let positionals = if positionals.0.is_empty() || positionals.1.is_empty() {
+            Vec::new()
+        } else {
+            [positionals.0, positionals.1].concat()
+        };
+        Ok((cmd, positionals))
+    }
+
+    /// If `e` is about an invalid value that came from this job, an error pointing at its key.
+    pub fn explain(&self, path: &Path, e: &clap::Error) -> Option<anyhow::Error> {
+        if !matches!(
+            e.kind(),
+            ErrorKind::InvalidValue | ErrorKind::ValueValidation
+        ) {
+            return None;
+        }
+        let (Some(ContextValue::String(arg)), Some(ContextValue::String(value))) = (
+            e.get(ContextKind::InvalidArg),
+            e.get(ContextKind::InvalidValue),
+        ) else {
+            return None;
+        };
+        let (key, _, _) = self.options.iter().find(|(_, id, v)| {
+            arg.starts_with(&format!("--{} ", id.replace('_', "-")))
+                && scalars(v).iter().any(|s| s == value)
+        })?;
+        let message = e.to_string();
+        let message = message.lines().next().unwrap_or_default();
+        Some(anyhow::anyhow!(
+            "{}: {}: {}",
+            path.display(),
+            key,
+            message.trim_start_matches("error: ")
+        ))
+    }
+}
+
+/// Keys may be written like the options (`maximum-zoom`) or the field names (`maximum_zoom`).
+fn option_id(key: &str) -> String {
+    key.replace('-', "_")
+}
+
+/// The command line values for a value in the file.
+fn arg_values(arg: &clap::Arg, value: &Value) -> Result<Vec<String>> {
+    let multiple = matches!(arg.get_action(), ArgAction::Append);
+    match value {
+        // `metadata = { license = "CC-BY-4.0" }`
+        Value::Object(fields) if arg.get_id() == "metadata" => Ok(fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect()),
+        // `bbox = [139.5, 35.5, 140.0, 36.0]`
+        Value::Array(_) if !multiple => Ok(vec![scalars(value).join(",")]),
+        Value::Array(_) | Value::String(_) | Value::Number(_) | Value::Bool(_) => {
+            let values = scalars(value);
+            if values.len() != value.as_array().map_or(1, Vec::len) {
+                bail!("expected a string, number or boolean");
+            }
+            Ok(values)
+        }
+        _ => bail!("expected a string, number, boolean or array"),
+    }
+}
+
+/// The scalar values of a value, or of each element of an array, as strings.
+fn scalars(value: &Value) -> Vec<String> {
+    let scalar = |v: &Value| match v {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    };
+    match value {
+        Value::Array(items) => items.iter().filter_map(scalar).collect(),
+        v => scalar(v).into_iter().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{Cli, Command as CliCommand, DownloadArgs};
+    use std::ffi::OsString;
+
+    fn write(name: &str, contents: &str) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(name), contents).unwrap();
+        dir
+    }
+
+    fn parse(dir: &Path, name: &str, args: &[&str]) -> Result<Vec<DownloadArgs>> {
+        let config = dir.join(name);
+        let args = ["tile-download-tool", "--config", config.to_str().unwrap()]
+            .iter()
+            .chain(args)
+            .map(OsString::from)
+            .collect();
+        Ok(Cli::parse_with_default_from(args)?
+            .into_iter()
+            .map(|cli| match cli.command {
+                CliCommand::Download(args) => *args,
+                _ => unreachable!(),
+            })
+            .collect())
+    }
+
+    #[test]
+    fn reads_jobs_with_shared_options() {
+        let dir = write(
+            "jobs.toml",
+            r#"
+            urls = ["https://example.com/{z}/{x}/{y}.png"]
+            concurrency = 4
+            bbox = [139.5, 35.5, 140.0, 36.0]
+
+            [[jobs]]
+            name = "low"
+            output = "low.pmtiles"
+            maximum-zoom = 8
+
+            [[jobs]]
+            name = "high"
+            output = "high.pmtiles"
+            concurrency = 16
+            metadata = { license = "CC-BY-4.0" }
+            "#,
+        );
+        let jobs = parse(dir.path(), "jobs.toml", &["--concurrency", "2"]).unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].job, vec!["low".to_string()]);
+        assert_eq!(jobs[0].output, PathBuf::from("low.pmtiles"));
+        assert_eq!(jobs[0].selection.maximum_zoom, 8);
+        assert_eq!(
+            jobs[0].selection.bbox.as_deref(),
+            Some("139.5,35.5,140.0,36.0")
+        );
+        // The command line overrides the file
+        assert_eq!(jobs[0].concurrency, 2);
+        assert_eq!(jobs[1].concurrency, 2);
+        assert_eq!(
+            jobs[1].metadata,
+            vec![("license".to_string(), Value::from("CC-BY-4.0"))]
+        );
+    }
+
+    #[test]
+    fn points_at_invalid_keys() {
+        let dir = write(
+            "job.yaml",
+            "urls: https://example.com/{z}/{x}/{y}.png\noutput: out.pmtiles\nconcurrency: lots\n",
+        );
+        let err = parse(dir.path(), "job.yaml", &[]).unwrap_err().to_string();
+        assert!(err.ends_with("job.yaml: concurrency: invalid value 'lots' for '--concurrency <CONCURRENCY>': invalid digit found in string"), "{}", err);
+
+        let dir = write("job.toml", "[[jobs]]\nmaximum_zom = 3\n");
+        let err = parse(dir.path(), "job.toml", &[]).unwrap_err().to_string();
+        assert!(
+            err.ends_with("job.toml: jobs[0].maximum_zom: unknown option"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! Elevation (DEM) tiles that encode heights in the RGB channels of a raster tile.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use image::{DynamicImage, Rgba};
+use pmtiles::TileType;
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use tracing::warn;
+
+use crate::{
+    progress::ProgressSender, raster, tile::Tile, tile_format::sniff_raster,
+    tile_processor::TileProcessor,
+};
+
+/// Heights outside this range (in metres) are treated as decoding errors.
+const VALID_HEIGHTS: std::ops::RangeInclusive<f64> = -12000.0..=9000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DemEncoding {
+    /// Mapzen / AWS Terrarium
+    Terrarium,
+    /// Mapbox Terrain-RGB
+    Mapbox,
+    /// GSI (Geospatial Information Authority of Japan) NumPNG
+    Gsi,
+}
+
+impl DemEncoding {
+    /// The value of the `encoding` metadata key, as used by MapLibre `raster-dem` sources.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DemEncoding::Terrarium => "terrarium",
+            DemEncoding::Mapbox => "mapbox",
+            DemEncoding::Gsi => "gsi",
+        }
+    }
+
+    /// The metadata a MapLibre `raster-dem` source needs to decode the tiles. MapLibre has no GSI
+    /// encoding, so it is described as a custom one, which reads negative heights as above 83km.
+    pub fn metadata(&self) -> Map<String, Value> {
+        let value = match self {
+            DemEncoding::Gsi => json!({
+                "encoding": "custom",
+                "redFactor": 655.36,
+                "greenFactor": 2.56,
+                "blueFactor": 0.01,
+                "baseShift": 0.0,
+            }),
+            _ => json!({ "encoding": self.name() }),
+        };
+        let Value::Object(map) = value else {
+            unreachable!()
+        };
+        map
+    }
+
+    /// Decodes the height of a pixel in metres. Returns None for "no data" pixels.
+    pub fn decode(&self, Rgba([r, g, b, a]): Rgba<u8>) -> Option<f64> {
+        if a == 0 {
+            return None;
+        }
+        let rgb = (r as u32) << 16 | (g as u32) << 8 | b as u32;
+        match self {
+            DemEncoding::Terrarium => {
+                Some(r as f64 * 256.0 + g as f64 + b as f64 / 256.0 - 32768.0)
+            }
+            DemEncoding::Mapbox => Some(-10000.0 + rgb as f64 * 0.1),
+            DemEncoding::Gsi => match rgb {
+                0x800000 => None,
+                x if x < 0x800000 => Some(x as f64 * 0.01),
+                x => Some((x as f64 - 16777216.0) * 0.01),
+            },
+        }
+    }
+
+    /// Encodes a height in metres. "No data" is encoded as 0m, except for GSI which has a value for it.
+    pub fn encode(&self, height: Option<f64>) -> Rgba<u8> {
+        let rgb = |v: u32| Rgba([(v >> 16) as u8, (v >> 8) as u8, v as u8, 255]);
+        match (self, height) {
+            (DemEncoding::Gsi, None) => rgb(0x800000),
+            (DemEncoding::Gsi, Some(h)) => rgb(((h * 100.0).round() as i32 as u32) & 0xffffff),
+            (DemEncoding::Terrarium, h) => {
+                let v = h.unwrap_or(0.0) + 32768.0;
+                Rgba([
+                    (v / 256.0).floor() as u8,
+                    (v.floor() % 256.0) as u8,
+                    ((v - v.floor()) * 256.0).floor() as u8,
+                    255,
+                ])
+            }
+            (DemEncoding::Mapbox, h) => rgb(((h.unwrap_or(0.0) + 10000.0) * 10.0).round() as u32),
+        }
+    }
+}
+
+/// Validates the heights in downloaded elevation tiles, optionally converting them to another encoding.
+pub struct DemProcessor {
+    source: DemEncoding,
+    target: DemEncoding,
+    invalid_tiles: AtomicU64,
+}
+
+impl DemProcessor {
+    pub fn new(source: DemEncoding, target: Option<DemEncoding>) -> Self {
+        Self {
+            source,
+            target: target.unwrap_or(source),
+            invalid_tiles: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of tiles with heights outside the plausible range.
+    pub fn invalid_tiles(&self) -> u64 {
+        self.invalid_tiles.load(Ordering::Relaxed)
+    }
+}
+
+impl TileProcessor for DemProcessor {
+    fn process(
+        &self,
+        tile: &Tile,
+        data: Vec<u8>,
+        _progress_tx: &ProgressSender,
+    ) -> Result<Vec<u8>> {
+        if sniff_raster(&data) == Some(TileType::Jpeg) {
+            bail!("Elevation tile {tile} is JPEG, whose lossy compression corrupts the heights.");
+        }
+        let mut img = raster::decode(&data)?.to_rgba8();
+        let mut invalid = 0usize;
+        for pixel in img.pixels_mut() {
+            let height = self.source.decode(*pixel);
+            if height.is_some_and(|h| !VALID_HEIGHTS.contains(&h)) {
+                invalid += 1;
+            }
+            if self.target != self.source {
+                *pixel = self.target.encode(height);
+            }
+        }
+        if invalid > 0 {
+            self.invalid_tiles.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                %tile,
+                "{} pixels have implausible {} heights. Is --dem set correctly?",
+                invalid,
+                self.source.name()
+            );
+        }
+        if self.target == self.source {
+            return Ok(data);
+        }
+        // Converted tiles are always written as PNG so the heights survive exactly
+        raster::encode(&DynamicImage::ImageRgba8(img), TileType::Png)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_heights() {
+        for encoding in [
+            DemEncoding::Terrarium,
+            DemEncoding::Mapbox,
+            DemEncoding::Gsi,
+        ] {
+            for height in [-420.5, 0.0, 3776.2] {
+                let decoded = encoding.decode(encoding.encode(Some(height))).unwrap();
+                assert!(
+                    (decoded - height).abs() < 0.1,
+                    "{:?}: {} became {}",
+                    encoding,
+                    height,
+                    decoded
+                );
+            }
+        }
+        assert_eq!(DemEncoding::Gsi.decode(DemEncoding::Gsi.encode(None)), None);
+    }
+
+    #[test]
+    fn decodes_known_values() {
+        assert_eq!(
+            DemEncoding::Terrarium.decode(Rgba([128, 0, 0, 255])),
+            Some(0.0)
+        );
+        assert_eq!(
+            DemEncoding::Mapbox.decode(Rgba([1, 134, 160, 255])),
+            Some(0.0)
+        );
+        assert_eq!(DemEncoding::Gsi.decode(Rgba([0, 0, 100, 255])), Some(1.0));
+        assert_eq!(
+            DemEncoding::Gsi.decode(Rgba([255, 255, 156, 255])),
+            Some(-1.0)
+        );
+    }
+
+    #[test]
+    fn describes_gsi_as_a_custom_encoding() {
+        let metadata = DemEncoding::Gsi.metadata();
+        assert_eq!(metadata["encoding"], "custom");
+        let [white, green, red] = [[255, 255, 255], [0, 1, 0], [1, 0, 0]].map(|rgb| {
+            let [r, g, b] = rgb.map(|c| c as f64);
+            r * metadata["redFactor"].as_f64().unwrap()
+                + g * metadata["greenFactor"].as_f64().unwrap()
+                + b * metadata["blueFactor"].as_f64().unwrap()
+                - metadata["baseShift"].as_f64().unwrap()
+        });
+        assert!((white - 167772.15).abs() < 1e-6);
+        assert!((green - 2.56).abs() < 1e-9);
+        assert!((red - 655.36).abs() < 1e-9);
+        assert_eq!(DemEncoding::Terrarium.metadata()["encoding"], "terrarium");
+    }
+
+    #[test]
+    fn rejects_jpeg_tiles() {
+        let (progress_tx, _progress_rx) = flume::unbounded();
+        let processor = DemProcessor::new(DemEncoding::Terrarium, None);
+        let err = processor
+            .process(
+                &Tile::new(0, 0, 0),
+                vec![0xff, 0xd8, 0xff, 0xe0],
+                &progress_tx,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("JPEG"));
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+use pmtiles::Compression;
+use serde::{Serialize, Serializer};
+
+use crate::{
+    append_reader::AppendReader,
+    cli::DiffArgs,
+    metadata::Metadata,
+    tile::Tile,
+    tile_list::TileList,
+    writer::{WriteTileMsg, Writer, tile_type_to_str, write_archive},
+};
+
+#[derive(Default, Serialize)]
+struct ZoomDiff {
+    added: u64,
+    removed: u64,
+    modified: u64,
+    unchanged: u64,
+}
+
+#[derive(Serialize)]
+struct DiffReport {
+    zooms: BTreeMap<u8, ZoomDiff>,
+    #[serde(serialize_with = "tile_strings")]
+    added: Vec<Tile>,
+    #[serde(serialize_with = "tile_strings")]
+    removed: Vec<Tile>,
+    #[serde(serialize_with = "tile_strings")]
+    modified: Vec<Tile>,
+}
+
+fn tile_strings<S: Serializer>(tiles: &[Tile], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(tiles.iter().map(|t| t.to_string()))
+}
+
+/// The directory entries of an archive.
+struct Entries<'a> {
+    reader: &'a AppendReader,
+    /// Offset and length of every tile's data
+    entries: HashMap<Tile, (u64, u32)>,
+}
+
+impl<'a> Entries<'a> {
+    async fn new(reader: &'a AppendReader) -> Result<Self> {
+        Ok(Self {
+            reader,
+            entries: reader.get_tile_entries().await?,
+        })
+    }
+
+    fn is_uncompressed(&self) -> bool {
+        self.reader.header().tile_compression == Compression::None
+    }
+}
+
+pub async fn run(args: DiffArgs) -> Result<()> {
+    let old = AppendReader::new(&args.old).await?;
+    let new = AppendReader::new(&args.new).await?;
+    let report = diff(&old, &new).await?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report_json(&report)?)?);
+    } else {
+        print_diff(&report.zooms);
+    }
+
+    if let Some(path) = &args.removed_list {
+        write_tile_list(path, &report.removed)?;
+    }
+
+    if let Some(patch) = &args.patch {
+        let mut changed = report.added;
+        changed.extend(report.modified);
+        if changed.is_empty() {
+            println!(
+                "No added or modified tiles; not writing {}.",
+                patch.display()
+            );
+        } else {
+            write_patch(&args, patch, &new, changed).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The `--json` output.
+fn report_json(report: &DiffReport) -> Result<serde_json::Value> {
+    Ok(serde_json::to_value(report)?)
+}
+
+fn write_tile_list(path: &Path, tiles: &[Tile]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for tile in tiles {
+        writeln!(out, "{}", tile)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Compares the directories of two archives, and the data of the tiles that are in both. When
+/// neither archive compresses its tiles, entries with different lengths are modified without
+/// reading them.
+async fn diff(old: &AppendReader, new: &AppendReader) -> Result<DiffReport> {
+    let old = Entries::new(old).await?;
+    let new = Entries::new(new).await?;
+    let compare_lengths = old.is_uncompressed() && new.is_uncompressed();
+    // Whether the data at (old offset, new offset) is the same, so data shared by a run of tiles
+    // is only compared once
+    let mut compared: HashMap<(u64, u64), bool> = HashMap::new();
+
+    let mut report = DiffReport {
+        zooms: BTreeMap::new(),
+        added: Vec::new(),
+        removed: Vec::new(),
+        modified: Vec::new(),
+    };
+    let mut removed = old
+        .entries
+        .keys()
+        .filter(|t| !new.entries.contains_key(t))
+        .cloned()
+        .collect::<Vec<_>>();
+    removed.sort_by_key(|t| t.to_id());
+    for tile in &removed {
+        report.zooms.entry(tile.z()).or_default().removed += 1;
+    }
+    report.removed = removed;
+
+    let mut new_sorted = new.entries.keys().cloned().collect::<Vec<_>>();
+    new_sorted.sort_by_key(|t| t.to_id());
+    for tile in new_sorted {
+        let zoom = report.zooms.entry(tile.z()).or_default();
+        let Some(&(old_offset, old_length)) = old.entries.get(&tile) else {
+            zoom.added += 1;
+            report.added.push(tile);
+            continue;
+        };
+        let (new_offset, new_length) = new.entries[&tile];
+        let modified = if compare_lengths && old_length != new_length {
+            true
+        } else if let Some(&same) = compared.get(&(old_offset, new_offset)) {
+            !same
+        } else {
+            let same = old.reader.get_tile(&tile).await? == new.reader.get_tile(&tile).await?;
+            compared.insert((old_offset, new_offset), same);
+            !same
+        };
+        if modified {
+            zoom.modified += 1;
+            report.modified.push(tile);
+        } else {
+            zoom.unchanged += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Writes the changed tiles from the new archive to a patch archive.
+async fn write_patch(
+    args: &DiffArgs,
+    patch: &Path,
+    new: &AppendReader,
+    changed: Vec<Tile>,
+) -> Result<()> {
+    let tile_type = new.header().tile_type;
+    let mut metadata = new.metadata_json().await?;
+    metadata.insert(
+        "patch_of".to_string(),
+        serde_json::json!({
+            "old": args.old.display().to_string(),
+            "new": args.new.display().to_string(),
+        }),
+    );
+    let metadata = Metadata::from_json(metadata);
+
+    let tile_list = TileList::from_tiles(changed);
+    let expected_tiles = tile_list.tiles.len() as u64;
+    write_archive(
+        expected_tiles,
+        |progress_tx| {
+            Writer::new(
+                patch.to_path_buf(),
+                args.force,
+                tile_type_to_str(tile_type)?,
+                metadata,
+                tile_list.meta,
+                false,
+                progress_tx,
+            )
+        },
+        |tile_tx, _progress_tx| async move {
+            for (index, tile) in tile_list.tiles.into_iter().enumerate() {
+                let data = new.get_tile(&tile).await?;
+                let msg = WriteTileMsg {
+                    index,
+                    tile,
+                    data,
+                    validators: None,
+                    source: None,
+                };
+                tile_tx.send_async(msg).await?;
+            }
+            Ok(())
+        },
+    )
+    .await
+}
+
+fn print_diff(zooms: &BTreeMap<u8, ZoomDiff>) {
+    println!(
+        "{:>4} {:>11} {:>11} {:>11} {:>11}",
+        "Zoom", "Added", "Removed", "Modified", "Unchanged"
+    );
+    let mut total = ZoomDiff::default();
+    for (z, d) in zooms {
+        println!(
+            "{:>4} {:>11} {:>11} {:>11} {:>11}",
+            z, d.added, d.removed, d.modified, d.unchanged
+        );
+        total.added += d.added;
+        total.removed += d.removed;
+        total.modified += d.modified;
+        total.unchanged += d.unchanged;
+    }
+    println!(
+        "{:>4} {:>11} {:>11} {:>11} {:>11}",
+        "All", total.added, total.removed, total.modified, total.unchanged
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::write_tiles;
+
+    fn tiles(tiles: &[((u8, u32, u32), &str)]) -> Vec<(Tile, Vec<u8>)> {
+        tiles
+            .iter()
+            .map(|((z, x, y), data)| (Tile::new(*z, *x, *y), data.as_bytes().to_vec()))
+            .collect()
+    }
+
+    fn strings(tiles: &[Tile]) -> Vec<String> {
+        tiles.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn finds_added_removed_and_modified_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old_path, new_path) = (
+            dir.path().join("old.pmtiles"),
+            dir.path().join("new.pmtiles"),
+        );
+        let old = tiles(&[
+            ((0, 0, 0), "root"),
+            ((1, 0, 0), "sea"),
+            ((1, 0, 1), "sea"),
+            ((1, 1, 1), "land"),
+            ((1, 1, 0), "gone"),
+        ]);
+        let new = tiles(&[
+            ((0, 0, 0), "root"),
+            ((1, 0, 0), "sea"),
+            // Same length, different data
+            ((1, 0, 1), "sky"),
+            ((1, 1, 1), "island"),
+            ((2, 0, 0), "new"),
+        ]);
+        write_tiles(&old_path, "png", old).unwrap();
+        write_tiles(&new_path, "png", new).unwrap();
+
+        let report = diff(
+            &AppendReader::new(&old_path).await.unwrap(),
+            &AppendReader::new(&new_path).await.unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(strings(&report.added), ["2/0/0"]);
+        assert_eq!(strings(&report.removed), ["1/1/0"]);
+        assert_eq!(strings(&report.modified), ["1/0/1", "1/1/1"]);
+        let json = report_json(&report).unwrap();
+        assert_eq!(json["modified"], serde_json::json!(["1/0/1", "1/1/1"]));
+        assert_eq!(
+            json["zooms"]["1"],
+            serde_json::json!({"added": 0, "removed": 1, "modified": 2, "unchanged": 1})
+        );
+
+        let (patch, removed_list) = (
+            dir.path().join("patch.pmtiles"),
+            dir.path().join("removed.txt"),
+        );
+        write_tile_list(&removed_list, &report.removed).unwrap();
+        assert_eq!(std::fs::read_to_string(removed_list).unwrap(), "1/1/0\n");
+        let args = DiffArgs {
+            old: old_path,
+            new: new_path.clone(),
+            patch: Some(patch.clone()),
+            removed_list: None,
+            force: false,
+            json: false,
+        };
+        let mut changed = report.added;
+        changed.extend(report.modified);
+        let new = AppendReader::new(&new_path).await.unwrap();
+        write_patch(&args, &patch, &new, changed).await.unwrap();
+        let patch = AppendReader::new(&patch).await.unwrap();
+        let mut patched = patch
+            .get_tiles()
+            .await
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>();
+        patched.sort_by_key(|t| t.to_id());
+        assert_eq!(strings(&patched), ["1/0/1", "1/1/1", "2/0/0"]);
+        assert_eq!(
+            patch.get_tile(&Tile::new(1, 1, 1)).await.unwrap(),
+            Some(b"island".to_vec())
+        );
+    }
+}
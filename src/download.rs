@@ -0,0 +1,735 @@
+//! Downloading tiles into a PMTiles archive, the `download` subcommand.
+
+use anyhow::{Context, Result, bail};
+use std::{path::PathBuf, sync::Arc};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    append_reader::AppendReader,
+    cli::DownloadArgs,
+    dem::DemProcessor,
+    downloader::Downloader,
+    metadata::Metadata,
+    metrics::{Metrics, serve as serve_metrics},
+    overviews::OverviewBuilder,
+    overzoom::ParentFiller,
+    progress::{Progress, ProgressListener, ProgressMode, ProgressMsg},
+    raster::{Resampling, TranscodeFormat, Transcoder},
+    refresh::Refresh,
+    report::Report,
+    tile_list::TileList,
+    tile_source::{TileSource, open_source},
+    validators::{SourceStore, ValidatorStore},
+    vector_filter::VectorFilter,
+    writer::{Writer, str_to_tile_type, tile_type_to_str},
+};
+
+/// Runs the `download` subcommand; Ctrl-C cancels the download.
+pub async fn run(cli: DownloadArgs) -> Result<()> {
+    let cancel = CancellationToken::new();
+    let job = DownloadJob::from_args(cli).with_cancellation(cancel.clone());
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Ctrl-C received; cancelling downloads and finalizing...");
+            cancel.cancel();
+        }
+    });
+    job.run().await
+}
+
+/// Downloads tiles from XYZ URL templates into a PMTiles archive.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use tile_download_tool::{CancellationToken, DownloadJob, progress::ProgressMsg};
+///
+/// let cancel = CancellationToken::new();
+/// DownloadJob::new(["https://example.com/{z}/{x}/{y}.png"], "tiles.pmtiles")
+///     .with_zoom(0, 10)
+///     .with_bbox([139.5, 35.5, 140.0, 36.0])
+///     .with_force()
+///     .on_progress(|msg: &ProgressMsg| {
+///         if let ProgressMsg::Written(tile) = msg {
+///             println!("{}", tile);
+///         }
+///     })
+///     .with_cancellation(cancel.clone())
+///     .run()
+///     .await
+/// # }
+/// ```
+pub struct DownloadJob {
+    args: DownloadArgs,
+    sources: Vec<Arc<dyn TileSource>>,
+    listeners: Vec<Arc<dyn ProgressListener>>,
+    cancel: CancellationToken,
+}
+
+impl DownloadJob {
+    /// A job downloading from `urls`, tried in order for each tile, into `output`. The other options
+    /// have the same defaults as on the command line, except that no progress is printed.
+    pub fn new<S: Into<String>>(
+        urls: impl IntoIterator<Item = S>,
+        output: impl Into<PathBuf>,
+    ) -> Self {
+        let mut args = DownloadArgs::new(urls.into_iter().map(Into::into).collect(), output.into());
+        args.progress = ProgressMode::Hidden;
+        Self::from_args(args)
+    }
+
+    /// A job with every option set, as parsed from the command line.
+    pub fn from_args(args: DownloadArgs) -> Self {
+        Self {
+            args,
+            sources: Vec::new(),
+            listeners: Vec::new(),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// The options of the job, to set the ones without a builder method.
+    pub fn args_mut(&mut self) -> &mut DownloadArgs {
+        &mut self.args
+    }
+
+    /// Downloads from `source` instead of the URLs. May be repeated; sources are tried in order for
+    /// each tile until one has it.
+    pub fn with_source(mut self, source: Arc<dyn TileSource>) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    /// Downloads zoom levels `minimum` to `maximum`.
+    pub fn with_zoom(mut self, minimum: u8, maximum: u8) -> Self {
+        self.args.selection.minimum_zoom = minimum;
+        self.args.selection.maximum_zoom = maximum;
+        self
+    }
+
+    /// Only downloads the tiles intersecting `[min_x, min_y, max_x, max_y]`, in degrees.
+    pub fn with_bbox(mut self, bbox: [f64; 4]) -> Self {
+        let bbox = bbox.map(|c| c.to_string());
+        self.args.selection.bbox = Some(bbox.join(","));
+        self
+    }
+
+    /// Only downloads the tiles listed in a file, in a format like `z/x/y`.
+    pub fn with_tile_list(mut self, path: impl Into<String>, format: impl Into<String>) -> Self {
+        self.args.selection.tile_list = Some(path.into());
+        self.args.selection.tile_list_format = format.into();
+        self
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.args.concurrency = concurrency;
+        self
+    }
+
+    /// Replaces the output file if it already exists.
+    pub fn with_force(mut self) -> Self {
+        self.args.force = true;
+        self
+    }
+
+    /// Only downloads the tiles missing from an existing output file.
+    pub fn with_append(mut self) -> Self {
+        self.args.append = true;
+        self
+    }
+
+    pub fn with_progress_mode(mut self, mode: ProgressMode) -> Self {
+        self.args.progress = mode;
+        self
+    }
+
+    /// Passes every progress event to `listener`.
+    pub fn with_listener(mut self, listener: Arc<dyn ProgressListener>) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Calls `callback` with every progress event, from a blocking thread.
+    pub fn on_progress(self, callback: impl Fn(&ProgressMsg) + Send + Sync + 'static) -> Self {
+        self.with_listener(Arc::new(callback))
+    }
+
+    /// Stops downloading when `cancel` is cancelled. The tiles downloaded so far are written, and
+    /// the archive is finalized.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let Self {
+            mut args,
+            sources,
+            listeners,
+            cancel,
+        } = self;
+        if !sources.is_empty() {
+            // Recorded in the archive's provenance metadata
+            args.urls = sources.iter().map(|s| s.name()).collect();
+        }
+        // A failed tile cancels the rest of this job, but not the caller's token
+        download(args, sources, listeners, cancel.child_token()).await
+    }
+}
+
+async fn download(
+    mut cli: DownloadArgs,
+    mut sources: Vec<Arc<dyn TileSource>>,
+    listeners: Vec<Arc<dyn ProgressListener>>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    if cli.urls.is_empty() {
+        bail!("At least one tile URL is required.");
+    }
+    if let Some(job) = cli.job.first() {
+        info!("Running job {} -> {}", job, cli.output.display());
+    }
+    // The channel for progress updates
+    let (progress_tx, progress_rx) = flume::bounded(4096);
+    if sources.is_empty() {
+        for url in &cli.urls {
+            sources.push(open_source(url, Some(&progress_tx)).await?);
+        }
+    }
+    let report = cli.report.clone().map(|path| Arc::new(Report::new(path)));
+    let mut refresh = None;
+    let mut tile_list = if cli.refresh {
+        info!("Reading existing tiles from {}...", cli.output.display());
+        let reader = AppendReader::new(&cli.output).await?;
+        let validators = ValidatorStore::load(&cli.output)?;
+        let sources = SourceStore::load(&cli.output)?;
+        let tile_list = TileList::from_tiles(reader.get_tiles().await?.into_iter().collect());
+        info!(
+            "Refreshing {} existing tiles ({} with stored ETag / Last-Modified values)...",
+            tile_list.tiles.len(),
+            validators.len()
+        );
+        refresh = Some(Arc::new(Refresh::new(reader, validators, sources)));
+        // --refresh replaces the existing file
+        cli.force = true;
+        tile_list
+    } else {
+        let selection = &cli.selection;
+        if let Some(tile_list_path) = &selection.tile_list {
+            info!("Parsing tile list from {}...", &tile_list_path);
+        } else {
+            info!(
+                "Downloading all tiles from zoom {} to {}...",
+                selection.minimum_zoom, selection.maximum_zoom
+            );
+        }
+        if let Some(bbox_str) = &selection.bbox {
+            info!("Filtering tiles by bounding box {}...", bbox_str);
+        }
+        for source in &sources {
+            let hints = source.hints();
+            if let Some(max_zoom) = hints.maximum_zoom
+                && selection.maximum_zoom > max_zoom
+            {
+                warn!("{} only has tiles up to zoom {}.", source.name(), max_zoom);
+            }
+            if let Some(min_zoom) = hints.minimum_zoom
+                && selection.minimum_zoom < min_zoom
+            {
+                warn!("{} only has tiles from zoom {}.", source.name(), min_zoom);
+            }
+        }
+        TileList::from_selection(selection)?
+    };
+
+    let inferred_ext = sources[0]
+        .hints()
+        .format
+        .unwrap_or_else(|| "png".to_string());
+    str_to_tile_type(&inferred_ext).context("Cannot tell the tile format of the source")?;
+    let filters_vector_tiles = !cli.keep_layers.is_empty()
+        || !cli.drop_layers.is_empty()
+        || !cli.drop_properties.is_empty();
+    if filters_vector_tiles && inferred_ext != "mvt" {
+        bail!("--keep-layers, --drop-layers and --drop-properties only work with vector tiles.");
+    }
+    if inferred_ext == "mvt" {
+        if cli.build_overviews {
+            bail!("--build-overviews only works with raster tiles.");
+        }
+        if cli.transcode.is_some() {
+            bail!("--transcode only works with raster tiles.");
+        }
+        if cli.tile_size == 512 {
+            bail!("--tile-size 512 only works with raster tiles.");
+        }
+        if cli.dem.is_some() {
+            bail!("--dem only works with raster tiles.");
+        }
+        if cli.composite {
+            bail!("--composite only works with raster tiles.");
+        }
+    }
+
+    if cli.dem.is_some() {
+        if matches!(inferred_ext.as_str(), "jpg" | "jpeg")
+            || matches!(cli.transcode, Some(TranscodeFormat::Jpeg))
+        {
+            bail!("Elevation tiles must be stored in a lossless format, not JPEG.");
+        }
+        if !matches!(cli.resampling, Resampling::Nearest) {
+            // Interpolating the encoded RGB values would produce garbage heights
+            info!("Using nearest-neighbour resampling for elevation tiles.");
+            cli.resampling = Resampling::Nearest;
+        }
+    }
+
+    if cli.tile_size == 512 {
+        info!("Assembling 512px tiles from the 256px source tiles one zoom level below...");
+        tile_list.shift_to_parents();
+    }
+
+    // The zoom levels of the archive, which differ from the selection with --tile-size 512
+    let overview_min_zoom = tile_list.meta.min_zoom;
+    // With --build-overviews, the archive still has every zoom level of the selection
+    let mut archive_meta = None;
+    if cli.build_overviews {
+        let max_zoom = tile_list.meta.max_zoom;
+        archive_meta = Some(tile_list.meta.clone());
+        info!(
+            "Only downloading zoom {}; lower zoom levels will be generated locally.",
+            max_zoom
+        );
+        tile_list.filter_zooms(max_zoom, max_zoom);
+    }
+
+    let expected_tile_len = tile_list.tiles.len();
+    info!(
+        "Expected number of tiles to download: {}",
+        expected_tile_len
+    );
+
+    let append_reader = if cli.append {
+        info!("Reading existing tiles from {}...", cli.output.display());
+        let append_reader = AppendReader::new(&cli.output).await?;
+        let existing_tiles = append_reader.get_tiles().await?;
+        tile_list.remove_existing(&existing_tiles);
+        info!(
+            "Skipping {} tiles already present in the existing PMTiles file.",
+            existing_tiles.len()
+        );
+        // --append implies --force
+        cli.force = true;
+        Some(append_reader)
+    } else {
+        None
+    };
+
+    // Bind before anything is written, so a busy port fails fast
+    let (metrics, _metrics_server) = match cli.metrics_listen {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to listen for metrics on {}", addr))?;
+            info!(
+                "Serving metrics at http://{}/metrics",
+                listener.local_addr()?
+            );
+            let metrics = Arc::new(Metrics::new(cli.concurrency));
+            let server = serve_metrics(listener, metrics.clone());
+            (Some(metrics), Some(server))
+        }
+        None => (None, None),
+    };
+
+    let mut js = JoinSet::new();
+    // Create a channel for downloaded tile data
+    // ballpark estimate, one tile is 100KB -- at 4096 tiles, that gives us ~400MB inflight, max
+    // Writing isn't hard so this is a worst case scenario
+    let (tile_tx, tile_rx) = flume::bounded(4096);
+
+    let metadata = Metadata::new(&cli, expected_tile_len)?;
+    let transcoder = cli
+        .transcode
+        .map(|format| Arc::new(Transcoder::new(format, cli.quality)));
+    let dem = cli
+        .dem
+        .map(|encoding| Arc::new(DemProcessor::new(encoding, cli.dem_convert)));
+    // Transcoded and converted tiles are written in the target format, not the one the source serves
+    let ext = match &transcoder {
+        Some(transcoder) => tile_type_to_str(transcoder.tile_type())?,
+        None if cli.dem_convert.is_some_and(|e| Some(e) != cli.dem) => "png",
+        None => &inferred_ext,
+    };
+    if cli.build_overviews && !matches!(ext, "png" | "jpg" | "jpeg" | "webp") {
+        bail!("--build-overviews only works with PNG, JPEG or WebP tiles.");
+    }
+    let mut writer = Writer::new(
+        cli.output.clone(),
+        cli.force,
+        ext,
+        metadata,
+        archive_meta.clone().unwrap_or(tile_list.meta),
+        cli.stats.tilestats,
+        progress_tx.clone(),
+    )?;
+    if report.is_some() {
+        writer = writer.with_duplicate_detection();
+    }
+    let mut progress = Progress::new(expected_tile_len as u64, cli.progress);
+    if let Some(metrics) = metrics {
+        progress = progress.with_listener(metrics);
+    }
+    if let Some(report) = &report {
+        progress = progress.with_listener(report.clone());
+    }
+    for listener in listeners {
+        progress = progress.with_listener(listener);
+    }
+    let mut downloader = Downloader::new(
+        sources.clone(),
+        tile_list.tiles,
+        cli.concurrency,
+        progress_tx.clone(),
+        cancel.clone(),
+    );
+    if let Some(refresh) = &refresh {
+        downloader = downloader.with_refresh(refresh.clone());
+    }
+    let parent_filler = cli
+        .fill_from_parent
+        .then(|| Arc::new(ParentFiller::new(sources[0].clone(), cli.resampling)));
+    if let Some(parent_filler) = &parent_filler {
+        downloader = downloader.with_parent_filler(parent_filler.clone());
+    }
+    if let Some(dem) = &dem {
+        downloader = downloader.with_processor(dem.clone());
+    }
+    if filters_vector_tiles {
+        downloader = downloader.with_processor(Arc::new(VectorFilter::new(
+            &cli.keep_layers,
+            &cli.drop_layers,
+            &cli.drop_properties,
+        )));
+    }
+    if let Some(transcoder) = transcoder {
+        downloader = downloader.with_processor(transcoder);
+    }
+    if cli.tile_size == 512 {
+        downloader = downloader.with_tile_assembly();
+    }
+    if cli.composite {
+        // Blended tiles are encoded in the format of the first source
+        downloader =
+            downloader.with_composite(cli.opacity.clone(), str_to_tile_type(&inferred_ext)?);
+    }
+    let source_stats = downloader.source_stats();
+    let writer = writer.with_tile_counts({
+        let source_stats = source_stats.clone();
+        move || source_stats.tile_counts()
+    });
+
+    // Overviews are built from the downloaded tiles on their way to the writer
+    let tile_rx = match &archive_meta {
+        Some(meta) => {
+            let overviews = OverviewBuilder::new(
+                &cli.output,
+                overview_min_zoom,
+                meta.max_zoom,
+                cli.resampling,
+                cli.concurrency,
+                cancel.clone(),
+            )?;
+            let (overview_tx, overview_rx) = flume::bounded(4096);
+            js.spawn(async move { overviews.run(tile_rx, overview_tx).await });
+            overview_rx
+        }
+        None => tile_rx,
+    };
+
+    // we start the writer and progress first so that they are ready to receive tiles
+    js.spawn_blocking(move || writer.write(tile_rx));
+    js.spawn_blocking(move || progress.run(progress_rx));
+
+    // preload existing tiles if appending
+    let mut download_start_idx = 0usize;
+    if let Some(ar) = append_reader {
+        let tile_tx = tile_tx.clone();
+        let validators = ValidatorStore::load(&cli.output)?;
+        // Keep the sources of the existing tiles, so the sources sidecar covers the whole archive
+        let sources = SourceStore::load(&cli.output)?;
+        download_start_idx = ar.read_tiles(tile_tx, &validators, &sources).await?;
+    }
+    // start the downloader after all existing tiles have been queued, so that indexing is correct
+    js.spawn(async move { downloader.download(download_start_idx, tile_tx).await });
+
+    // Wait for all tasks to finish; if any failed, remember the first error
+    let mut first_err: Option<anyhow::Error> = None;
+    while let Some(res) = js.join_next().await {
+        match res {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                if first_err.is_none() {
+                    first_err = Some(e);
+                }
+            }
+            Err(join_err) => {
+                if first_err.is_none() {
+                    first_err = Some(anyhow::anyhow!(join_err));
+                }
+            }
+        }
+    }
+
+    let cancelled = cancel.is_cancelled();
+    if let Some(e) = first_err {
+        if let Some(report) = &report {
+            report.write(&cli, Some(&e), cancelled)?;
+        }
+        // Ensure a clean exit after finalization; surface non-zero status by returning Err
+        return Err(e);
+    }
+
+    if let Some(refresh) = &refresh {
+        info!("{}", refresh.summary().trim_end());
+    }
+    if cli.urls.len() > 1 {
+        info!("{}", source_stats.summary().trim_end());
+    }
+    if let Some(dem) = &dem
+        && dem.invalid_tiles() > 0
+    {
+        warn!("{} tiles had implausible heights.", dem.invalid_tiles());
+    }
+    if let Some(parent_filler) = &parent_filler {
+        info!(
+            "Filled {} missing tiles from their parents.",
+            parent_filler.filled()
+        );
+    }
+
+    if let Some(report) = &report {
+        report.write(&cli, None, cancelled)?;
+        info!("Wrote the report to {}", report.path().display());
+    }
+
+    info!("All done!");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockFormat, MockTiles};
+    use crate::tile::Tile;
+    use crate::validators;
+    use pmtiles::{Compression, TileType};
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::Mutex,
+        time::Duration,
+    };
+
+    /// The data the mock server serves for every tile between two zoom levels, by "z/x/y".
+    fn served_tiles(tiles: &MockTiles, min_zoom: u8, max_zoom: u8) -> BTreeMap<String, Vec<u8>> {
+        (min_zoom..=max_zoom)
+            .flat_map(|z| (0..1u32 << z).flat_map(move |x| (0..1u32 << z).map(move |y| (z, x, y))))
+            .filter_map(|(z, x, y)| {
+                let tile = Tile::new(z, x, y);
+                Some((tile.to_string(), tiles.tile_data(&tile)?))
+            })
+            .collect()
+    }
+
+    /// Reads every tile of a downloaded archive back, decompressed, by "z/x/y".
+    async fn written_tiles(reader: &AppendReader) -> BTreeMap<String, Vec<u8>> {
+        let mut tiles = BTreeMap::new();
+        for tile in reader.get_tiles().await.unwrap() {
+            let data = reader.get_tile(&tile).await.unwrap().unwrap();
+            tiles.insert(tile.to_string(), data);
+        }
+        tiles
+    }
+
+    /// Counts progress messages by kind.
+    fn count_progress(job: DownloadJob) -> (DownloadJob, Arc<Mutex<HashMap<&'static str, usize>>>) {
+        let counts = Arc::new(Mutex::new(HashMap::new()));
+        let job_counts = counts.clone();
+        let job = job.on_progress(move |msg: &ProgressMsg| {
+            let kind = match msg {
+                ProgressMsg::Downloaded(..) => "downloaded",
+                ProgressMsg::Skipped(_) => "skipped",
+                ProgressMsg::Written(_) => "written",
+                ProgressMsg::Failed(..) => "failed",
+                ProgressMsg::RequestStarted() => "requests",
+                ProgressMsg::Retry() => "retries",
+                _ => return,
+            };
+            *job_counts.lock().unwrap().entry(kind).or_insert(0) += 1;
+        });
+        (job, counts)
+    }
+
+    #[test]
+    fn builds_jobs_with_command_line_defaults() {
+        let mut job = DownloadJob::new(["https://example.com/{z}/{x}/{y}.png"], "out.pmtiles")
+            .with_zoom(2, 5)
+            .with_bbox([139.5, 35.5, 140.0, 36.0]);
+        let args = job.args_mut();
+        assert_eq!(args.urls, vec!["https://example.com/{z}/{x}/{y}.png"]);
+        assert_eq!(args.output, PathBuf::from("out.pmtiles"));
+        assert_eq!(args.concurrency, 10);
+        assert_eq!(args.progress, ProgressMode::Hidden);
+        assert_eq!(args.selection.minimum_zoom, 2);
+        assert_eq!(args.selection.maximum_zoom, 5);
+        assert_eq!(args.selection.bbox.as_deref(), Some("139.5,35.5,140,36"));
+        assert_eq!(args.selection.tile_list_format, "z/x/y");
+    }
+
+    #[tokio::test]
+    async fn downloads_from_a_flaky_server() {
+        let server = MockTiles::new(MockFormat::Png)
+            .with_error_rate(0.5)
+            .with_rate_limit(10, 2)
+            .with_retry_after(0)
+            .with_max_zoom(1)
+            .start(([127, 0, 0, 1], 0).into())
+            .await
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.pmtiles");
+        let (job, counts) = count_progress(
+            DownloadJob::new([server.url()], &output)
+                .with_zoom(0, 2)
+                .with_concurrency(1),
+        );
+        job.run().await.unwrap();
+
+        let counts = counts.lock().unwrap().clone();
+        // 21 tiles at zooms 0-2, of which the 16 at zoom 2 are missing
+        assert_eq!(counts.get("downloaded"), Some(&5));
+        assert_eq!(counts.get("written"), Some(&5));
+        assert_eq!(counts.get("skipped"), Some(&16));
+        assert_eq!(counts.get("failed"), None);
+        assert!(counts.get("retries").is_some_and(|&n| n > 0));
+        assert_eq!(
+            counts.get("requests").copied(),
+            Some(server.requests() as usize)
+        );
+
+        let reader = AppendReader::new(&output).await.unwrap();
+        let expected = served_tiles(&MockTiles::new(MockFormat::Png).with_max_zoom(1), 0, 2);
+        assert_eq!(expected.len(), 5);
+        assert_eq!(written_tiles(&reader).await, expected);
+        let header = reader.header();
+        assert_eq!(header.tile_type, TileType::Png);
+        assert_eq!((header.min_zoom, header.max_zoom), (0, 2));
+        let metadata = reader.metadata_json().await.unwrap();
+        let provenance = &metadata["provenance"];
+        assert_eq!(provenance["source"], server.url());
+        assert_eq!(provenance["downloaded_tiles"], 5);
+        assert_eq!(provenance["written_tiles"], 5);
+        assert_eq!(provenance["skipped_tiles"], 16);
+        assert_eq!(provenance["failed_tiles"], 0);
+        let skipped = validators::load_skipped(&output).unwrap();
+        assert_eq!(skipped.len(), 16);
+        assert!(skipped.iter().all(|t| t.z() == 2));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_source_for_blank_tiles() {
+        let first = MockTiles::new(MockFormat::Png)
+            .with_blank_region("-180,-85,-1,85".parse().unwrap())
+            .start(([127, 0, 0, 1], 0).into())
+            .await
+            .unwrap();
+        let second = MockTiles::new(MockFormat::Png)
+            .start(([127, 0, 0, 1], 0).into())
+            .await
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.pmtiles");
+        DownloadJob::new([first.url(), second.url()], &output)
+            .with_zoom(0, 1)
+            .run()
+            .await
+            .unwrap();
+
+        // The transparent tiles of the first source were replaced by the second source's
+        let reader = AppendReader::new(&output).await.unwrap();
+        assert_eq!(
+            written_tiles(&reader).await,
+            served_tiles(&MockTiles::new(MockFormat::Png), 0, 1)
+        );
+        assert_eq!(first.requests(), 5);
+        assert_eq!(second.requests(), 2);
+        let sources = SourceStore::load(&output).unwrap();
+        let supplied_by = |z, x, y| sources.get(&Tile::new(z, x, y)).unwrap_or(0);
+        assert_eq!(supplied_by(0, 0, 0), 0);
+        assert_eq!(supplied_by(1, 0, 0), 1);
+        assert_eq!(supplied_by(1, 0, 1), 1);
+        assert_eq!(supplied_by(1, 1, 0), 0);
+        assert_eq!(supplied_by(1, 1, 1), 0);
+    }
+
+    #[tokio::test]
+    async fn downloads_vector_tiles() {
+        let server = MockTiles::new(MockFormat::Mvt)
+            .start(([127, 0, 0, 1], 0).into())
+            .await
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.pmtiles");
+        DownloadJob::new([server.url()], &output)
+            .with_zoom(0, 2)
+            .with_concurrency(4)
+            .run()
+            .await
+            .unwrap();
+
+        let reader = AppendReader::new(&output).await.unwrap();
+        let expected = served_tiles(&MockTiles::new(MockFormat::Mvt), 0, 2);
+        assert_eq!(expected.len(), 21);
+        assert_eq!(written_tiles(&reader).await, expected);
+        let header = reader.header();
+        assert_eq!(header.tile_type, TileType::Mvt);
+        assert_eq!(header.tile_compression, Compression::Gzip);
+        assert_eq!((header.min_zoom, header.max_zoom), (0, 2));
+        let metadata = reader.metadata_json().await.unwrap();
+        assert!(
+            metadata["vector_layers"]
+                .as_array()
+                .is_some_and(|l| !l.is_empty())
+        );
+        assert_eq!(metadata["provenance"]["written_tiles"], 21);
+    }
+
+    #[tokio::test]
+    async fn downloads_concurrently_from_a_slow_server() {
+        let server = MockTiles::new(MockFormat::Png)
+            .with_latency(Duration::from_millis(20))
+            .start(([127, 0, 0, 1], 0).into())
+            .await
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.pmtiles");
+        let (job, counts) = count_progress(
+            DownloadJob::new([server.url()], &output)
+                .with_zoom(0, 3)
+                .with_concurrency(8),
+        );
+        job.run().await.unwrap();
+
+        let counts = counts.lock().unwrap().clone();
+        assert_eq!(counts.get("written"), Some(&85));
+        assert_eq!(server.requests(), 85);
+        let reader = AppendReader::new(&output).await.unwrap();
+        assert_eq!(
+            written_tiles(&reader).await,
+            served_tiles(&MockTiles::new(MockFormat::Png), 0, 3)
+        );
+    }
+}
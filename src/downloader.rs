@@ -1,53 +1,165 @@
 use anyhow::{Result, anyhow, bail};
 use flume::Sender;
+use futures_util::future::try_join_all;
+use pmtiles::TileType;
 use reqwest::{Client, ClientBuilder};
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
 use tokio::task::JoinSet;
-use tokio::time::{Duration, sleep};
+use tokio::time::{Duration, Instant, sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, debug_span, info, warn};
 
 use crate::{
+    metadata::TileCounts,
+    overzoom::ParentFiller,
     progress::{ProgressMsg, ProgressSender},
+    raster,
+    refresh::{Refresh, RefreshOutcome},
     tile::Tile,
-    tile_urls::TileUrl,
+    tile_format,
+    tile_processor::{TileProcessor, process_tile},
+    tile_source::TileSource,
+    tile_urls::{TileUrl, redact_url},
+    validators::Validators,
     writer::WriteTileMsg,
 };
 
+/// How many tiles each source supplied, when downloading from several sources with fallback,
+/// and how many tiles were downloaded, skipped or failed overall.
+pub struct SourceStats {
+    /// The names of the sources, with secrets redacted
+    names: Vec<String>,
+    supplied: Vec<AtomicU64>,
+    missing: AtomicU64,
+    downloaded: AtomicU64,
+    /// Missing tiles generated from their ancestors
+    filled: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl SourceStats {
+    fn new(sources: &[Arc<dyn TileSource>]) -> Self {
+        Self {
+            names: sources.iter().map(|s| s.name()).collect(),
+            supplied: sources.iter().map(|_| AtomicU64::new(0)).collect(),
+            missing: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            filled: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        }
+    }
+
+    /// The tile counts recorded in the provenance. `written_tiles` is left for the writer.
+    pub fn tile_counts(&self) -> TileCounts {
+        let filled = self.filled.load(Ordering::Relaxed);
+        TileCounts {
+            downloaded_tiles: self.downloaded.load(Ordering::Relaxed),
+            skipped_tiles: self.missing.load(Ordering::Relaxed) - filled,
+            failed_tiles: self.failed.load(Ordering::Relaxed),
+            filled_from_parent: filled,
+            ..Default::default()
+        }
+    }
+
+    /// A table of how many tiles came from each source.
+    pub fn summary(&self) -> String {
+        let mut out = format!("{:>6} {:>11}  {}\n", "Source", "Tiles", "Name");
+        for (i, (name, supplied)) in self.names.iter().zip(&self.supplied).enumerate() {
+            out.push_str(&format!(
+                "{:>6} {:>11}  {}\n",
+                i,
+                supplied.load(Ordering::Relaxed),
+                name
+            ));
+        }
+        out.push_str(&format!(
+            "{:>6} {:>11}\n",
+            "none",
+            self.missing.load(Ordering::Relaxed)
+        ));
+        out
+    }
+}
+
 pub struct Downloader {
-    url_template: String,
+    /// Sources are tried in order until one has the tile
+    sources: Vec<Arc<dyn TileSource>>,
     tiles: Vec<Tile>,
     concurrency: usize,
-    client: Client,
     progress_tx: ProgressSender,
-    cancel: Arc<RwLock<bool>>,
+    cancel: CancellationToken,
+    refresh: Option<Arc<Refresh>>,
+    parent_filler: Option<Arc<ParentFiller>>,
+    processors: Vec<Arc<dyn TileProcessor>>,
+    /// Build each tile from its four children at the source (`--tile-size 512`)
+    assemble: bool,
+    /// Blend every source into each tile instead of falling back (`--composite`): the opacity of
+    /// each source, and the format of the blended tiles
+    composite: Option<(Arc<Vec<f32>>, TileType)>,
+    source_stats: Arc<SourceStats>,
 }
 
 impl Downloader {
     pub fn new(
-        url_template: &str,
+        sources: Vec<Arc<dyn TileSource>>,
         tiles: Vec<Tile>,
         concurrency: usize,
         progress_tx: ProgressSender,
-        cancel: Arc<RwLock<bool>>,
+        cancel: CancellationToken,
     ) -> Self {
-        let client = ClientBuilder::new()
-            .user_agent(format!(
-                "tile-download-tool/{} (+https://github.com/KotobaMedia/tile-download-tool)",
-                env!("CARGO_PKG_VERSION")
-            ))
-            .build()
-            .unwrap();
-
+        let source_stats = Arc::new(SourceStats::new(&sources));
         Self {
-            url_template: url_template.to_string(),
+            sources,
             tiles,
             concurrency,
-            client,
             progress_tx,
             cancel,
+            refresh: None,
+            parent_filler: None,
+            processors: Vec::new(),
+            assemble: false,
+            composite: None,
+            source_stats,
         }
     }
 
+    pub fn source_stats(&self) -> Arc<SourceStats> {
+        self.source_stats.clone()
+    }
+
+    /// Refresh tiles of an existing archive using conditional requests, reusing unmodified tiles.
+    pub fn with_refresh(mut self, refresh: Arc<Refresh>) -> Self {
+        self.refresh = Some(refresh);
+        self
+    }
+
+    /// Fill tiles the source doesn't have from their nearest available ancestor.
+    pub fn with_parent_filler(mut self, parent_filler: Arc<ParentFiller>) -> Self {
+        self.parent_filler = Some(parent_filler);
+        self
+    }
+
+    /// Run a processor on every tile before it is written. Processors run in the order they are added.
+    pub fn with_processor(mut self, processor: Arc<dyn TileProcessor>) -> Self {
+        self.processors.push(processor);
+        self
+    }
+
+    /// Download the four children of every tile and stitch them into one tile twice their size.
+    pub fn with_tile_assembly(mut self) -> Self {
+        self.assemble = true;
+        self
+    }
+
+    /// Blend the tiles of every source together, bottom first, with the given opacities.
+    pub fn with_composite(mut self, opacities: Vec<f32>, tile_type: TileType) -> Self {
+        self.composite = Some((Arc::new(opacities), tile_type));
+        self
+    }
+
     pub async fn download(
         &mut self,
         start_idx: usize,
@@ -67,49 +179,151 @@ impl Downloader {
         });
 
         for _ in 0..self.concurrency {
-            let client = self.client.clone();
-            let url_template = self.url_template.clone();
+            let sources = self.sources.clone();
+            let source_stats = self.source_stats.clone();
             let dlq_rx = dlq_rx.clone();
             let output_tx = output_tx.clone();
             let progress_tx = self.progress_tx.clone();
             let cancel = self.cancel.clone();
+            let refresh = self.refresh.clone();
+            let parent_filler = self.parent_filler.clone();
+            let processors = self.processors.clone();
+            let assemble = self.assemble;
+            let composite = self.composite.clone();
             tasks.spawn(async move {
                 while let Ok((index, tile)) = dlq_rx.recv_async().await {
-                    if *cancel.read().await {
+                    if cancel.is_cancelled() {
                         break;
                     }
 
-                    let tile_url = TileUrl::from_template(&url_template, tile.clone());
-
                     let mut msg = WriteTileMsg {
                         index,
                         tile: tile.clone(),
                         data: None,
+                        validators: None,
+                        source: None,
                     };
-                    match download_tile(&client, tile_url).await {
-                        Ok(Some(bytes)) => {
+                    let span = debug_span!("tile", %tile);
+                    let fetch = async {
+                        if let Some((opacities, tile_type)) = &composite {
+                            let response = fetch_composite(
+                                &sources,
+                                opacities,
+                                *tile_type,
+                                &tile,
+                                &source_stats,
+                            )
+                            .await;
+                            (response, None)
+                        } else {
+                            // Try each source in turn until one has the tile
+                            let mut response = Ok(TileResponse::Empty);
+                            let mut source = 0;
+                            for (i, tile_source) in sources.iter().enumerate() {
+                                source = i;
+                                response = if assemble {
+                                    fetch_assembled(tile_source.as_ref(), &tile).await
+                                } else {
+                                    let validators =
+                                        refresh.as_ref().and_then(|r| r.validators(&tile, i));
+                                    tile_source.fetch_if_modified(&tile, validators).await
+                                };
+                                let blank = match &response {
+                                    Ok(TileResponse::Empty) => true,
+                                    // The last source's tile is kept even if it is blank
+                                    Ok(TileResponse::Data(bytes, _)) if i + 1 < sources.len() => {
+                                        bytes.is_empty() || is_blank(bytes).await
+                                    }
+                                    _ => false,
+                                };
+                                if !blank {
+                                    break;
+                                }
+                            }
+                            (response, Some(source))
+                        }
+                    };
+                    let (response, source) = fetch.instrument(span.clone()).await;
+                    match response {
+                        Ok(TileResponse::Data(bytes, validators)) => {
+                            source_stats.downloaded.fetch_add(1, Ordering::Relaxed);
+                            if let Some(source) = source {
+                                source_stats.supplied[source].fetch_add(1, Ordering::Relaxed);
+                                if sources.len() > 1 {
+                                    msg.source = Some(source);
+                                }
+                            }
                             progress_tx
                                 .send_async(ProgressMsg::Downloaded(tile.clone(), bytes.len()))
                                 .await?;
+                            let bytes =
+                                process_tile(&processors, &tile, bytes, &progress_tx).await?;
+                            if let Some(refresh) = &refresh {
+                                refresh.compare(&tile, Some(&bytes)).await?;
+                            }
                             msg.data = Some(bytes);
+                            msg.validators = Some(validators);
+                            output_tx.send_async(msg).await?;
+                        }
+                        Ok(TileResponse::Empty) => {
+                            source_stats.missing.fetch_add(1, Ordering::Relaxed);
+                            if let Some(refresh) = &refresh {
+                                refresh.compare(&tile, None).await?;
+                            }
+                            if let Some(parent_filler) = &parent_filler {
+                                match parent_filler.fill(&tile).instrument(span.clone()).await {
+                                    Ok(Some(data)) => {
+                                        msg.data = Some(
+                                            process_tile(&processors, &tile, data, &progress_tx)
+                                                .await?,
+                                        )
+                                    }
+                                    Ok(None) => {}
+                                    Err(e) => {
+                                        warn!(%tile, "Failed to fill tile from its parent: {}", e)
+                                    }
+                                }
+                            }
+                            if msg.data.is_some() {
+                                source_stats.filled.fetch_add(1, Ordering::Relaxed);
+                                progress_tx
+                                    .send_async(ProgressMsg::FilledFromParent(tile.clone()))
+                                    .await?;
+                            } else {
+                                progress_tx
+                                    .send_async(ProgressMsg::Skipped(tile.clone()))
+                                    .await?;
+                            }
                             output_tx.send_async(msg).await?;
                         }
-                        Ok(None) => {
-                            progress_tx.send_async(ProgressMsg::Skipped()).await?;
+                        Ok(TileResponse::NotModified(new_validators)) => {
+                            // Only conditional requests are answered with 304, and we only send
+                            // them when refreshing
+                            let Some(refresh) = &refresh else {
+                                bail!("Unexpected 304 Not Modified for tile {} without a refresh source", tile);
+                            };
+                            refresh.record(&tile, RefreshOutcome::Unchanged);
+                            msg.data = refresh.previous(&tile).await?;
+                            let source = source.unwrap_or(0);
+                            msg.validators = refresh
+                                .validators(&tile, source)
+                                .cloned()
+                                .map(|v| v.updated(new_validators));
+                            if sources.len() > 1 {
+                                msg.source = Some(source);
+                            }
+                            progress_tx
+                                .send_async(ProgressMsg::NotModified(tile.clone()))
+                                .await?;
                             output_tx.send_async(msg).await?;
                         }
                         Err(e) => {
-                            // Log the failure via progress logger, request cancellation, then error out
+                            // Report the failure via progress, request cancellation, then error out
+                            source_stats.failed.fetch_add(1, Ordering::Relaxed);
                             let _ = progress_tx
-                                .send_async(ProgressMsg::Log(format!(
-                                    "Error downloading tile {}: {}",
-                                    tile, e
-                                )))
+                                .send_async(ProgressMsg::Failed(tile.clone(), e.to_string()))
                                 .await;
-                            {
-                                let mut w = cancel.write().await;
-                                *w = true;
-                            }
+                            cancel.cancel();
                             return Err(e);
                         }
                     }
@@ -122,28 +336,137 @@ impl Downloader {
             res??;
         }
 
-        self.progress_tx
-            .send(ProgressMsg::Log("All downloads complete.".to_string()))?;
+        info!("All downloads complete.");
 
         Ok(())
     }
 }
 
-async fn download_tile(client: &Client, tile_url: TileUrl) -> Result<Option<Vec<u8>>> {
+/// Decodes a tile to check whether it is blank, so the next source can be tried.
+async fn is_blank(data: &[u8]) -> bool {
+    let data = data.to_vec();
+    tokio::task::spawn_blocking(move || tile_format::is_blank(&data))
+        .await
+        .unwrap_or(false)
+}
+
+/// Downloads the four children of a tile and stitches them into one tile twice their size.
+async fn fetch_assembled(source: &dyn TileSource, tile: &Tile) -> Result<TileResponse> {
+    let children = tile.children();
+    let fetched = try_join_all(children.iter().map(|child| source.fetch(child))).await?;
+    let mut children: [Option<Vec<u8>>; 4] = Default::default();
+    for (data, child) in children.iter_mut().zip(fetched) {
+        *data = child.map(|d| d.to_vec());
+    }
+    match tokio::task::spawn_blocking({
+        let tile = tile.clone();
+        move || raster::assemble(&tile, children)
+    })
+    .await??
+    {
+        Some(data) => Ok(TileResponse::Data(data, Validators::default())),
+        None => Ok(TileResponse::Empty),
+    }
+}
+
+/// Downloads a tile from every source and blends them together, counting which sources had it.
+async fn fetch_composite(
+    sources: &[Arc<dyn TileSource>],
+    opacities: &[f32],
+    tile_type: TileType,
+    tile: &Tile,
+    source_stats: &SourceStats,
+) -> Result<TileResponse> {
+    let mut layers = Vec::with_capacity(sources.len());
+    for (i, source) in sources.iter().enumerate() {
+        let data = source.fetch(tile).await?.map(|d| d.to_vec());
+        if data.is_some() {
+            source_stats.supplied[i].fetch_add(1, Ordering::Relaxed);
+        }
+        layers.push((data, opacities.get(i).copied().unwrap_or(1.0)));
+    }
+    match tokio::task::spawn_blocking(move || raster::composite(layers, tile_type)).await?? {
+        Some(data) => Ok(TileResponse::Data(data, Validators::default())),
+        None => Ok(TileResponse::Empty),
+    }
+}
+
+pub fn build_client() -> Client {
+    ClientBuilder::new()
+        .user_agent(format!(
+            "tile-download-tool/{} (+https://github.com/KotobaMedia/tile-download-tool)",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .build()
+        .unwrap()
+}
+
+pub enum TileResponse {
+    Data(Vec<u8>, Validators),
+    /// 404 or 204; the server has no tile here
+    Empty,
+    /// 304; the tile hasn't changed since the validators we sent. Carries any updated validators.
+    NotModified(Validators),
+}
+
+pub async fn download_tile(
+    client: &Client,
+    tile_url: TileUrl,
+    progress_tx: Option<&ProgressSender>,
+) -> Result<Option<Vec<u8>>> {
+    match fetch_tile(client, tile_url, None, progress_tx).await? {
+        TileResponse::Data(bytes, _) => Ok(Some(bytes)),
+        TileResponse::Empty | TileResponse::NotModified(_) => Ok(None),
+    }
+}
+
+/// Downloads a tile with retries, waiting at least as long as `Retry-After` asks between them.
+/// If `validators` are given, a conditional request is sent.
+/// If `progress_tx` is given, every HTTP request and retry is reported to it.
+pub async fn fetch_tile(
+    client: &Client,
+    tile_url: TileUrl,
+    validators: Option<&Validators>,
+    progress_tx: Option<&ProgressSender>,
+) -> Result<TileResponse> {
     const MAX_ATTEMPTS: usize = 4;
+    /// Longer `Retry-After` delays are shortened to this, so a misbehaving server can't stall the download
+    const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
     let url = tile_url.url();
+    let report = |msg| async {
+        if let Some(progress_tx) = progress_tx {
+            let _ = progress_tx.send_async(msg).await;
+        }
+    };
 
     for attempt in 1..=MAX_ATTEMPTS {
-        match attempt_download(client, &url).await {
-            Ok(Some(bytes)) => return Ok(Some(bytes)),
-            Ok(None) => return Ok(None),
+        let span = debug_span!("request", url = %redact_url(&url), attempt);
+        report(ProgressMsg::RequestStarted()).await;
+        let started = Instant::now();
+        let (status, result) = attempt_download(client, &url, validators)
+            .instrument(span.clone())
+            .await;
+        let latency = started.elapsed();
+        debug!(parent: &span, status, latency_ms = latency.as_millis() as u64, "Response");
+        report(ProgressMsg::RequestFinished(status, latency)).await;
+        match result {
+            Ok(response) => return Ok(response),
             Err(AttemptError::Fatal(e)) => return Err(e),
-            Err(AttemptError::Retryable(e)) => {
+            Err(AttemptError::Retryable(e, retry_after)) => {
                 if attempt == MAX_ATTEMPTS {
                     return Err(e);
                 }
-                let delay_ms = 200u64.saturating_mul(1u64 << (attempt - 1));
-                sleep(Duration::from_millis(delay_ms)).await;
+                report(ProgressMsg::Retry()).await;
+                let backoff = Duration::from_millis(200u64.saturating_mul(1u64 << (attempt - 1)));
+                // The server knows best when it will be ready again
+                let delay = retry_after.map_or(backoff, |r| r.min(MAX_RETRY_AFTER).max(backoff));
+                warn!(
+                    parent: &span,
+                    "Request failed, retrying in {}ms: {}",
+                    delay.as_millis(),
+                    e
+                );
+                sleep(delay).await;
             }
         }
     }
@@ -152,31 +475,72 @@ async fn download_tile(client: &Client, tile_url: TileUrl) -> Result<Option<Vec<
 }
 
 enum AttemptError {
-    Retryable(anyhow::Error),
+    /// With the delay the server asked for in `Retry-After`, if any
+    Retryable(anyhow::Error, Option<Duration>),
     Fatal(anyhow::Error),
 }
 
+/// Sends a single request. Also returns the response status, or None if no response was received.
 async fn attempt_download(
     client: &Client,
     url: &str,
-) -> std::result::Result<Option<Vec<u8>>, AttemptError> {
-    let resp = client
-        .get(url.to_string())
-        .send()
-        .await
-        .map_err(|e| AttemptError::Retryable(e.into()))?;
-
+    validators: Option<&Validators>,
+) -> (Option<u16>, std::result::Result<TileResponse, AttemptError>) {
+    let mut req = client.get(url.to_string());
+    if let Some(validators) = validators {
+        if let Some(etag) = &validators.etag {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            req = req.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let resp = match req.send().await {
+        Ok(resp) => resp,
+        Err(e) => return (None, Err(AttemptError::Retryable(e.into(), None))),
+    };
     let status = resp.status();
+    let result = match read_response(resp, status).await {
+        // Only a conditional request can be answered with "not modified"
+        Ok(TileResponse::NotModified(_)) if validators.is_none_or(|v| v.is_empty()) => {
+            Err(AttemptError::Fatal(anyhow!(
+                "Unexpected 304 Not Modified to an unconditional request"
+            )))
+        }
+        result => result,
+    };
+    (Some(status.as_u16()), result)
+}
+
+async fn read_response(
+    resp: reqwest::Response,
+    status: reqwest::StatusCode,
+) -> std::result::Result<TileResponse, AttemptError> {
     if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::NO_CONTENT {
-        return Ok(None);
+        return Ok(TileResponse::Empty);
+    }
+
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let response_validators = Validators {
+        etag: header(reqwest::header::ETAG),
+        last_modified: header(reqwest::header::LAST_MODIFIED),
+    };
+
+    if status == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(TileResponse::NotModified(response_validators));
     }
 
     if status.is_success() {
         let bytes = resp
             .bytes()
             .await
-            .map_err(|e| AttemptError::Retryable(e.into()))?;
-        return Ok(Some(bytes.to_vec()));
+            .map_err(|e| AttemptError::Retryable(e.into(), None))?;
+        return Ok(TileResponse::Data(bytes.to_vec(), response_validators));
     }
 
     let retryable = status.is_server_error()
@@ -184,7 +548,14 @@ async fn attempt_download(
         || status == reqwest::StatusCode::REQUEST_TIMEOUT;
 
     if retryable {
-        Err(AttemptError::Retryable(anyhow!("HTTP error: {}", status)))
+        // Only the number of seconds is supported, not an HTTP date
+        let retry_after = header(reqwest::header::RETRY_AFTER)
+            .and_then(|s| s.trim().parse().ok())
+            .map(Duration::from_secs);
+        Err(AttemptError::Retryable(
+            anyhow!("HTTP error: {}", status),
+            retry_after,
+        ))
     } else {
         Err(AttemptError::Fatal(anyhow!(
             "Failed to download tile: {}",
@@ -196,136 +567,158 @@ async fn attempt_download(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock_server::{MockFormat, MockServer, MockTiles};
     use crate::tile::Tile;
     use crate::tile_urls::TileUrl;
-    use std::net::SocketAddr;
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::net::TcpListener;
-    use tokio::time::timeout;
-
-    async fn spawn_scripted_server(
-        statuses: Vec<u16>,
-        ok_body: &'static [u8],
-    ) -> (SocketAddr, Arc<AtomicUsize>) {
-        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
-            .await
-            .unwrap();
-        let addr = listener.local_addr().unwrap();
-        let hit = Arc::new(AtomicUsize::new(0));
-        let hit_clone = hit.clone();
-
-        tokio::spawn(async move {
-            loop {
-                // Exit if no connection arrives for a bit to avoid hanging tests
-                let Ok(Ok((mut socket, _))) =
-                    timeout(Duration::from_millis(3000), listener.accept()).await
-                else {
-                    break;
-                };
-
-                let i = hit_clone.fetch_add(1, Ordering::SeqCst);
-                let status = *statuses.get(i).unwrap_or_else(|| statuses.last().unwrap());
-
-                // Read request headers (best-effort)
-                let mut buf = vec![0u8; 1024];
-                let _ = timeout(Duration::from_millis(200), socket.read(&mut buf)).await;
-
-                let (status_line, body) = match status {
-                    200 => ("200 OK", ok_body),
-                    204 => ("204 No Content", &b""[..]),
-                    400 => ("400 Bad Request", &b""[..]),
-                    404 => ("404 Not Found", &b""[..]),
-                    408 => ("408 Request Timeout", &b""[..]),
-                    429 => ("429 Too Many Requests", &b""[..]),
-                    500 => ("500 Internal Server Error", &b""[..]),
-                    503 => ("503 Service Unavailable", &b""[..]),
-                    _ => ("500 Internal Server Error", &b""[..]),
-                };
-
-                let resp = format!(
-                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
-                    status_line,
-                    body.len()
-                );
-                let _ = socket.write_all(resp.as_bytes()).await;
-                if !body.is_empty() {
-                    let _ = socket.write_all(body).await;
-                }
-                let _ = socket.shutdown().await;
-            }
-        });
 
-        (addr, hit)
+    async fn start(tiles: MockTiles) -> MockServer {
+        tiles.start(([127, 0, 0, 1], 0).into()).await.unwrap()
     }
 
-    fn make_url(addr: SocketAddr) -> String {
-        format!("http://{}/{{z}}/{{x}}/{{y}}", addr)
+    fn tile_url(server: &MockServer) -> TileUrl {
+        TileUrl::from_template(&server.url(), Tile::new(0, 0, 0))
     }
 
     #[tokio::test]
     async fn retries_on_500_then_succeeds() {
-        let (addr, hit) = spawn_scripted_server(vec![500, 200], b"OK").await;
+        let server = start(
+            MockTiles::new(MockFormat::Mvt)
+                .with_error_rate(1.0)
+                .with_error_status(500),
+        )
+        .await;
         let client = reqwest::Client::new();
-        let url_template = make_url(addr);
-        let tile_url = TileUrl::from_template(&url_template, Tile::new(0, 0, 0));
 
-        let data = download_tile(&client, tile_url)
+        let data = download_tile(&client, tile_url(&server), None)
+            .await
+            .expect("should not error");
+        let expected = MockTiles::new(MockFormat::Mvt).tile_data(&Tile::new(0, 0, 0));
+        assert_eq!(data, expected);
+        assert_eq!(server.requests(), 2, "should attempt twice");
+    }
+
+    #[tokio::test]
+    async fn waits_as_long_as_retry_after_asks() {
+        let server = start(
+            MockTiles::new(MockFormat::Mvt)
+                .with_error_rate(1.0)
+                .with_retry_after(1),
+        )
+        .await;
+        let client = reqwest::Client::new();
+
+        let started = Instant::now();
+        download_tile(&client, tile_url(&server), None)
             .await
             .expect("should not error");
-        assert_eq!(data.as_deref(), Some(&b"OK"[..]));
         assert!(
-            hit.load(Ordering::SeqCst) >= 2,
-            "should attempt at least twice"
+            started.elapsed() >= Duration::from_secs(1),
+            "retried after {:?}",
+            started.elapsed()
         );
+        assert_eq!(server.requests(), 2);
     }
 
     #[tokio::test]
     async fn gives_up_after_4_attempts_on_5xx() {
-        let (addr, hit) = spawn_scripted_server(vec![500, 500, 500, 500, 500], b"").await;
+        let server = start(
+            MockTiles::new(MockFormat::Mvt)
+                .with_error_rate(1.0)
+                .with_error_attempts(u32::MAX),
+        )
+        .await;
         let client = reqwest::Client::new();
-        let url_template = make_url(addr);
-        let tile_url = TileUrl::from_template(&url_template, Tile::new(0, 0, 0));
 
-        let _err = download_tile(&client, tile_url)
+        download_tile(&client, tile_url(&server), None)
             .await
             .expect_err("should error after retries");
-        let attempts = hit.load(Ordering::SeqCst);
-        assert!(attempts >= 4, "should try 4 times, got {}", attempts);
-        // Optional: ensure not absurdly high
-        assert!(attempts <= 5);
+        assert_eq!(server.requests(), 4, "should try 4 times");
+    }
+
+    #[tokio::test]
+    async fn retries_on_429() {
+        let server = start(
+            MockTiles::new(MockFormat::Mvt)
+                .with_rate_limit(10, 2)
+                .with_retry_after(0),
+        )
+        .await;
+        let client = reqwest::Client::new();
+
+        let data = download_tile(&client, tile_url(&server), None)
+            .await
+            .expect("should not error");
+        assert!(data.is_some());
+        assert_eq!(server.requests(), 3);
     }
 
     #[tokio::test]
     async fn skips_on_404_without_retry() {
-        let (addr, hit) = spawn_scripted_server(vec![404], b"").await;
+        let server = start(MockTiles::new(MockFormat::Mvt).with_max_zoom(0)).await;
         let client = reqwest::Client::new();
-        let url_template = make_url(addr);
-        let tile_url = TileUrl::from_template(&url_template, Tile::new(0, 0, 0));
 
-        let data = download_tile(&client, tile_url)
+        let url = TileUrl::from_template(&server.url(), Tile::new(1, 0, 0));
+        let data = download_tile(&client, url, None)
             .await
             .expect("404 should not error");
         assert!(data.is_none(), "404 should return None");
-        assert_eq!(hit.load(Ordering::SeqCst), 1, "should not retry on 404");
+        assert_eq!(server.requests(), 1, "should not retry on 404");
+    }
+
+    #[tokio::test]
+    async fn fails_on_304_to_an_unconditional_request() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/{{z}}/{{x}}/{{y}}.png",
+            listener.local_addr().unwrap()
+        );
+        let _server = crate::http_server::serve(listener, |_| async {
+            crate::http_server::Response::new(304)
+        });
+        let client = reqwest::Client::new();
+
+        let err = download_tile(
+            &client,
+            TileUrl::from_template(&url, Tile::new(0, 0, 0)),
+            None,
+        )
+        .await
+        .expect_err("should not be treated as unchanged");
+        assert!(err.to_string().contains("304"));
     }
 
     #[tokio::test]
     async fn does_not_retry_on_400() {
-        let (addr, hit) = spawn_scripted_server(vec![400, 200], b"OK").await;
+        let server = start(
+            MockTiles::new(MockFormat::Mvt)
+                .with_error_rate(1.0)
+                .with_error_status(400),
+        )
+        .await;
         let client = reqwest::Client::new();
-        let url_template = make_url(addr);
-        let tile_url = TileUrl::from_template(&url_template, Tile::new(0, 0, 0));
 
-        let err = download_tile(&client, tile_url)
+        download_tile(&client, tile_url(&server), None)
             .await
             .expect_err("400 should be fatal");
-        let attempts = hit.load(Ordering::SeqCst);
-        assert_eq!(
-            attempts, 1,
-            "should not retry on 400, got {} attempts",
-            attempts
-        );
-        let _ = err; // silence warning in case of different formatting
+        assert_eq!(server.requests(), 1, "should not retry on 400");
+    }
+
+    #[tokio::test]
+    async fn returns_not_modified_on_304() {
+        let server = start(MockTiles::new(MockFormat::Mvt)).await;
+        let client = reqwest::Client::new();
+
+        let TileResponse::Data(_, validators) = fetch_tile(&client, tile_url(&server), None, None)
+            .await
+            .expect("should not error")
+        else {
+            panic!("expected a tile");
+        };
+        assert!(validators.etag.is_some());
+        let resp = fetch_tile(&client, tile_url(&server), Some(&validators), None)
+            .await
+            .expect("304 should not error");
+        assert!(matches!(resp, TileResponse::NotModified(_)));
+        assert_eq!(server.requests(), 2, "should not retry on 304");
     }
 }
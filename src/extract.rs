@@ -0,0 +1,170 @@
+use anyhow::Result;
+use tracing::info;
+
+use crate::{
+    append_reader::AppendReader,
+    cli::ExtractArgs,
+    metadata::Metadata,
+    polygon::Polygon,
+    progress::ProgressMsg,
+    tile_list::TileList,
+    writer::{WriteTileMsg, Writer, tile_type_to_str, write_archive},
+};
+
+pub async fn run(args: ExtractArgs) -> Result<()> {
+    info!("Reading existing tiles from {}...", args.input.display());
+    let reader = AppendReader::new(&args.input).await?;
+    let header = reader.header();
+    let tile_type = header.tile_type;
+
+    let mut tile_list = TileList::from_tiles(reader.get_tiles().await?.into_iter().collect());
+    tile_list.filter_zooms(
+        args.minimum_zoom.unwrap_or(header.min_zoom),
+        args.maximum_zoom.unwrap_or(header.max_zoom),
+    );
+    if let Some(bbox_str) = &args.bbox {
+        info!("Filtering tiles by bounding box {}...", bbox_str);
+        tile_list.filter_bbox(bbox_str.parse()?);
+    }
+    if let Some(polygon_path) = &args.polygon {
+        info!("Filtering tiles by polygon {}...", polygon_path.display());
+        tile_list.filter_polygon(&Polygon::from_geojson_file(polygon_path)?);
+    }
+    info!("Extracting {} tiles...", tile_list.tiles.len());
+
+    let mut metadata = reader.metadata_json().await?;
+    metadata.insert(
+        "extracted_from".to_string(),
+        args.input.display().to_string().into(),
+    );
+    let metadata = Metadata::from_json(metadata);
+
+    let expected_tiles = tile_list.tiles.len() as u64;
+    write_archive(
+        expected_tiles,
+        |progress_tx| {
+            Writer::new(
+                args.output.clone(),
+                args.force,
+                tile_type_to_str(tile_type)?,
+                metadata,
+                tile_list.meta,
+                args.stats.tilestats,
+                progress_tx,
+            )
+        },
+        |tile_tx, progress_tx| async move {
+            for (index, tile) in tile_list.tiles.into_iter().enumerate() {
+                let data = reader.get_tile(&tile).await?;
+                if data.is_none() {
+                    progress_tx
+                        .send_async(ProgressMsg::Skipped(tile.clone()))
+                        .await?;
+                }
+                let msg = WriteTileMsg {
+                    index,
+                    tile,
+                    data,
+                    validators: None,
+                    source: None,
+                };
+                tile_tx.send_async(msg).await?;
+            }
+            Ok(())
+        },
+    )
+    .await?;
+
+    info!("All done!");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, path::Path};
+
+    use super::*;
+    use crate::writer::write_tiles;
+
+    /// Writes every tile from zoom 0 to 2, each containing its own coordinates.
+    fn write_input(path: &Path) {
+        let tiles = TileList::from_zoom_range(0, 2)
+            .tiles
+            .into_iter()
+            .map(|tile| {
+                let data = tile.to_string().into_bytes();
+                (tile, data)
+            })
+            .collect();
+        write_tiles(path, "png", tiles).unwrap();
+    }
+
+    fn args(dir: &Path) -> ExtractArgs {
+        ExtractArgs {
+            input: dir.join("in.pmtiles"),
+            output: dir.join("out.pmtiles"),
+            config: None,
+            job: Vec::new(),
+            force: false,
+            maximum_zoom: None,
+            minimum_zoom: None,
+            bbox: None,
+            polygon: None,
+            stats: crate::cli::TilestatsArgs { tilestats: false },
+        }
+    }
+
+    /// Runs the extract and returns the tiles of the output, checking their data.
+    async fn extract(args: ExtractArgs) -> HashSet<String> {
+        let output = args.output.clone();
+        run(args).await.unwrap();
+        let reader = AppendReader::new(&output).await.unwrap();
+        let mut tiles = HashSet::new();
+        for tile in reader.get_tiles().await.unwrap() {
+            let data = reader.get_tile(&tile).await.unwrap();
+            assert_eq!(data, Some(tile.to_string().into_bytes()));
+            tiles.insert(tile.to_string());
+        }
+        tiles
+    }
+
+    fn set(tiles: &[&str]) -> HashSet<String> {
+        tiles.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn extracts_a_bbox() {
+        let dir = tempfile::tempdir().unwrap();
+        write_input(&dir.path().join("in.pmtiles"));
+
+        let tiles = extract(ExtractArgs {
+            bbox: Some("10,10,170,80".to_string()),
+            ..args(dir.path())
+        })
+        .await;
+        assert_eq!(
+            tiles,
+            set(&["0/0/0", "1/1/0", "2/2/0", "2/2/1", "2/3/0", "2/3/1"])
+        );
+    }
+
+    #[tokio::test]
+    async fn extracts_a_polygon_and_zoom_range() {
+        let dir = tempfile::tempdir().unwrap();
+        write_input(&dir.path().join("in.pmtiles"));
+        let polygon = dir.path().join("area.geojson");
+        std::fs::write(
+            &polygon,
+            r#"{"type": "Polygon", "coordinates": [[[100, 10], [110, 10], [110, 20], [100, 10]]]}"#,
+        )
+        .unwrap();
+
+        let tiles = extract(ExtractArgs {
+            polygon: Some(polygon),
+            minimum_zoom: Some(1),
+            ..args(dir.path())
+        })
+        .await;
+        assert_eq!(tiles, set(&["1/1/0", "2/3/1"]));
+    }
+}
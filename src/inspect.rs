@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{append_reader::AppendReader, cli::InspectArgs, tile::Tile, tile_list::TileList};
+
+#[derive(Serialize)]
+struct InspectReport {
+    header: HeaderInfo,
+    metadata: serde_json::Value,
+    zooms: Vec<ZoomStats>,
+    total_tiles: u64,
+    total_bytes: u64,
+    average_bytes: u64,
+    /// Only present when a tile selection was given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    missing: Option<Vec<MissingStats>>,
+}
+
+#[derive(Serialize)]
+struct HeaderInfo {
+    tile_type: String,
+    tile_compression: String,
+    min_zoom: u8,
+    max_zoom: u8,
+    bounds: (f32, f32, f32, f32),
+    center: (f32, f32),
+    center_zoom: u8,
+}
+
+#[derive(Serialize)]
+struct ZoomStats {
+    zoom: u8,
+    tiles: u64,
+    bytes: u64,
+    average_bytes: u64,
+    min_bytes: u64,
+    max_bytes: u64,
+    /// Tile coordinate extent at this zoom: (min_x, min_y, max_x, max_y)
+    extent: (u32, u32, u32, u32),
+}
+
+#[derive(Serialize)]
+struct MissingStats {
+    zoom: u8,
+    expected: u64,
+    missing: u64,
+}
+
+pub async fn run(args: InspectArgs) -> Result<()> {
+    let report = inspect(&args).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report)?;
+    }
+    Ok(())
+}
+
+async fn inspect(args: &InspectArgs) -> Result<InspectReport> {
+    let reader = AppendReader::new(&args.input).await?;
+    let header = reader.header();
+    let header = HeaderInfo {
+        tile_type: format!("{:?}", header.tile_type),
+        tile_compression: format!("{:?}", header.tile_compression),
+        min_zoom: header.min_zoom,
+        max_zoom: header.max_zoom,
+        bounds: (
+            header.min_longitude,
+            header.min_latitude,
+            header.max_longitude,
+            header.max_latitude,
+        ),
+        center: (header.center_longitude, header.center_latitude),
+        center_zoom: header.center_zoom,
+    };
+
+    let metadata_str = reader.metadata().await?;
+    let metadata =
+        serde_json::from_str(&metadata_str).unwrap_or(serde_json::Value::String(metadata_str));
+
+    let tile_sizes = reader.get_tile_sizes().await?;
+    let mut zooms: BTreeMap<u8, ZoomStats> = BTreeMap::new();
+    for (tile, size) in &tile_sizes {
+        let size = *size as u64;
+        let stats = zooms.entry(tile.z()).or_insert_with(|| ZoomStats {
+            zoom: tile.z(),
+            tiles: 0,
+            bytes: 0,
+            average_bytes: 0,
+            min_bytes: u64::MAX,
+            max_bytes: 0,
+            extent: (u32::MAX, u32::MAX, 0, 0),
+        });
+        stats.tiles += 1;
+        stats.bytes += size;
+        stats.min_bytes = stats.min_bytes.min(size);
+        stats.max_bytes = stats.max_bytes.max(size);
+        stats.extent = (
+            stats.extent.0.min(tile.x()),
+            stats.extent.1.min(tile.y()),
+            stats.extent.2.max(tile.x()),
+            stats.extent.3.max(tile.y()),
+        );
+    }
+    for stats in zooms.values_mut() {
+        stats.average_bytes = stats.bytes / stats.tiles;
+    }
+    let total_tiles = tile_sizes.len() as u64;
+    let total_bytes = zooms.values().map(|z| z.bytes).sum::<u64>();
+
+    let missing = match &args.selection {
+        Some(selection) => {
+            let existing = tile_sizes
+                .into_iter()
+                .map(|(tile, _)| tile)
+                .collect::<HashSet<Tile>>();
+            let mut missing: BTreeMap<u8, MissingStats> = BTreeMap::new();
+            for tile in TileList::from_selection(selection)?.tiles {
+                let stats = missing.entry(tile.z()).or_insert_with(|| MissingStats {
+                    zoom: tile.z(),
+                    expected: 0,
+                    missing: 0,
+                });
+                stats.expected += 1;
+                if !existing.contains(&tile) {
+                    stats.missing += 1;
+                }
+            }
+            Some(missing.into_values().collect())
+        }
+        None => None,
+    };
+
+    Ok(InspectReport {
+        header,
+        metadata,
+        zooms: zooms.into_values().collect(),
+        total_tiles,
+        total_bytes,
+        average_bytes: total_bytes.checked_div(total_tiles).unwrap_or(0),
+        missing,
+    })
+}
+
+fn print_report(report: &InspectReport) -> Result<()> {
+    let h = &report.header;
+    println!("Header");
+    println!("  Tile type:        {}", h.tile_type);
+    println!("  Tile compression: {}", h.tile_compression);
+    println!("  Zoom:             {} - {}", h.min_zoom, h.max_zoom);
+    println!(
+        "  Bounds:           {}, {}, {}, {}",
+        h.bounds.0, h.bounds.1, h.bounds.2, h.bounds.3
+    );
+    println!(
+        "  Center:           {}, {} (zoom {})",
+        h.center.0, h.center.1, h.center_zoom
+    );
+    println!();
+
+    println!("Metadata");
+    println!("{}", serde_json::to_string_pretty(&report.metadata)?);
+    println!();
+
+    println!(
+        "{:>4} {:>11} {:>14} {:>10} {:>10} {:>10}  Extent (x, y)",
+        "Zoom", "Tiles", "Bytes", "Avg", "Min", "Max"
+    );
+    for z in &report.zooms {
+        println!(
+            "{:>4} {:>11} {:>14} {:>10} {:>10} {:>10}  {}-{}, {}-{}",
+            z.zoom,
+            z.tiles,
+            z.bytes,
+            z.average_bytes,
+            z.min_bytes,
+            z.max_bytes,
+            z.extent.0,
+            z.extent.2,
+            z.extent.1,
+            z.extent.3
+        );
+    }
+    println!(
+        "{:>4} {:>11} {:>14} {:>10}",
+        "All", report.total_tiles, report.total_bytes, report.average_bytes
+    );
+
+    if let Some(missing) = &report.missing {
+        println!();
+        println!("{:>4} {:>11} {:>11}", "Zoom", "Expected", "Missing");
+        for m in missing {
+            println!("{:>4} {:>11} {:>11}", m.zoom, m.expected, m.missing);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli::TileSelectionArgs, writer::write_tiles};
+
+    #[tokio::test]
+    async fn counts_tiles_per_zoom_and_missing_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.pmtiles");
+        let tiles = vec![
+            (Tile::new(0, 0, 0), vec![1; 10]),
+            (Tile::new(1, 0, 0), vec![2; 20]),
+            (Tile::new(1, 1, 1), vec![3; 40]),
+        ];
+        write_tiles(&input, "png", tiles).unwrap();
+
+        let report = inspect(&InspectArgs {
+            input,
+            json: true,
+            selection: Some(TileSelectionArgs::zoom_range(0, 2)),
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(report.total_tiles, 3);
+        assert_eq!(report.total_bytes, 70);
+        let zooms = report
+            .zooms
+            .iter()
+            .map(|z| (z.zoom, z.tiles, z.bytes, z.min_bytes, z.max_bytes, z.extent))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            zooms,
+            vec![
+                (0, 1, 10, 10, 10, (0, 0, 0, 0)),
+                (1, 2, 60, 20, 40, (0, 0, 1, 1))
+            ]
+        );
+        let missing = report
+            .missing
+            .unwrap()
+            .iter()
+            .map(|m| (m.zoom, m.expected, m.missing))
+            .collect::<Vec<_>>();
+        assert_eq!(missing, vec![(0, 1, 0), (1, 4, 2), (2, 16, 16)]);
+    }
+}
@@ -0,0 +1,65 @@
+//! Downloads XYZ map tiles into PMTiles archives.
+//!
+//! The `tile-download-tool` command is a thin wrapper around this library: [`DownloadJob`] runs the
+//! same downloads from Rust code, with progress events passed to a callback instead of printed.
+
+use anyhow::Result;
+
+use crate::cli::Command;
+
+mod append_reader;
+pub mod cli;
+mod config;
+mod dem;
+mod diff;
+mod download;
+mod downloader;
+mod extract;
+mod http_server;
+mod inspect;
+pub mod logging;
+mod merge;
+mod metadata;
+mod metrics;
+mod mock_server;
+mod mvt;
+mod overviews;
+mod overzoom;
+mod polygon;
+pub mod progress;
+mod raster;
+mod refresh;
+mod report;
+mod serve;
+mod spill;
+pub mod tile;
+mod tile_format;
+mod tile_list;
+mod tile_list_format;
+mod tile_processor;
+pub mod tile_source;
+mod tile_urls;
+mod validators;
+mod vector_filter;
+mod vector_layers;
+mod verify;
+mod writer;
+
+pub use dem::DemEncoding;
+pub use download::DownloadJob;
+pub use raster::{Resampling, TranscodeFormat};
+pub use tokio_util::sync::CancellationToken;
+
+/// Runs a subcommand parsed from the command line.
+pub async fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Download(args) => download::run(*args).await,
+        Command::Inspect(args) => inspect::run(args).await,
+        Command::Verify(args) => verify::run(args).await,
+        Command::Merge(args) => merge::run(args).await,
+        Command::Extract(args) => extract::run(args).await,
+        Command::Diff(args) => diff::run(args).await,
+        Command::Serve(args) => serve::run(args).await,
+        Command::MockServer(args) => mock_server::run(args).await,
+    }
+}
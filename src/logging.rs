@@ -0,0 +1,234 @@
+//! Logging with `tracing`, to stderr and optionally a file. Log lines are printed above the progress
+//! bars instead of over them, and are also sent to the JSON progress stream as `log` events.
+
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use indicatif::MultiProgress;
+use serde_json::{Map, Value};
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span,
+};
+use tracing_subscriber::{
+    Layer,
+    filter::Targets,
+    fmt,
+    layer::{self, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+use crate::{cli::LoggingArgs, progress::StatusOut};
+
+/// The progress bars currently on screen, if any
+static PROGRESS_BARS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Prints log lines above these progress bars from now on.
+pub fn set_progress_bars(m: MultiProgress) {
+    *PROGRESS_BARS.lock().unwrap() = Some(m);
+}
+
+/// The JSON progress stream currently being printed, if any
+static JSON_EVENTS: Mutex<Option<StatusOut>> = Mutex::new(None);
+
+/// Sends log messages to this JSON progress stream as `log` events from now on.
+pub fn set_json_events(out: StatusOut) {
+    *JSON_EVENTS.lock().unwrap() = Some(out);
+}
+
+/// Writes to stderr, hiding the progress bars while doing so.
+struct StderrWriter;
+
+impl Write for StderrWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &*PROGRESS_BARS.lock().unwrap() {
+            Some(m) => m.suspend(|| io::stderr().write_all(buf))?,
+            None => io::stderr().write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// The message and tile of a log event or span.
+#[derive(Default)]
+struct LogFields {
+    message: Option<String>,
+    tile: Option<String>,
+}
+
+impl Visit for LogFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = Some(value.to_string()),
+            "tile" => self.tile = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => self.message = Some(format!("{:?}", value)),
+            "tile" => self.tile = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+}
+
+/// Prints log events to a JSON progress stream, with the tile of the event or its span.
+struct JsonLogLayer {
+    out: &'static Mutex<Option<StatusOut>>,
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for JsonLogLayer {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: layer::Context<'_, S>) {
+        let mut fields = LogFields::default();
+        attrs.record(&mut fields);
+        if let (Some(tile), Some(span)) = (fields.tile, ctx.span(id)) {
+            span.extensions_mut().insert(SpanTile(tile));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: layer::Context<'_, S>) {
+        let Some(out) = self.out.lock().unwrap().clone() else {
+            return;
+        };
+        let mut fields = LogFields::default();
+        event.record(&mut fields);
+        let tile = fields.tile.or_else(|| {
+            ctx.event_scope(event)?
+                .find_map(|span| span.extensions().get::<SpanTile>().map(|t| t.0.clone()))
+        });
+        let mut line = Map::new();
+        line.insert(
+            "time".to_string(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                .into(),
+        );
+        line.insert("event".to_string(), "log".into());
+        line.insert(
+            "level".to_string(),
+            event.metadata().level().as_str().to_lowercase().into(),
+        );
+        line.insert(
+            "message".to_string(),
+            fields.message.unwrap_or_default().into(),
+        );
+        line.insert("tile".to_string(), tile.into());
+        out.println(Value::Object(line));
+    }
+}
+
+/// The tile a span is for, so events inside it can be attributed to the tile
+struct SpanTile(String);
+
+/// Our own log messages are filtered by `level`; dependencies only log warnings and errors.
+fn targets(level: LevelFilter) -> Targets {
+    Targets::new()
+        .with_default(LevelFilter::WARN.min(level))
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+}
+
+pub fn init(args: &LoggingArgs) -> Result<()> {
+    let level = match args.verbose as i16 - args.quiet as i16 {
+        ..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+
+    let stderr = fmt::layer()
+        .with_writer(|| StderrWriter)
+        .without_time()
+        .with_target(false)
+        .with_filter(targets(level));
+
+    // The log file always includes the per-request debug messages
+    let file = match &args.log_file {
+        Some(path) => Some(
+            fmt::layer()
+                .with_writer(Mutex::new(create_log_file(path)?))
+                .with_ansi(false)
+                .with_filter(targets(level.max(LevelFilter::DEBUG))),
+        ),
+        None => None,
+    };
+
+    let json = JsonLogLayer { out: &JSON_EVENTS }.with_filter(targets(level));
+
+    tracing_subscriber::registry()
+        .with(stderr)
+        .with(json)
+        .with(file)
+        .try_init()?;
+    Ok(())
+}
+
+fn create_log_file(path: &Path) -> Result<File> {
+    File::create(path).with_context(|| format!("Failed to create log file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile::Tile;
+    use std::sync::Arc;
+    use tracing::{debug_span, warn};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sends_warnings_to_the_json_stream() {
+        static OUT: Mutex<Option<StatusOut>> = Mutex::new(None);
+        let buffer = Buffer::default();
+        *OUT.lock().unwrap() = Some(StatusOut::new(buffer.clone()));
+        let subscriber = tracing_subscriber::registry().with(JsonLogLayer { out: &OUT });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let tile = Tile::new(3, 1, 2);
+            warn!(%tile, "Failed to inspect vector tile: {}", "bad data");
+            debug_span!("tile", %tile).in_scope(|| warn!("Retrying"));
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events = output
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "log");
+        assert_eq!(events[0]["level"], "warn");
+        assert_eq!(
+            events[0]["message"],
+            "Failed to inspect vector tile: bad data"
+        );
+        assert_eq!(events[0]["tile"], "3/1/2");
+        assert_eq!(events[1]["message"], "Retrying");
+        assert_eq!(events[1]["tile"], "3/1/2");
+    }
+}
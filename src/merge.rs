@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+use tracing::info;
+
+use crate::{
+    append_reader::AppendReader,
+    cli::{MergeArgs, MergePolicy},
+    metadata::Metadata,
+    progress::ProgressMsg,
+    tile::Tile,
+    tile_list::TileListMeta,
+    writer::{WriteTileMsg, Writer, tile_type_to_str, write_archive},
+};
+
+pub async fn run(args: MergeArgs) -> Result<()> {
+    let mut readers = Vec::new();
+    for input in &args.inputs {
+        info!("Reading {}...", input.display());
+        readers.push(AppendReader::new(input).await?);
+    }
+
+    let tile_type = readers[0].header().tile_type;
+    if let Some(i) = readers
+        .iter()
+        .position(|r| r.header().tile_type != tile_type)
+    {
+        bail!(
+            "Cannot merge archives of different tile types: {} is {:?}, but {} is {:?}",
+            args.inputs[0].display(),
+            tile_type,
+            args.inputs[i].display(),
+            readers[i].header().tile_type
+        );
+    }
+
+    // Which inputs contain each tile, in tile ID order
+    let mut sources = BTreeMap::new();
+    for (i, reader) in readers.iter().enumerate() {
+        for tile in reader.get_tiles().await? {
+            sources
+                .entry(tile.to_id())
+                .or_insert_with(|| (tile, Vec::new()))
+                .1
+                .push(i);
+        }
+    }
+    for (_, inputs) in sources.values_mut() {
+        inputs.sort_unstable();
+    }
+    let overlapping = sources.values().filter(|(_, i)| i.len() > 1).count();
+    info!(
+        "Merging {} tiles ({} present in more than one input, resolved by {:?}).",
+        sources.len(),
+        overlapping,
+        args.policy
+    );
+
+    let mut metadata = Map::new();
+    let mut merged_from = Vec::new();
+    let mut downloaded_at = Vec::new();
+    for (reader, input) in readers.iter().zip(&args.inputs) {
+        let mut input_metadata = reader.metadata_json().await?;
+        downloaded_at.push(downloaded_at_of(&input_metadata, input)?);
+        let provenance = input_metadata.remove("provenance");
+        merged_from.push(serde_json::json!({
+            "file": input.display().to_string(),
+            "provenance": provenance,
+        }));
+        // The first input that has a key wins
+        for (key, value) in input_metadata {
+            metadata.entry(key).or_insert(value);
+        }
+    }
+    metadata.insert("merged_from".to_string(), Value::Array(merged_from));
+    let metadata = Metadata::from_json(metadata);
+
+    let tile_list_meta = merged_tile_list_meta(&readers);
+    let expected_tiles = sources.len() as u64;
+    write_archive(
+        expected_tiles,
+        |progress_tx| {
+            Writer::new(
+                args.output.clone(),
+                args.force,
+                tile_type_to_str(tile_type)?,
+                metadata,
+                tile_list_meta,
+                args.stats.tilestats,
+                progress_tx,
+            )
+        },
+        |tile_tx, progress_tx| async move {
+            for (index, (tile, inputs)) in sources.into_values().enumerate() {
+                let data = pick_tile(&readers, &tile, &inputs, args.policy, &downloaded_at).await?;
+                if data.is_none() {
+                    progress_tx
+                        .send_async(ProgressMsg::Skipped(tile.clone()))
+                        .await?;
+                }
+                let msg = WriteTileMsg {
+                    index,
+                    tile,
+                    data,
+                    validators: None,
+                    source: None,
+                };
+                tile_tx.send_async(msg).await?;
+            }
+            Ok(())
+        },
+    )
+    .await?;
+
+    info!("All done!");
+    Ok(())
+}
+
+/// Chooses which input's copy of a tile to keep. `inputs` is sorted and non-empty.
+async fn pick_tile(
+    readers: &[AppendReader],
+    tile: &Tile,
+    inputs: &[usize],
+    policy: MergePolicy,
+    downloaded_at: &[DateTime<Utc>],
+) -> Result<Option<Vec<u8>>> {
+    let chosen = match policy {
+        MergePolicy::First => inputs[0],
+        MergePolicy::Last => inputs[inputs.len() - 1],
+        MergePolicy::Newest => *inputs
+            .iter()
+            .rev()
+            .max_by_key(|i| downloaded_at[**i])
+            .unwrap(),
+        MergePolicy::Largest => {
+            let mut largest: Option<Vec<u8>> = None;
+            for i in inputs {
+                if let Some(data) = readers[*i].get_tile(tile).await?
+                    && largest.as_ref().is_none_or(|l| data.len() > l.len())
+                {
+                    largest = Some(data);
+                }
+            }
+            return Ok(largest);
+        }
+    };
+    readers[chosen].get_tile(tile).await
+}
+
+/// When an input was downloaded, from its provenance metadata or else the file modification time.
+fn downloaded_at_of(
+    metadata: &Map<String, Value>,
+    input: &std::path::Path,
+) -> Result<DateTime<Utc>> {
+    let recorded = metadata
+        .get("provenance")
+        .and_then(|p| p.get("downloaded_at"))
+        .and_then(|d| d.as_str())
+        .and_then(|d| DateTime::parse_from_rfc3339(d).ok());
+    match recorded {
+        Some(d) => Ok(d.with_timezone(&Utc)),
+        None => Ok(std::fs::metadata(input)?.modified()?.into()),
+    }
+}
+
+/// The union of the zoom ranges and bounds of all inputs.
+fn merged_tile_list_meta(readers: &[AppendReader]) -> TileListMeta {
+    let headers = readers.iter().map(|r| r.header()).collect::<Vec<_>>();
+    let min_zoom = headers.iter().map(|h| h.min_zoom).min().unwrap_or(0);
+    let max_zoom = headers.iter().map(|h| h.max_zoom).max().unwrap_or(0);
+    let bounds = headers.iter().fold(
+        (
+            f32::INFINITY,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::NEG_INFINITY,
+        ),
+        |(w, s, e, n), h| {
+            (
+                w.min(h.min_longitude),
+                s.min(h.min_latitude),
+                e.max(h.max_longitude),
+                n.max(h.max_latitude),
+            )
+        },
+    );
+    TileListMeta {
+        min_zoom,
+        max_zoom,
+        center: Some(((bounds.0 + bounds.2) / 2.0, (bounds.1 + bounds.3) / 2.0)),
+        bounds: Some(bounds),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::writer::write_tiles_with_metadata;
+
+    /// Writes an archive downloaded at `downloaded_at`, with `data` at 0/0/0 and `own` at a tile
+    /// no other input has.
+    fn input(dir: &Path, name: &str, downloaded_at: &str, data: &str, own: Tile) -> PathBuf {
+        let path = dir.join(format!("{}.pmtiles", name));
+        let serde_json::Value::Object(metadata) =
+            serde_json::json!({"name": name, "provenance": {"downloaded_at": downloaded_at}})
+        else {
+            unreachable!()
+        };
+        let tiles = vec![
+            (Tile::new(0, 0, 0), data.as_bytes().to_vec()),
+            (own, name.as_bytes().to_vec()),
+        ];
+        write_tiles_with_metadata(&path, "png", metadata, tiles).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn resolves_overlapping_tiles_by_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = vec![
+            input(
+                dir.path(),
+                "a",
+                "2024-01-01T00:00:00Z",
+                "first",
+                Tile::new(1, 0, 0),
+            ),
+            input(
+                dir.path(),
+                "b",
+                "2024-02-01T00:00:00Z",
+                "largest",
+                Tile::new(1, 0, 1),
+            ),
+            input(
+                dir.path(),
+                "c",
+                "2025-01-01T00:00:00Z",
+                "newest",
+                Tile::new(1, 1, 1),
+            ),
+            input(
+                dir.path(),
+                "d",
+                "2024-03-01T00:00:00Z",
+                "last",
+                Tile::new(1, 1, 0),
+            ),
+        ];
+
+        for (policy, expected) in [
+            (MergePolicy::First, "first"),
+            (MergePolicy::Last, "last"),
+            (MergePolicy::Largest, "largest"),
+            (MergePolicy::Newest, "newest"),
+        ] {
+            let output = dir.path().join("merged.pmtiles");
+            run(MergeArgs {
+                inputs: inputs.clone(),
+                output: output.clone(),
+                force: true,
+                policy,
+                stats: crate::cli::TilestatsArgs { tilestats: false },
+            })
+            .await
+            .unwrap();
+
+            let reader = AppendReader::new(&output).await.unwrap();
+            assert_eq!(reader.get_tiles().await.unwrap().len(), 5);
+            let data = reader.get_tile(&Tile::new(0, 0, 0)).await.unwrap();
+            assert_eq!(data.as_deref(), Some(expected.as_bytes()), "{:?}", policy);
+            let data = reader.get_tile(&Tile::new(1, 1, 0)).await.unwrap();
+            assert_eq!(data.as_deref(), Some(&b"d"[..]));
+            let metadata = reader.metadata_json().await.unwrap();
+            assert_eq!(metadata["name"], "a");
+            assert_eq!(metadata["merged_from"].as_array().unwrap().len(), 4);
+        }
+    }
+}
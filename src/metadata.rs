@@ -1,30 +1,211 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    fs::File,
+    io::BufReader,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::{Context, Result, bail};
 use serde::Serialize;
+use serde_json::{Map, Value};
 
-use crate::cli::Cli;
+use crate::{
+    cli::DownloadArgs,
+    tile_urls::redact_url,
+    vector_layers::{TileStats, VectorLayer},
+};
 
 #[derive(Serialize)]
 pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub attribution: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector_layers: Option<Vec<VectorLayer>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tilestats: Option<TileStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// Arbitrary keys from `--metadata-file` and `--metadata`
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Where and when the archive was downloaded from.
+#[derive(Serialize)]
+pub struct Provenance {
+    /// The URL template, with anything that looks like a secret redacted
+    pub source: String,
+    /// Sources tried in order when `source` doesn't have a tile
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fallback_sources: Vec<String>,
+    /// Sources blended on top of `source` (`--composite`)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub composited_sources: Vec<String>,
+    pub generator: String,
+    pub downloaded_at: String,
+    pub requested_tiles: usize,
+    /// Filled in when the writer finishes
+    #[serde(flatten)]
+    pub tiles: Option<TileCounts>,
+}
+
+/// What happened to the requested tiles, recorded in the provenance once the writer finishes.
+#[derive(Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileCounts {
+    pub downloaded_tiles: u64,
+    pub written_tiles: u64,
+    pub skipped_tiles: u64,
+    pub failed_tiles: u64,
+    /// Tiles the source didn't have, generated from their ancestors (`--fill-from-parent`)
+    #[serde(skip_serializing_if = "is_zero")]
+    pub filled_from_parent: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
 }
 
 impl Metadata {
-    pub fn new(cli: &Cli) -> Self {
+    pub fn new(cli: &DownloadArgs, requested_tiles: usize) -> Result<Self> {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("It can't be before 1970...")
             .as_secs();
-        let version = format!("1.0.0.{}", now);
 
+        // Precedence, lowest to highest: metadata file, --metadata pairs, dedicated flags.
+        let mut extra = Map::new();
+        if let Some(path) = &cli.metadata_file {
+            let file = File::open(path)
+                .with_context(|| format!("Failed to open metadata file {}", path.display()))?;
+            let value: Value = serde_json::from_reader(BufReader::new(file))
+                .with_context(|| format!("Failed to parse metadata file {}", path.display()))?;
+            let Value::Object(map) = value else {
+                bail!(
+                    "Metadata file {} must contain a JSON object",
+                    path.display()
+                );
+            };
+            extra.extend(map);
+        }
+        for (key, value) in &cli.metadata {
+            extra.insert(key.clone(), value.clone());
+        }
+
+        // Keys with dedicated fields are taken out of `extra` so they are not serialized twice.
+        // A dedicated flag replaces the key; otherwise values that aren't strings are kept as-is.
+        let mut take = |key: &str, flag: &Option<String>| {
+            if flag.is_some() {
+                extra.remove(key);
+                return flag.clone();
+            }
+            take_string(&mut extra, key)
+        };
+        let name = take("name", &cli.name);
+        let description = take("description", &cli.description);
+        let attribution = take("attribution", &cli.attribution);
+        let version = take("version", &None)
+            .or_else(|| (!extra.contains_key("version")).then(|| format!("1.0.0.{}", now)));
+        extra.remove("provenance");
+        let other_sources = cli.urls[1..].iter().map(|u| redact_url(u));
+        if cli.tile_size != 256 || cli.dem.is_some() {
+            extra.insert("tileSize".to_string(), cli.tile_size.into());
+        }
+        if let Some(encoding) = cli.dem_convert.or(cli.dem) {
+            extra.extend(encoding.metadata());
+        }
+
+        Ok(Self {
+            name,
+            description,
+            attribution,
+            version,
+            vector_layers: None,
+            tilestats: None,
+            provenance: Some(Provenance {
+                source: redact_url(&cli.urls[0]),
+                fallback_sources: other_sources.clone().filter(|_| !cli.composite).collect(),
+                composited_sources: other_sources.filter(|_| cli.composite).collect(),
+                generator: format!("tile-download-tool {}", env!("CARGO_PKG_VERSION")),
+                downloaded_at: chrono::Utc::now()
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                requested_tiles,
+                tiles: None,
+            }),
+            extra,
+        })
+    }
+
+    /// Builds metadata from the JSON metadata of an existing archive, e.g. when merging or extracting.
+    /// `vector_layers` and `tilestats` are left in `extra`; the writer replaces them for vector tilesets.
+    pub fn from_json(mut extra: Map<String, Value>) -> Self {
         Self {
-            name: cli.name.clone(),
-            description: cli.description.clone(),
-            attribution: cli.attribution.clone(),
-            version: Some(version),
+            name: take_string(&mut extra, "name"),
+            description: take_string(&mut extra, "description"),
+            attribution: take_string(&mut extra, "attribution"),
+            version: take_string(&mut extra, "version"),
+            vector_layers: None,
+            tilestats: None,
+            provenance: None,
+            extra,
         }
     }
 }
+
+/// Removes `key` from `extra` if it is a string. Other values are left in place.
+fn take_string(extra: &mut Map<String, Value>, key: &str) -> Option<String> {
+    let value = extra.get(key)?.as_str()?.to_string();
+    extra.remove(key);
+    Some(value)
+}
+
+/// Parses a `key=value` pair for `--metadata`. The value is parsed as JSON if
+/// possible, otherwise it is used as a string.
+pub fn parse_metadata_pair(s: &str) -> Result<(String, Value)> {
+    let Some((key, value)) = s.split_once('=') else {
+        bail!("Invalid metadata \"{}\". Expected format: key=value", s);
+    };
+    if key.is_empty() {
+        bail!("Invalid metadata \"{}\". The key must not be empty", s);
+    }
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    Ok((key.to_string(), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn keeps_metadata_values_that_are_not_strings() {
+        let Value::Object(map) = json!({"name": "Roads", "version": 3, "description": {"en": "x"}})
+        else {
+            unreachable!()
+        };
+        let metadata = Metadata::from_json(map);
+        assert_eq!(metadata.name.as_deref(), Some("Roads"));
+        assert_eq!(metadata.version, None);
+        let value = serde_json::to_value(&metadata).unwrap();
+        assert_eq!(value["version"], json!(3));
+        assert_eq!(value["description"], json!({"en": "x"}));
+        assert_eq!(value["name"], json!("Roads"));
+    }
+
+    #[test]
+    fn parses_metadata_pairs() {
+        assert_eq!(
+            parse_metadata_pair("minzoom=3").unwrap(),
+            ("minzoom".to_string(), json!(3))
+        );
+        assert_eq!(
+            parse_metadata_pair("type=overlay").unwrap(),
+            ("type".to_string(), json!("overlay"))
+        );
+        assert!(parse_metadata_pair("=x").is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! Prometheus metrics for a running download, fed from the same messages as the progress display.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+};
+
+use tokio::net::TcpListener;
+
+use crate::{
+    http_server::{self, Request, Response, Server},
+    progress::{ProgressListener, ProgressMsg},
+};
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Completed requests by status code; None for requests that got no response
+    requests: Mutex<BTreeMap<Option<u16>, u64>>,
+    requests_in_flight: AtomicI64,
+    retries: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    tiles_downloaded: AtomicU64,
+    tiles_written: AtomicU64,
+    tiles_skipped: AtomicU64,
+    tiles_failed: AtomicU64,
+    reorder_buffer: AtomicU64,
+    concurrency: u64,
+}
+
+impl Metrics {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            concurrency: concurrency as u64,
+            ..Default::default()
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            for (labels, value) in samples {
+                writeln!(out, "{}{} {}", name, labels, value).unwrap();
+            }
+        };
+        let sample = |value: &dyn ToString| vec![(String::new(), value.to_string())];
+        let load = |counter: &AtomicU64| sample(&counter.load(Ordering::Relaxed));
+
+        let requests = self
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(status, count)| {
+                let status = status.map_or("error".to_string(), |s| s.to_string());
+                (format!("{{status=\"{}\"}}", status), count.to_string())
+            })
+            .collect::<Vec<_>>();
+        metric(
+            "tile_download_requests_total",
+            "counter",
+            "HTTP requests by response status code, or \"error\" if no response was received.",
+            &requests,
+        );
+        metric(
+            "tile_download_requests_in_flight",
+            "gauge",
+            "HTTP requests currently in progress.",
+            &sample(&self.requests_in_flight.load(Ordering::Relaxed)),
+        );
+        metric(
+            "tile_download_concurrency",
+            "gauge",
+            "The configured download concurrency.",
+            &sample(&self.concurrency),
+        );
+        metric(
+            "tile_download_retries_total",
+            "counter",
+            "Failed requests that were retried.",
+            &load(&self.retries),
+        );
+        metric(
+            "tile_download_downloaded_bytes_total",
+            "counter",
+            "Bytes of tile data downloaded.",
+            &load(&self.downloaded_bytes),
+        );
+        metric(
+            "tile_download_tiles_downloaded_total",
+            "counter",
+            "Tiles downloaded.",
+            &load(&self.tiles_downloaded),
+        );
+        metric(
+            "tile_download_tiles_written_total",
+            "counter",
+            "Tiles written to the archive.",
+            &load(&self.tiles_written),
+        );
+        metric(
+            "tile_download_tiles_skipped_total",
+            "counter",
+            "Tiles the source didn't have.",
+            &load(&self.tiles_skipped),
+        );
+        metric(
+            "tile_download_tiles_failed_total",
+            "counter",
+            "Tiles that failed to download.",
+            &load(&self.tiles_failed),
+        );
+        metric(
+            "tile_download_reorder_buffer_tiles",
+            "gauge",
+            "Tiles waiting to be written in order.",
+            &load(&self.reorder_buffer),
+        );
+        out
+    }
+}
+
+impl ProgressListener for Metrics {
+    fn record(&self, msg: &ProgressMsg) {
+        let inc = |counter: &AtomicU64, n: u64| {
+            counter.fetch_add(n, Ordering::Relaxed);
+        };
+        match msg {
+            ProgressMsg::RequestStarted() => {
+                self.requests_in_flight.fetch_add(1, Ordering::Relaxed);
+            }
+            ProgressMsg::RequestFinished(status, _) => {
+                self.requests_in_flight.fetch_sub(1, Ordering::Relaxed);
+                *self.requests.lock().unwrap().entry(*status).or_default() += 1;
+            }
+            ProgressMsg::Retry() => inc(&self.retries, 1),
+            ProgressMsg::Downloaded(_, size) => {
+                inc(&self.tiles_downloaded, 1);
+                inc(&self.downloaded_bytes, *size as u64);
+            }
+            ProgressMsg::Written(_) => inc(&self.tiles_written, 1),
+            ProgressMsg::Skipped(_) => inc(&self.tiles_skipped, 1),
+            ProgressMsg::Failed(_, _) => inc(&self.tiles_failed, 1),
+            ProgressMsg::ReorderBuffer(depth) => {
+                self.reorder_buffer.store(*depth as u64, Ordering::Relaxed)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Serves the metrics at `/metrics` until dropped, so a later job can listen on the same address.
+pub fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> Server {
+    http_server::serve(listener, move |request: Request| {
+        let metrics = metrics.clone();
+        async move {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET" | "HEAD", "/metrics") => Response::new(200)
+                    .with_header("Content-Type", "text/plain; version=0.0.4")
+                    .with_body(metrics.render()),
+                _ => Response::not_found(),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile::Tile;
+    use std::time::Duration;
+
+    #[test]
+    fn renders_recorded_messages() {
+        let metrics = Metrics::new(4);
+        for msg in [
+            ProgressMsg::RequestStarted(),
+            ProgressMsg::RequestFinished(Some(503), Duration::ZERO),
+            ProgressMsg::Retry(),
+            ProgressMsg::RequestStarted(),
+            ProgressMsg::RequestFinished(Some(200), Duration::ZERO),
+            ProgressMsg::Downloaded(Tile::new(1, 0, 0), 1234),
+            ProgressMsg::RequestStarted(),
+            ProgressMsg::RequestFinished(None, Duration::ZERO),
+            ProgressMsg::RequestStarted(),
+            ProgressMsg::ReorderBuffer(3),
+        ] {
+            metrics.record(&msg);
+        }
+
+        let rendered = metrics.render();
+        for line in [
+            "# TYPE tile_download_requests_total counter",
+            "tile_download_requests_total{status=\"error\"} 1",
+            "tile_download_requests_total{status=\"200\"} 1",
+            "tile_download_requests_total{status=\"503\"} 1",
+            "tile_download_requests_in_flight 1",
+            "tile_download_concurrency 4",
+            "tile_download_retries_total 1",
+            "tile_download_downloaded_bytes_total 1234",
+            "tile_download_tiles_downloaded_total 1",
+            "tile_download_reorder_buffer_tiles 3",
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "{} in\n{}",
+                line,
+                rendered
+            );
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! A mock XYZ tile server for tests and benchmarks, also available as the hidden `mock-server`
+//! subcommand. Every z/x/y has a deterministic synthetic tile, and the server can be made slow,
+//! flaky or rate limited.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use image::{DynamicImage, Rgba, RgbaImage};
+use pmtiles::TileType;
+use tokio::{net::TcpListener, time::sleep};
+
+use crate::{
+    cli::MockServerArgs,
+    http_server::{self, Request, Response, Server},
+    mvt,
+    progress::StatusOut,
+    raster,
+    serve::parse_tile_path,
+    tile::Tile,
+    tile_list::SimpleBBox,
+};
+
+/// Tiles never change, so every response has the same Last-Modified date.
+const LAST_MODIFIED: &str = "Thu, 01 Jan 2026 00:00:00 GMT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MockFormat {
+    /// Solid-colored 256x256 PNG tiles
+    Png,
+    /// Vector tiles with a single point, in a layer named "mock"
+    Mvt,
+}
+
+impl MockFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            MockFormat::Png => "png",
+            MockFormat::Mvt => "mvt",
+        }
+    }
+}
+
+/// What the mock server serves, and how badly it behaves.
+pub struct MockTiles {
+    format: MockFormat,
+    latency: Duration,
+    error_rate: f64,
+    error_attempts: u32,
+    error_status: u16,
+    /// (every, burst): the first `burst` of every `every` requests are answered with 429
+    rate_limit: Option<(u64, u64)>,
+    retry_after: Option<u64>,
+    blank: Vec<SimpleBBox>,
+    max_zoom: Option<u8>,
+    requests: AtomicU64,
+    attempts: Mutex<HashMap<Tile, u32>>,
+}
+
+impl MockTiles {
+    pub fn new(format: MockFormat) -> Self {
+        Self {
+            format,
+            latency: Duration::ZERO,
+            error_rate: 0.0,
+            error_attempts: 1,
+            error_status: 503,
+            rate_limit: None,
+            retry_after: None,
+            blank: Vec::new(),
+            max_zoom: None,
+            requests: AtomicU64::new(0),
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_args(args: &MockServerArgs) -> Result<Self> {
+        let mut tiles = Self::new(args.format)
+            .with_latency(Duration::from_millis(args.latency_ms))
+            .with_error_rate(args.error_rate)
+            .with_error_attempts(args.error_attempts)
+            .with_error_status(args.error_status);
+        if let Some(every) = args.rate_limit_every {
+            tiles = tiles.with_rate_limit(every, args.rate_limit_burst);
+        }
+        if let Some(seconds) = args.retry_after {
+            tiles = tiles.with_retry_after(seconds);
+        }
+        for bbox in &args.blank {
+            let bbox = bbox
+                .parse()
+                .with_context(|| format!("Invalid --blank bbox: {}", bbox))?;
+            tiles = tiles.with_blank_region(bbox);
+        }
+        if let Some(max_zoom) = args.max_zoom {
+            tiles = tiles.with_max_zoom(max_zoom);
+        }
+        Ok(tiles)
+    }
+
+    /// Delays every tile response.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// The fraction of tiles, picked by a hash of their coordinates, whose first requests fail.
+    pub fn with_error_rate(mut self, error_rate: f64) -> Self {
+        self.error_rate = error_rate;
+        self
+    }
+
+    /// How many requests for a failing tile fail before it is served. Defaults to 1.
+    pub fn with_error_attempts(mut self, attempts: u32) -> Self {
+        self.error_attempts = attempts;
+        self
+    }
+
+    /// The status code of failed requests. Defaults to 503.
+    pub fn with_error_status(mut self, status: u16) -> Self {
+        self.error_status = status;
+        self
+    }
+
+    /// Answers the first `burst` of every `every` requests with 429 Too Many Requests.
+    pub fn with_rate_limit(mut self, every: u64, burst: u64) -> Self {
+        self.rate_limit = Some((every.max(1), burst));
+        self
+    }
+
+    /// Sends `Retry-After` with 429 and 503 responses.
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    /// Serves blank tiles (a transparent PNG or an empty vector tile) for tiles whose center is
+    /// inside `bbox`.
+    pub fn with_blank_region(mut self, bbox: SimpleBBox) -> Self {
+        self.blank.push(bbox);
+        self
+    }
+
+    /// Answers 404 for tiles above `max_zoom`.
+    pub fn with_max_zoom(mut self, max_zoom: u8) -> Self {
+        self.max_zoom = Some(max_zoom);
+        self
+    }
+
+    /// Listens on `addr` until the returned server is dropped. Port 0 picks a free port.
+    pub async fn start(self, addr: SocketAddr) -> Result<MockServer> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        let addr = listener.local_addr()?;
+        let tiles = Arc::new(self);
+        let handler_tiles = tiles.clone();
+        let server = http_server::serve(listener, move |request: Request| {
+            let tiles = handler_tiles.clone();
+            async move { tiles.respond(&request).await }
+        });
+        Ok(MockServer {
+            addr,
+            tiles,
+            _server: server,
+        })
+    }
+
+    async fn respond(&self, request: &Request) -> Response {
+        let n = self.requests.fetch_add(1, Ordering::SeqCst);
+        if !matches!(request.method.as_str(), "GET" | "HEAD") {
+            return Response::new(405);
+        }
+        let Some((tile, ext)) = parse_tile_path(&request.path) else {
+            return Response::not_found();
+        };
+        if ext != self.format.extension() {
+            return Response::not_found();
+        }
+        let attempt = {
+            let mut attempts = self.attempts.lock().unwrap();
+            let attempt = attempts.entry(tile.clone()).or_insert(0);
+            *attempt += 1;
+            *attempt
+        };
+        sleep(self.latency).await;
+
+        if let Some((every, burst)) = self.rate_limit
+            && n % every < burst
+        {
+            return self.error(429);
+        }
+        let hash = tile_hash(&tile);
+        if attempt <= self.error_attempts && (hash as f64 / u64::MAX as f64) < self.error_rate {
+            return self.error(self.error_status);
+        }
+        if self.is_missing(&tile) {
+            return Response::not_found();
+        }
+
+        let etag = format!("\"{:016x}\"", hash);
+        let not_modified = match request.header("If-None-Match") {
+            Some(if_none_match) => if_none_match == etag,
+            None => request.header("If-Modified-Since") == Some(LAST_MODIFIED),
+        };
+        let response = if not_modified {
+            Response::new(304)
+        } else {
+            match self.generate(&tile, hash) {
+                Ok(data) => Response::new(200)
+                    .with_header("Content-Type", self.content_type())
+                    .with_body(data),
+                Err(e) => return Response::new(500).with_body(format!("{:#}\n", e)),
+            }
+        };
+        response
+            .with_header("ETag", etag)
+            .with_header("Last-Modified", LAST_MODIFIED)
+    }
+
+    fn error(&self, status: u16) -> Response {
+        let mut response = Response::new(status);
+        if let Some(seconds) = self.retry_after
+            && matches!(status, 429 | 503)
+        {
+            response = response.with_header("Retry-After", seconds.to_string());
+        }
+        response
+    }
+
+    fn is_missing(&self, tile: &Tile) -> bool {
+        self.max_zoom.is_some_and(|max_zoom| tile.z() > max_zoom)
+    }
+
+    fn is_blank(&self, tile: &Tile) -> bool {
+        let (min_lon, min_lat, max_lon, max_lat) = tile.bounds();
+        let (lon, lat) = ((min_lon + max_lon) / 2.0, (min_lat + max_lat) / 2.0);
+        self.blank.iter().any(|bbox| {
+            (bbox.min_x()..=bbox.max_x()).contains(&lon)
+                && (bbox.min_y()..=bbox.max_y()).contains(&lat)
+        })
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self.format {
+            MockFormat::Png => "image/png",
+            MockFormat::Mvt => "application/vnd.mapbox-vector-tile",
+        }
+    }
+
+    /// The data served for a tile once its requests succeed, or None if it is answered with 404.
+    #[cfg(test)]
+    pub fn tile_data(&self, tile: &Tile) -> Option<Vec<u8>> {
+        (!self.is_missing(tile)).then(|| self.generate(tile, tile_hash(tile)).unwrap())
+    }
+
+    fn generate(&self, tile: &Tile, hash: u64) -> Result<Vec<u8>> {
+        if self.is_blank(tile) {
+            return match self.format {
+                MockFormat::Png => {
+                    let img = RgbaImage::from_pixel(256, 256, Rgba([0, 0, 0, 0]));
+                    raster::encode(&DynamicImage::ImageRgba8(img), TileType::Png)
+                }
+                MockFormat::Mvt => mvt::encode(&mvt::VectorTile::default(), false),
+            };
+        }
+        match self.format {
+            MockFormat::Png => {
+                let [r, g, b, ..] = hash.to_le_bytes();
+                let img = RgbaImage::from_pixel(256, 256, Rgba([r, g, b, 255]));
+                raster::encode(&DynamicImage::ImageRgba8(img), TileType::Png)
+            }
+            MockFormat::Mvt => mvt::encode(&mock_vector_tile(tile), false),
+        }
+    }
+}
+
+/// A running mock server. Stops when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    tiles: Arc<MockTiles>,
+    _server: Server,
+}
+
+impl MockServer {
+    /// The XYZ URL template of the served tiles.
+    pub fn url(&self) -> String {
+        format!(
+            "http://{}/{{z}}/{{x}}/{{y}}.{}",
+            self.addr,
+            self.tiles.format.extension()
+        )
+    }
+
+    /// The number of requests received so far.
+    pub fn requests(&self) -> u64 {
+        self.tiles.requests.load(Ordering::SeqCst)
+    }
+}
+
+/// Runs the hidden `mock-server` subcommand until Ctrl-C.
+pub async fn run(args: MockServerArgs) -> Result<()> {
+    let server = MockTiles::from_args(&args)?.start(args.listen).await?;
+    let out = StatusOut::stdout();
+    out.println(format_args!("Serving mock tiles at {}", server.url()));
+    tokio::signal::ctrl_c().await?;
+    out.println(format_args!("Answered {} requests", server.requests()));
+    Ok(())
+}
+
+/// A stable pseudo-random number for a tile (SplitMix64 over its coordinates).
+fn tile_hash(tile: &Tile) -> u64 {
+    let mix = |mut z: u64| {
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    let seed = ((tile.z() as u64) << 58) ^ ((tile.x() as u64) << 29) ^ tile.y() as u64;
+    mix(seed.wrapping_add(0x9e3779b97f4a7c15))
+}
+
+/// A point in the middle of the tile, tagged with its coordinates.
+fn mock_vector_tile(tile: &Tile) -> mvt::VectorTile {
+    // MoveTo(1), then the zigzag-encoded offset to (2048, 2048)
+    let geometry = vec![9, 4096, 4096];
+    mvt::VectorTile {
+        layers: vec![mvt::Layer {
+            version: 2,
+            name: "mock".to_string(),
+            features: vec![mvt::Feature {
+                id: Some(1),
+                tags: vec![0, 0],
+                r#type: Some(1),
+                geometry,
+            }],
+            keys: vec!["tile".to_string()],
+            values: vec![mvt::Value {
+                string_value: Some(tile.to_string()),
+                ..Default::default()
+            }],
+            extent: Some(4096),
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_the_same_tile_every_time() {
+        let tiles = MockTiles::new(MockFormat::Mvt);
+        let tile = Tile::new(5, 10, 12);
+        let data = tiles.generate(&tile, tile_hash(&tile)).unwrap();
+        assert_eq!(data, tiles.generate(&tile, tile_hash(&tile)).unwrap());
+        let decoded = mvt::decode(&data).unwrap();
+        assert_eq!(decoded.layers[0].name, "mock");
+        assert_ne!(tile_hash(&tile), tile_hash(&Tile::new(5, 12, 10)));
+    }
+
+    #[test]
+    fn blanks_regions_and_zooms() {
+        let tiles = MockTiles::new(MockFormat::Mvt)
+            .with_blank_region("-180,-85,-1,85".parse().unwrap())
+            .with_max_zoom(3);
+        assert!(!tiles.is_blank(&Tile::new(0, 0, 0)));
+        assert!(tiles.is_blank(&Tile::new(1, 0, 1)));
+        assert!(!tiles.is_blank(&Tile::new(1, 1, 1)));
+        assert!(!tiles.is_missing(&Tile::new(3, 7, 0)));
+        assert!(tiles.is_missing(&Tile::new(4, 15, 0)));
+        assert_eq!(
+            tiles.tile_data(&Tile::new(1, 0, 1)).as_deref(),
+            Some(&[][..])
+        );
+        assert!(tiles.tile_data(&Tile::new(4, 15, 0)).is_none());
+    }
+}
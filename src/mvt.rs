@@ -0,0 +1,526 @@
+//! Minimal Mapbox Vector Tile (MVT) protobuf definitions.
+//!
+//! Only the subset of the spec needed to inspect and rewrite tiles is
+//! modelled here; see https://github.com/mapbox/vector-tile-spec/tree/master/2.1
+
+use std::{
+    borrow::Cow,
+    io::{Read, Write},
+};
+
+use anyhow::{Result, bail};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use prost::Message;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct VectorTile {
+    #[prost(message, repeated, tag = "3")]
+    pub layers: Vec<Layer>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Layer {
+    #[prost(uint32, required, tag = "15", default = "1")]
+    pub version: u32,
+    #[prost(string, required, tag = "1")]
+    pub name: String,
+    #[prost(message, repeated, tag = "2")]
+    pub features: Vec<Feature>,
+    #[prost(string, repeated, tag = "3")]
+    pub keys: Vec<String>,
+    #[prost(message, repeated, tag = "4")]
+    pub values: Vec<Value>,
+    #[prost(uint32, optional, tag = "5", default = "4096")]
+    pub extent: Option<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Feature {
+    #[prost(uint64, optional, tag = "1", default = "0")]
+    pub id: Option<u64>,
+    #[prost(uint32, repeated, packed = "true", tag = "2")]
+    pub tags: Vec<u32>,
+    #[prost(int32, optional, tag = "3", default = "0")]
+    pub r#type: Option<i32>,
+    #[prost(uint32, repeated, packed = "true", tag = "4")]
+    pub geometry: Vec<u32>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Value {
+    #[prost(string, optional, tag = "1")]
+    pub string_value: Option<String>,
+    #[prost(float, optional, tag = "2")]
+    pub float_value: Option<f32>,
+    #[prost(double, optional, tag = "3")]
+    pub double_value: Option<f64>,
+    #[prost(int64, optional, tag = "4")]
+    pub int_value: Option<i64>,
+    #[prost(uint64, optional, tag = "5")]
+    pub uint_value: Option<u64>,
+    #[prost(sint64, optional, tag = "6")]
+    pub sint_value: Option<i64>,
+    #[prost(bool, optional, tag = "7")]
+    pub bool_value: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GeomType {
+    Unknown,
+    Point,
+    LineString,
+    Polygon,
+}
+
+impl Feature {
+    pub fn geom_type(&self) -> GeomType {
+        match self.r#type() {
+            1 => GeomType::Point,
+            2 => GeomType::LineString,
+            3 => GeomType::Polygon,
+            _ => GeomType::Unknown,
+        }
+    }
+}
+
+/// Returns true if the data starts with the gzip magic bytes.
+pub fn is_gzipped(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+/// Returns the data with gzip compression removed, if it was gzipped.
+pub fn decompress(data: &[u8]) -> Result<Cow<'_, [u8]>> {
+    if is_gzipped(data) {
+        let mut raw = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut raw)?;
+        Ok(Cow::Owned(raw))
+    } else {
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+/// Decodes a vector tile, transparently decompressing gzipped data.
+pub fn decode(data: &[u8]) -> Result<VectorTile> {
+    Ok(VectorTile::decode(decompress(data)?.as_ref())?)
+}
+
+/// Encodes a vector tile, gzipping it if requested.
+pub fn encode(tile: &VectorTile, gzip: bool) -> Result<Vec<u8>> {
+    let data = tile.encode_to_vec();
+    if !gzip {
+        return Ok(data);
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&data)?;
+    Ok(encoder.finish()?)
+}
+
+const MOVE_TO: u32 = 1;
+const LINE_TO: u32 = 2;
+const CLOSE_PATH: u32 = 7;
+
+/// A geometry command with absolute coordinates.
+type Command = (u32, Vec<(i64, i64)>);
+
+fn zigzag(v: i64) -> u32 {
+    let v = v.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+    ((v << 1) ^ (v >> 31)) as u32
+}
+
+fn unzigzag(v: u32) -> i64 {
+    ((v >> 1) as i32 ^ -((v & 1) as i32)) as i64
+}
+
+fn decode_geometry(geometry: &[u32]) -> Result<Vec<Command>> {
+    let mut commands = Vec::new();
+    let (mut x, mut y) = (0i64, 0i64);
+    let mut values = geometry.iter();
+    while let Some(header) = values.next() {
+        let (id, count) = (header & 7, header >> 3);
+        let mut points = Vec::new();
+        if matches!(id, MOVE_TO | LINE_TO) {
+            for _ in 0..count {
+                let (Some(dx), Some(dy)) = (values.next(), values.next()) else {
+                    bail!("Truncated geometry");
+                };
+                x += unzigzag(*dx);
+                y += unzigzag(*dy);
+                points.push((x, y));
+            }
+        } else if id != CLOSE_PATH {
+            bail!("Unknown geometry command {}", id);
+        }
+        commands.push((id, points));
+    }
+    Ok(commands)
+}
+
+fn encode_geometry(commands: &[Command]) -> Vec<u32> {
+    let mut geometry = Vec::new();
+    let (mut x, mut y) = (0i64, 0i64);
+    for (id, points) in commands {
+        let count = if *id == CLOSE_PATH { 1 } else { points.len() };
+        geometry.push(id | ((count as u32) << 3));
+        for (px, py) in points {
+            geometry.push(zigzag(px - x));
+            geometry.push(zigzag(py - y));
+            (x, y) = (*px, *py);
+        }
+    }
+    geometry
+}
+
+/// Rescales an ancestor tile to the extent of a tile `dz` zoom levels below it, at offset
+/// (`dx`, `dy`) in units of that tile. Geometry is clipped to the tile plus a small buffer, and
+/// features that don't reach it are dropped.
+pub fn overzoom(tile: &VectorTile, dz: u8, (dx, dy): (u32, u32)) -> Result<VectorTile> {
+    let mut out = VectorTile::default();
+    for layer in &tile.layers {
+        let extent = layer.extent() as i64;
+        let buffer = extent / 16;
+        let scale = 1i64 << dz;
+        let (ox, oy) = (dx as i64 * extent, dy as i64 * extent);
+
+        let mut features = Vec::new();
+        for feature in &layer.features {
+            let mut commands = decode_geometry(&feature.geometry)?;
+            for (x, y) in commands.iter_mut().flat_map(|(_, points)| points) {
+                (*x, *y) = (*x * scale - ox, *y * scale - oy);
+            }
+            let commands = clip_geometry(feature.geom_type(), commands, -buffer, extent + buffer);
+            if commands.is_empty() {
+                continue;
+            }
+            features.push(Feature {
+                geometry: encode_geometry(&commands),
+                ..feature.clone()
+            });
+        }
+        if !features.is_empty() {
+            out.layers.push(Layer {
+                features,
+                ..layer.clone()
+            });
+        }
+    }
+    Ok(out)
+}
+
+/// Clips geometry to the square from `min` to `max` on both axes. Returns no commands if nothing
+/// is left.
+fn clip_geometry(geom_type: GeomType, commands: Vec<Command>, min: i64, max: i64) -> Vec<Command> {
+    let inside = |(x, y): (i64, i64)| (min..=max).contains(&x) && (min..=max).contains(&y);
+    match geom_type {
+        GeomType::Point => {
+            let points = commands
+                .into_iter()
+                .flat_map(|(_, points)| points)
+                .filter(|p| inside(*p))
+                .collect::<Vec<_>>();
+            if points.is_empty() {
+                return Vec::new();
+            }
+            vec![(MOVE_TO, points)]
+        }
+        GeomType::LineString => split_parts(commands)
+            .iter()
+            .flat_map(|line| clip_line(line, min, max))
+            .flat_map(|line| [(MOVE_TO, line[..1].to_vec()), (LINE_TO, line[1..].to_vec())])
+            .collect(),
+        GeomType::Polygon => split_parts(commands)
+            .iter()
+            .map(|ring| clip_ring(ring, min, max))
+            .filter(|ring| ring.len() >= 3 && ring_area(ring) != 0)
+            .flat_map(|ring| {
+                [
+                    (MOVE_TO, ring[..1].to_vec()),
+                    (LINE_TO, ring[1..].to_vec()),
+                    (CLOSE_PATH, Vec::new()),
+                ]
+            })
+            .collect(),
+        // Without a type we can't tell how to clip, so keep the feature whole if it reaches the tile
+        GeomType::Unknown => {
+            let (mut lo, mut hi) = ((i64::MAX, i64::MAX), (i64::MIN, i64::MIN));
+            for (x, y) in commands.iter().flat_map(|(_, points)| points) {
+                (lo, hi) = ((lo.0.min(*x), lo.1.min(*y)), (hi.0.max(*x), hi.1.max(*y)));
+            }
+            if hi.0 < min || hi.1 < min || lo.0 > max || lo.1 > max {
+                return Vec::new();
+            }
+            commands
+        }
+    }
+}
+
+/// Splits commands into line strings or rings, each starting at a MoveTo.
+fn split_parts(commands: Vec<Command>) -> Vec<Vec<(i64, i64)>> {
+    let mut parts: Vec<Vec<(i64, i64)>> = Vec::new();
+    for (id, points) in commands {
+        match (id, parts.last_mut()) {
+            (MOVE_TO, _) | (LINE_TO, None) => parts.push(points),
+            (LINE_TO, Some(part)) => part.extend(points),
+            _ => {}
+        }
+    }
+    parts
+}
+
+/// Clips a line string, which may split it into several.
+fn clip_line(line: &[(i64, i64)], min: i64, max: i64) -> Vec<Vec<(i64, i64)>> {
+    let mut lines = Vec::new();
+    let mut current: Vec<(i64, i64)> = Vec::new();
+    for segment in line.windows(2) {
+        let Some((a, b)) = clip_segment(segment[0], segment[1], min, max) else {
+            if current.len() >= 2 {
+                lines.push(std::mem::take(&mut current));
+            }
+            current.clear();
+            continue;
+        };
+        if current.last() != Some(&a) {
+            if current.len() >= 2 {
+                lines.push(std::mem::take(&mut current));
+            }
+            current = vec![a];
+        }
+        if current.last() != Some(&b) {
+            current.push(b);
+        }
+    }
+    if current.len() >= 2 {
+        lines.push(current);
+    }
+    lines
+}
+
+/// Clips a segment to the square (Liang-Barsky). Returns None if it lies outside.
+fn clip_segment(
+    a: (i64, i64),
+    b: (i64, i64),
+    min: i64,
+    max: i64,
+) -> Option<((i64, i64), (i64, i64))> {
+    let (x0, y0) = (a.0 as f64, a.1 as f64);
+    let (dx, dy) = ((b.0 - a.0) as f64, (b.1 - a.1) as f64);
+    let (min, max) = (min as f64, max as f64);
+    let (mut t0, mut t1) = (0.0f64, 1.0f64);
+    for (p, q) in [
+        (-dx, x0 - min),
+        (dx, max - x0),
+        (-dy, y0 - min),
+        (dy, max - y0),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+    }
+    if t0 > t1 {
+        return None;
+    }
+    let at = |t: f64| ((x0 + t * dx).round() as i64, (y0 + t * dy).round() as i64);
+    Some((
+        if t0 == 0.0 { a } else { at(t0) },
+        if t1 == 1.0 { b } else { at(t1) },
+    ))
+}
+
+/// Clips a ring (without its closing point) to the square (Sutherland-Hodgman).
+fn clip_ring(ring: &[(i64, i64)], min: i64, max: i64) -> Vec<(i64, i64)> {
+    let mut out = ring.to_vec();
+    // Left, right, top and bottom edges: whether a point is inside, and where a segment crosses
+    for edge in 0..4 {
+        let input = std::mem::take(&mut out);
+        let Some(&last) = input.last() else {
+            break;
+        };
+        let (axis, bound) = match edge {
+            0 => (0, min),
+            1 => (0, max),
+            2 => (1, min),
+            _ => (1, max),
+        };
+        let coord = |p: (i64, i64)| if axis == 0 { p.0 } else { p.1 };
+        let inside = |p: (i64, i64)| {
+            if bound == min {
+                coord(p) >= bound
+            } else {
+                coord(p) <= bound
+            }
+        };
+        let cross = |a: (i64, i64), b: (i64, i64)| {
+            let t = (bound - coord(a)) as f64 / (coord(b) - coord(a)) as f64;
+            if axis == 0 {
+                (bound, (a.1 as f64 + t * (b.1 - a.1) as f64).round() as i64)
+            } else {
+                ((a.0 as f64 + t * (b.0 - a.0) as f64).round() as i64, bound)
+            }
+        };
+        let mut prev = last;
+        for &p in &input {
+            match (inside(prev), inside(p)) {
+                (true, true) => out.push(p),
+                (true, false) => out.push(cross(prev, p)),
+                (false, true) => {
+                    out.push(cross(prev, p));
+                    out.push(p);
+                }
+                (false, false) => {}
+            }
+            prev = p;
+        }
+    }
+    out.dedup();
+    if out.len() > 1 && out.first() == out.last() {
+        out.pop();
+    }
+    out
+}
+
+/// Twice the signed area of a ring.
+fn ring_area(ring: &[(i64, i64)]) -> i64 {
+    let mut area = 0;
+    for (i, a) in ring.iter().enumerate() {
+        let b = ring[(i + 1) % ring.len()];
+        area += a.0 * b.1 - b.0 * a.1;
+    }
+    area
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_tile() -> VectorTile {
+        VectorTile {
+            layers: vec![Layer {
+                version: 2,
+                name: "roads".to_string(),
+                features: vec![Feature {
+                    id: Some(1),
+                    tags: vec![0, 0],
+                    r#type: Some(2),
+                    geometry: vec![9, 0, 0, 10, 2, 2],
+                }],
+                keys: vec!["class".to_string()],
+                values: vec![Value {
+                    string_value: Some("primary".to_string()),
+                    ..Default::default()
+                }],
+                extent: Some(4096),
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trips_plain() {
+        let tile = sample_tile();
+        let data = tile.encode_to_vec();
+        assert!(!is_gzipped(&data));
+        assert_eq!(decode(&data).unwrap(), tile);
+    }
+
+    #[test]
+    fn round_trips_gzipped() {
+        let tile = sample_tile();
+        let data = encode(&tile, true).unwrap();
+        assert!(is_gzipped(&data));
+        let decoded = decode(&data).unwrap();
+        assert_eq!(
+            decoded.layers[0].features[0].geom_type(),
+            GeomType::LineString
+        );
+        assert_eq!(decoded, tile);
+    }
+
+    #[test]
+    fn overzooms_geometry() {
+        let tile = sample_tile();
+        let geometry = &tile.layers[0].features[0].geometry;
+        assert_eq!(
+            &encode_geometry(&decode_geometry(geometry).unwrap()),
+            geometry
+        );
+
+        // The line from (0,0) to (1,1) lies in the top-left child
+        let child = overzoom(&tile, 1, (0, 0)).unwrap();
+        let geometry = decode_geometry(&child.layers[0].features[0].geometry).unwrap();
+        assert_eq!(
+            geometry,
+            vec![(MOVE_TO, vec![(0, 0)]), (LINE_TO, vec![(2, 2)])]
+        );
+        assert_eq!(child.layers[0].keys, tile.layers[0].keys);
+
+        let child = overzoom(&tile, 1, (1, 1)).unwrap();
+        assert!(child.layers.is_empty());
+    }
+
+    #[test]
+    fn clips_overzoomed_geometry_to_the_child() {
+        let feature = |r#type: i32, geometry: Vec<u32>| Feature {
+            id: None,
+            tags: vec![],
+            r#type: Some(r#type),
+            geometry,
+        };
+        let commands = |commands: Vec<Command>| encode_geometry(&commands);
+        let tile = VectorTile {
+            layers: vec![Layer {
+                version: 2,
+                name: "crossing".to_string(),
+                features: vec![
+                    // A line across the whole parent, through the boundary between its quadrants
+                    feature(
+                        2,
+                        commands(vec![
+                            (MOVE_TO, vec![(0, 1024)]),
+                            (LINE_TO, vec![(4096, 1024)]),
+                        ]),
+                    ),
+                    // A square around the center of the parent
+                    feature(
+                        3,
+                        commands(vec![
+                            (MOVE_TO, vec![(1024, 1024)]),
+                            (LINE_TO, vec![(3072, 1024), (3072, 3072), (1024, 3072)]),
+                            (CLOSE_PATH, vec![]),
+                        ]),
+                    ),
+                    // Points on both sides of the boundary
+                    feature(
+                        1,
+                        commands(vec![(MOVE_TO, vec![(1000, 1000), (3000, 1000)])]),
+                    ),
+                ],
+                keys: vec![],
+                values: vec![],
+                extent: Some(4096),
+            }],
+        };
+
+        // The top-left child spans 0..2048 of the parent; the buffer is 256 at the child's scale
+        let child = overzoom(&tile, 1, (0, 0)).unwrap();
+        let features = &child.layers[0].features;
+        assert_eq!(features.len(), 3);
+        let geometry = |i: usize| decode_geometry(&features[i].geometry).unwrap();
+        assert_eq!(
+            geometry(0),
+            vec![(MOVE_TO, vec![(0, 2048)]), (LINE_TO, vec![(4352, 2048)])]
+        );
+        assert_eq!(
+            geometry(1),
+            vec![
+                (MOVE_TO, vec![(2048, 4352)]),
+                (LINE_TO, vec![(2048, 2048), (4352, 2048), (4352, 4352)]),
+                (CLOSE_PATH, vec![]),
+            ]
+        );
+        assert_eq!(geometry(2), vec![(MOVE_TO, vec![(2000, 2000)])]);
+    }
+}
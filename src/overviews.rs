@@ -0,0 +1,197 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{Context, Result};
+use flume::{Receiver, Sender};
+use futures_util::{StreamExt, TryStreamExt, stream};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{
+    raster::{self, Resampling},
+    spill::TileSpill,
+    tile::Tile,
+    writer::WriteTileMsg,
+};
+
+/// Generates the zoom levels from `min_zoom` up to (but not including) `max_zoom` by downsampling
+/// the tiles below them (`--build-overviews`). It sits between the downloader and the writer, so
+/// the archive is written once with every zoom level.
+pub struct OverviewBuilder {
+    min_zoom: u8,
+    max_zoom: u8,
+    filter: Resampling,
+    concurrency: usize,
+    cancel: CancellationToken,
+    /// Tiles of the maximum zoom level and generated overview tiles, until they are sent to the
+    /// writer
+    store: TileSpill,
+}
+
+impl OverviewBuilder {
+    pub fn new(
+        output: &Path,
+        min_zoom: u8,
+        max_zoom: u8,
+        filter: Resampling,
+        concurrency: usize,
+        cancel: CancellationToken,
+    ) -> Result<Self> {
+        let store = TileSpill::new_in(
+            output
+                .parent()
+                .context("Output path must have a parent directory")?,
+        )?;
+        Ok(Self {
+            min_zoom,
+            max_zoom,
+            filter,
+            concurrency,
+            cancel,
+            store,
+        })
+    }
+
+    /// Stores the tiles received from `tile_rx`, builds the overviews from them, then sends every
+    /// tile to `tile_tx` in tile ID order. When the download was cancelled, only the received tiles
+    /// are sent.
+    pub async fn run(
+        self,
+        tile_rx: Receiver<WriteTileMsg>,
+        tile_tx: Sender<WriteTileMsg>,
+    ) -> Result<()> {
+        let mut received = BTreeMap::new();
+        while let Ok(mut msg) = tile_rx.recv_async().await {
+            // Tiles the source didn't have are passed on, so the writer records them as skipped
+            if let Some(data) = msg.data.take() {
+                self.store.insert(msg.tile.clone(), &data)?;
+            }
+            received.insert(msg.tile.to_id(), msg);
+        }
+
+        let mut overviews = BTreeMap::new();
+        if !self.cancel.is_cancelled() {
+            let mut level = received
+                .values()
+                .map(|m| m.tile.clone())
+                .collect::<Vec<_>>();
+            for z in (self.min_zoom..self.max_zoom).rev() {
+                level = self.build_level(z, &level).await?;
+                overviews.extend(level.iter().map(|t| (t.to_id(), t.clone())));
+            }
+        }
+
+        // Every overview tile comes before the maximum zoom level in tile ID order
+        let overviews = overviews.into_values().map(|tile| WriteTileMsg {
+            index: 0,
+            tile,
+            data: None,
+            validators: None,
+            source: None,
+        });
+        for (index, mut msg) in overviews.chain(received.into_values()).enumerate() {
+            msg.index = index;
+            msg.data = self.store.get(&msg.tile)?;
+            tile_tx.send_async(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Builds the parents of `level` at zoom `z`, and returns them. Parents without any child data
+    /// are returned too, but not stored.
+    async fn build_level(&self, z: u8, level: &[Tile]) -> Result<Vec<Tile>> {
+        let parents = level
+            .iter()
+            .filter_map(|t| t.parent())
+            .map(|t| (t.to_id(), t))
+            .collect::<BTreeMap<_, _>>();
+        info!("Building {} overview tiles at zoom {}...", parents.len(), z);
+
+        let (store, filter) = (&self.store, self.filter);
+        stream::iter(parents.into_values())
+            .map(|parent| async move {
+                let mut children: [Option<Vec<u8>>; 4] = Default::default();
+                for (data, child) in children.iter_mut().zip(parent.children()) {
+                    *data = store.get(&child)?;
+                }
+                let composed = tokio::task::spawn_blocking({
+                    let parent = parent.clone();
+                    move || raster::compose_parent(&parent, children, filter)
+                })
+                .await??;
+                if let Some(data) = composed {
+                    store.insert(parent.clone(), &data)?;
+                }
+                Ok::<_, anyhow::Error>(parent)
+            })
+            .buffer_unordered(self.concurrency)
+            .try_collect()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use pmtiles::TileType;
+
+    fn solid(color: [u8; 4]) -> Vec<u8> {
+        let img = RgbaImage::from_pixel(4, 4, Rgba(color));
+        raster::encode(&DynamicImage::ImageRgba8(img), TileType::Png).unwrap()
+    }
+
+    fn msg(index: usize, tile: Tile, data: Option<Vec<u8>>) -> WriteTileMsg {
+        WriteTileMsg {
+            index,
+            tile,
+            data,
+            validators: None,
+            source: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_overviews_before_the_maximum_zoom() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = OverviewBuilder::new(
+            &dir.path().join("out.pmtiles"),
+            0,
+            2,
+            Resampling::Nearest,
+            2,
+            CancellationToken::new(),
+        )
+        .unwrap();
+        let (tile_tx, tile_rx) = flume::unbounded();
+        let (writer_tx, writer_rx) = flume::unbounded();
+        // Out of order, as they arrive from the downloader
+        tile_tx
+            .send(msg(1, Tile::new(2, 3, 3), Some(solid([0, 0, 255, 255]))))
+            .unwrap();
+        tile_tx
+            .send(msg(0, Tile::new(2, 0, 0), Some(solid([255, 0, 0, 255]))))
+            .unwrap();
+        tile_tx.send(msg(2, Tile::new(2, 1, 0), None)).unwrap();
+        drop(tile_tx);
+
+        builder.run(tile_rx, writer_tx).await.unwrap();
+
+        let written = writer_rx.iter().collect::<Vec<_>>();
+        let tiles = written
+            .iter()
+            .map(|m| m.tile.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tiles,
+            ["0/0/0", "1/0/0", "1/1/1", "2/0/0", "2/1/0", "2/3/3"]
+        );
+        assert!(written.iter().enumerate().all(|(i, m)| m.index == i));
+        // Passed on without data, so it is recorded as skipped
+        assert!(written[4].data.is_none());
+        let parent = raster::decode(written[1].data.as_ref().unwrap())
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(parent.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(parent.get_pixel(3, 3)[3], 0);
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Result, bail};
+use tokio::sync::OnceCell;
+
+use crate::{
+    mvt,
+    raster::{self, Resampling},
+    tile::Tile,
+    tile_format::sniff_raster,
+    tile_source::TileSource,
+};
+
+type AncestorCell = Arc<OnceCell<Option<Arc<Vec<u8>>>>>;
+
+/// How many ancestors are kept in memory. Tiles are downloaded in tile ID order, so the tiles
+/// below an ancestor are requested close together.
+const MAX_CACHED_ANCESTORS: usize = 1024;
+
+/// Ancestors fetched so far, shared by every tile below them. The least recently used one is
+/// evicted when the cache is full.
+struct AncestorCache {
+    capacity: usize,
+    cells: HashMap<Tile, (AncestorCell, u64)>,
+    clock: u64,
+}
+
+impl AncestorCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            cells: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, tile: &Tile) -> AncestorCell {
+        self.clock += 1;
+        if !self.cells.contains_key(tile) && self.cells.len() >= self.capacity {
+            let oldest = self
+                .cells
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(t, _)| t.clone());
+            if let Some(oldest) = oldest {
+                self.cells.remove(&oldest);
+            }
+        }
+        let (cell, used) = self.cells.entry(tile.clone()).or_default();
+        *used = self.clock;
+        cell.clone()
+    }
+}
+
+/// Fills tiles missing at the source by overzooming their nearest available ancestor.
+pub struct ParentFiller {
+    source: Arc<dyn TileSource>,
+    filter: Resampling,
+    /// None for ancestors the source doesn't have either
+    ancestors: Mutex<AncestorCache>,
+    filled: AtomicU64,
+}
+
+impl ParentFiller {
+    pub fn new(source: Arc<dyn TileSource>, filter: Resampling) -> Self {
+        Self {
+            source,
+            filter,
+            ancestors: Mutex::new(AncestorCache::new(MAX_CACHED_ANCESTORS)),
+            filled: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of tiles filled so far.
+    pub fn filled(&self) -> u64 {
+        self.filled.load(Ordering::Relaxed)
+    }
+
+    async fn ancestor(&self, tile: &Tile) -> Result<Option<Arc<Vec<u8>>>> {
+        let cell = self.ancestors.lock().unwrap().get(tile);
+        let data = cell
+            .get_or_try_init(|| async {
+                let data = self.source.fetch(tile).await?;
+                Ok::<_, anyhow::Error>(data.map(|d| Arc::new(d.to_vec())))
+            })
+            .await?;
+        Ok(data.clone())
+    }
+
+    /// Builds a tile from the nearest ancestor the source has. Returns None if there is none.
+    pub async fn fill(&self, tile: &Tile) -> Result<Option<Vec<u8>>> {
+        let mut ancestor = tile.parent();
+        while let Some(parent) = ancestor {
+            if let Some(data) = self.ancestor(&parent).await? {
+                let dz = tile.z() - parent.z();
+                let offset = (tile.x() - (parent.x() << dz), tile.y() - (parent.y() << dz));
+                let filter = self.filter;
+                let data = tokio::task::spawn_blocking(move || overzoom(&data, dz, offset, filter))
+                    .await??;
+                self.filled.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(data));
+            }
+            ancestor = parent.parent();
+        }
+        Ok(None)
+    }
+}
+
+fn overzoom(data: &[u8], dz: u8, offset: (u32, u32), filter: Resampling) -> Result<Vec<u8>> {
+    if sniff_raster(data).is_some() {
+        return raster::overzoom(data, dz, offset, filter);
+    }
+    let Ok(tile) = mvt::decode(data) else {
+        bail!("Cannot overzoom tile: neither a raster image nor a vector tile");
+    };
+    mvt::encode(&mvt::overzoom(&tile, dz, offset)?, mvt::is_gzipped(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_least_recently_used_ancestor() {
+        let mut cache = AncestorCache::new(2);
+        let (a, b, c) = (Tile::new(1, 0, 0), Tile::new(1, 1, 0), Tile::new(1, 0, 1));
+        let cell = cache.get(&a);
+        cache.get(&b);
+        assert!(Arc::ptr_eq(&cell, &cache.get(&a)));
+        cache.get(&c);
+        assert_eq!(cache.cells.len(), 2);
+        assert!(cache.cells.contains_key(&a));
+        assert!(!cache.cells.contains_key(&b));
+    }
+}
@@ -0,0 +1,172 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::{Context, Result, bail};
+use serde_json::Value;
+
+type Ring = Vec<(f32, f32)>;
+
+/// A (multi)polygon in WGS84 longitude / latitude, read from GeoJSON.
+/// Holes are handled with the even-odd rule, so every ring is stored flat.
+pub struct Polygon {
+    rings: Vec<Ring>,
+}
+
+impl Polygon {
+    /// Reads a GeoJSON file containing a Polygon or MultiPolygon geometry, a Feature, or a FeatureCollection.
+    /// All polygons found are combined.
+    pub fn from_geojson_file(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open polygon file {}", path.display()))?;
+        let value: Value = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse polygon file {}", path.display()))?;
+        let mut rings = Vec::new();
+        collect_rings(&value, &mut rings)?;
+        if rings.is_empty() {
+            bail!("No Polygon or MultiPolygon found in {}", path.display());
+        }
+        Ok(Self { rings })
+    }
+
+    /// The bounding box of the polygon: (min_lon, min_lat, max_lon, max_lat)
+    pub fn bounds(&self) -> (f32, f32, f32, f32) {
+        self.rings.iter().flatten().fold(
+            (
+                f32::INFINITY,
+                f32::INFINITY,
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY,
+            ),
+            |(w, s, e, n), (x, y)| (w.min(*x), s.min(*y), e.max(*x), n.max(*y)),
+        )
+    }
+
+    pub fn contains(&self, (x, y): (f32, f32)) -> bool {
+        let mut inside = false;
+        for ring in &self.rings {
+            for (a, b) in edges(ring) {
+                if (a.1 > y) != (b.1 > y) && x < (b.0 - a.0) * (y - a.1) / (b.1 - a.1) + a.0 {
+                    inside = !inside;
+                }
+            }
+        }
+        inside
+    }
+
+    /// Returns true if the rectangle (min_lon, min_lat, max_lon, max_lat) overlaps the polygon.
+    pub fn intersects_rect(&self, rect: (f32, f32, f32, f32)) -> bool {
+        let (w, s, e, n) = rect;
+        let corners = [(w, s), (e, s), (e, n), (w, n)];
+        if corners.iter().any(|c| self.contains(*c)) {
+            return true;
+        }
+        let in_rect = |(x, y): (f32, f32)| x >= w && x <= e && y >= s && y <= n;
+        if self.rings.iter().flatten().any(|p| in_rect(*p)) {
+            return true;
+        }
+        let rect_edges = [
+            (corners[0], corners[1]),
+            (corners[1], corners[2]),
+            (corners[2], corners[3]),
+            (corners[3], corners[0]),
+        ];
+        self.rings.iter().any(|ring| {
+            edges(ring).any(|(a, b)| rect_edges.iter().any(|(c, d)| segments_cross(a, b, *c, *d)))
+        })
+    }
+}
+
+fn edges(ring: &Ring) -> impl Iterator<Item = ((f32, f32), (f32, f32))> + '_ {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| (*a, *b))
+}
+
+fn segments_cross(a: (f32, f32), b: (f32, f32), c: (f32, f32), d: (f32, f32)) -> bool {
+    let orient = |p: (f32, f32), q: (f32, f32), r: (f32, f32)| {
+        (q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0)
+    };
+    let (d1, d2) = (orient(c, d, a), orient(c, d, b));
+    let (d3, d4) = (orient(a, b, c), orient(a, b, d));
+    (d1 > 0.0) != (d2 > 0.0) && (d3 > 0.0) != (d4 > 0.0)
+}
+
+fn collect_rings(value: &Value, rings: &mut Vec<Ring>) -> Result<()> {
+    match value.get("type").and_then(|t| t.as_str()) {
+        Some("FeatureCollection") => {
+            for feature in value["features"].as_array().into_iter().flatten() {
+                collect_rings(feature, rings)?;
+            }
+        }
+        Some("Feature") => collect_rings(&value["geometry"], rings)?,
+        Some("Polygon") => {
+            for ring in value["coordinates"].as_array().into_iter().flatten() {
+                rings.push(parse_ring(ring)?);
+            }
+        }
+        Some("MultiPolygon") => {
+            for polygon in value["coordinates"].as_array().into_iter().flatten() {
+                for ring in polygon.as_array().into_iter().flatten() {
+                    rings.push(parse_ring(ring)?);
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn parse_ring(ring: &Value) -> Result<Ring> {
+    ring.as_array()
+        .context("Polygon ring must be an array")?
+        .iter()
+        .map(|p| {
+            match (
+                p.get(0).and_then(Value::as_f64),
+                p.get(1).and_then(Value::as_f64),
+            ) {
+                (Some(x), Some(y)) => Ok((x as f32, y as f32)),
+                _ => bail!("Invalid polygon coordinate: {}", p),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square_with_hole() -> Polygon {
+        let value = serde_json::json!({
+            "type": "Polygon",
+            "coordinates": [
+                [[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0], [0.0, 0.0]],
+                [[4.0, 4.0], [6.0, 4.0], [6.0, 6.0], [4.0, 6.0], [4.0, 4.0]]
+            ]
+        });
+        let mut rings = Vec::new();
+        collect_rings(&value, &mut rings).unwrap();
+        Polygon { rings }
+    }
+
+    #[test]
+    fn contains_respects_holes() {
+        let polygon = square_with_hole();
+        assert!(polygon.contains((1.0, 1.0)));
+        assert!(!polygon.contains((5.0, 5.0)));
+        assert!(!polygon.contains((11.0, 1.0)));
+    }
+
+    #[test]
+    fn intersects_rects() {
+        let polygon = square_with_hole();
+        // Overlapping an edge
+        assert!(polygon.intersects_rect((9.0, 9.0, 12.0, 12.0)));
+        // Containing the whole polygon
+        assert!(polygon.intersects_rect((-1.0, -1.0, 11.0, 11.0)));
+        // Entirely inside the hole
+        assert!(!polygon.intersects_rect((4.5, 4.5, 5.5, 5.5)));
+        // Entirely outside
+        assert!(!polygon.intersects_rect((20.0, 20.0, 21.0, 21.0)));
+        assert_eq!(polygon.bounds(), (0.0, 0.0, 10.0, 10.0));
+    }
+}
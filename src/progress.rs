@@ -1,33 +1,124 @@
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
 use anyhow::Result;
+use clap::ValueEnum;
 use flume::Receiver;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::Serialize;
+use serde_json::{Value, json};
+use tracing::{error, info};
 
-use crate::tile::Tile;
+use crate::{logging, tile::Tile};
 
-pub enum ProgressMsg {
-    Log(String),
+/// How progress is reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProgressMode {
+    /// Interactive progress bars
+    #[default]
+    Bars,
+    /// A plain text status line every few seconds, for logs
+    Plain,
+    /// One JSON object per line for every event
+    Json,
+    /// Nothing is printed; only listeners receive the events. Used when embedding the library.
+    #[value(skip)]
+    Hidden,
+}
+
+/// Where status lines and JSON events are printed. Clones share one lock, so lines printed from
+/// different threads never interleave.
+#[derive(Clone)]
+pub struct StatusOut(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl StatusOut {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(out))))
+    }
+
+    /// Standard output, shared by the whole process.
+    pub fn stdout() -> Self {
+        static STDOUT: LazyLock<StatusOut> = LazyLock::new(|| StatusOut::new(io::stdout()));
+        STDOUT.clone()
+    }
 
-    Skipped(),
+    /// Prints one line. Errors are ignored, like `println!` would if stdout were closed.
+    pub fn println(&self, line: impl Display) {
+        let mut out = self.0.lock().unwrap();
+        let _ = writeln!(out, "{}", line).and_then(|_| out.flush());
+    }
+}
+
+/// The events of a download, sent by its tasks and passed to every [`ProgressListener`].
+pub enum ProgressMsg {
+    /// The source didn't have a tile.
+    Skipped(Tile),
 
     /// A tile was downloaded. (Tile, byte size)
     Downloaded(Tile, usize),
+    /// A tile was not modified since the previous download, and is reused as-is.
+    NotModified(Tile),
+    /// The source didn't have a tile, so it was generated from an ancestor tile.
+    FilledFromParent(Tile),
+    /// A tile was re-encoded in another format. (byte size before, byte size after)
+    Transcoded(usize, usize),
     Written(Tile),
+    /// A tile failed to download. (Tile, error message)
+    Failed(Tile, String),
+    /// An HTTP request was sent.
+    RequestStarted(),
+    /// An HTTP request completed with a status code, or failed without a response. (status, latency)
+    RequestFinished(Option<u16>, Duration),
+    /// A failed request will be retried.
+    Retry(),
+    /// The number of tiles waiting in the writer's reorder buffer.
+    ReorderBuffer(usize),
+    /// A written tile has the same content as an earlier one, so the archive only stores it once.
+    Deduplicated(Tile),
 
     Finished(),
 }
 
 pub type ProgressSender = flume::Sender<ProgressMsg>;
 
+/// Receives every progress message, for example to collect metrics or a report.
+pub trait ProgressListener: Send + Sync {
+    fn record(&self, msg: &ProgressMsg);
+}
+
+impl<F: Fn(&ProgressMsg) + Send + Sync> ProgressListener for F {
+    fn record(&self, msg: &ProgressMsg) {
+        self(msg)
+    }
+}
+
 pub struct Progress {
-    m: MultiProgress,
+    mode: ProgressMode,
+    out: StatusOut,
     tile_dl: ProgressBar,
     tile_dl_bytes: ProgressBar,
     tile_written: ProgressBar,
+    listeners: Vec<Arc<dyn ProgressListener>>,
 }
 
 impl Progress {
-    pub fn new(initial_count: u64) -> Self {
-        let m = MultiProgress::new();
+    pub fn new(initial_count: u64, mode: ProgressMode) -> Self {
+        // Hidden unless the mode is `Bars`; the bars also keep the counts and rates for the other modes
+        let m = match mode {
+            ProgressMode::Bars => MultiProgress::new(),
+            _ => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+        };
+        if mode == ProgressMode::Bars {
+            logging::set_progress_bars(m.clone());
+        }
+        if mode == ProgressMode::Json {
+            logging::set_json_events(StatusOut::stdout());
+        }
 
         let tile_dl = m.add(ProgressBar::new(initial_count));
         tile_dl.set_style(
@@ -54,20 +145,64 @@ impl Progress {
         );
 
         Self {
-            m,
+            mode,
+            out: StatusOut::stdout(),
             tile_dl,
             tile_dl_bytes,
             tile_written,
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Progress for writing tiles that don't need to be downloaded; only the output bar is shown.
+    pub fn new_write_only(initial_count: u64) -> Self {
+        let m = MultiProgress::new();
+        logging::set_progress_bars(m.clone());
+
+        let tile_written = m.add(ProgressBar::new(initial_count));
+        tile_written.set_style(
+            ProgressStyle::with_template(
+                "TileOut {msg} {bar:40.cyan/blue} {pos:>11}/{len:11} ({percent}%) ({eta})",
+            )
+            .unwrap(),
+        );
+
+        Self {
+            mode: ProgressMode::Bars,
+            out: StatusOut::stdout(),
+            tile_dl: ProgressBar::hidden(),
+            tile_dl_bytes: ProgressBar::hidden(),
+            tile_written,
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Prints status lines and JSON events, including log events, to `out` instead of stdout.
+    pub fn with_output(mut self, out: StatusOut) -> Self {
+        if self.mode == ProgressMode::Json {
+            logging::set_json_events(out.clone());
         }
+        self.out = out;
+        self
+    }
+
+    /// Also passes every message to `listener`.
+    pub fn with_listener(mut self, listener: Arc<dyn ProgressListener>) -> Self {
+        self.listeners.push(listener);
+        self
     }
 
     pub fn run(&self, rx: Receiver<ProgressMsg>) -> Result<()> {
+        // (tiles, bytes before, bytes after)
+        let mut transcoded = (0u64, 0u64, 0u64);
+        let mut last_status = Instant::now();
         while let Ok(msg) = rx.recv() {
+            for listener in &self.listeners {
+                listener.record(&msg);
+            }
+            let event = Self::event(&msg);
             match msg {
-                ProgressMsg::Log(s) => {
-                    self.m.println(s)?;
-                }
-                ProgressMsg::Skipped() => {
+                ProgressMsg::Skipped(_) => {
                     self.tile_dl.dec_length(1);
                     self.tile_written.dec_length(1);
                 }
@@ -84,19 +219,197 @@ impl Progress {
                     self.tile_dl.set_message(tile_str.clone());
                     self.tile_dl_bytes.set_message(tile_str);
                 }
+                ProgressMsg::NotModified(tile) | ProgressMsg::FilledFromParent(tile) => {
+                    self.tile_dl.inc(1);
+                    self.tile_dl
+                        .set_message(format!("{:<14}", tile.to_string()));
+                }
                 ProgressMsg::Written(tile) => {
                     self.tile_written.inc(1);
                     let tile_str = format!("{:<14}", tile.to_string());
                     self.tile_written.set_message(tile_str);
                 }
+                ProgressMsg::Transcoded(before, after) => {
+                    transcoded.0 += 1;
+                    transcoded.1 += before as u64;
+                    transcoded.2 += after as u64;
+                }
+                ProgressMsg::Failed(tile, error) => {
+                    error!(%tile, "Error downloading tile: {}", error);
+                }
+                ProgressMsg::RequestStarted()
+                | ProgressMsg::RequestFinished(..)
+                | ProgressMsg::Retry()
+                | ProgressMsg::ReorderBuffer(_)
+                | ProgressMsg::Deduplicated(_) => {}
                 ProgressMsg::Finished() => {
+                    if transcoded.0 > 0 {
+                        let (count, before, after) = transcoded;
+                        info!(
+                            "Transcoded {} tiles: {} -> {} ({:+.1}%)",
+                            count,
+                            HumanBytes(before),
+                            HumanBytes(after),
+                            (after as f64 / before.max(1) as f64 - 1.0) * 100.0
+                        );
+                    }
                     self.tile_dl.abandon();
                     self.tile_dl_bytes.abandon();
                     self.tile_written.abandon();
+                    self.report(event, &mut last_status);
                     break;
                 }
             }
+            self.report(event, &mut last_status);
         }
         Ok(())
     }
+
+    /// The name and fields of the JSON event for a message. Request-level messages are only used by
+    /// listeners.
+    fn event(msg: &ProgressMsg) -> Option<(&'static str, Value)> {
+        let event = match msg {
+            ProgressMsg::RequestStarted()
+            | ProgressMsg::RequestFinished(..)
+            | ProgressMsg::Retry()
+            | ProgressMsg::ReorderBuffer(_)
+            | ProgressMsg::Deduplicated(_) => return None,
+            ProgressMsg::Failed(tile, error) => (
+                "failed",
+                json!({ "tile": tile.to_string(), "error": error }),
+            ),
+            ProgressMsg::Skipped(tile) => ("skipped", json!({ "tile": tile.to_string() })),
+            ProgressMsg::Downloaded(tile, size) => (
+                "downloaded",
+                json!({ "tile": tile.to_string(), "bytes": size }),
+            ),
+            ProgressMsg::NotModified(tile) => ("not_modified", json!({ "tile": tile.to_string() })),
+            ProgressMsg::FilledFromParent(tile) => {
+                ("filled_from_parent", json!({ "tile": tile.to_string() }))
+            }
+            ProgressMsg::Transcoded(before, after) => (
+                "transcoded",
+                json!({ "bytes_before": before, "bytes_after": after }),
+            ),
+            ProgressMsg::Written(tile) => ("written", json!({ "tile": tile.to_string() })),
+            ProgressMsg::Finished() => ("finished", json!({})),
+        };
+        Some(event)
+    }
+
+    /// Prints an event in the JSON mode, or a periodic status line in the plain mode.
+    fn report(&self, event: Option<(&'static str, Value)>, last_status: &mut Instant) {
+        /// How often the plain mode prints a status line
+        const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+        let Some((name, fields)) = event else {
+            return;
+        };
+        match self.mode {
+            ProgressMode::Bars | ProgressMode::Hidden => {}
+            ProgressMode::Plain => {
+                if name == "finished" || last_status.elapsed() >= STATUS_INTERVAL {
+                    *last_status = Instant::now();
+                    self.out.println(format_args!(
+                        "Downloaded {}/{} tiles ({}), written {}/{}, {:.1} tiles/s, ETA {}",
+                        self.tile_dl.position(),
+                        self.tile_dl.length().unwrap_or(0),
+                        HumanBytes(self.tile_dl_bytes.position()),
+                        self.tile_written.position(),
+                        self.tile_written.length().unwrap_or(0),
+                        self.tile_written.per_sec(),
+                        indicatif::HumanDuration(self.tile_written.eta())
+                    ));
+                }
+            }
+            ProgressMode::Json => self.print_json(name, fields),
+        }
+    }
+
+    fn print_json(&self, name: &str, mut fields: Value) {
+        let fields = fields.as_object_mut().expect("event fields are an object");
+        let mut event = serde_json::Map::new();
+        event.insert(
+            "time".to_string(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                .into(),
+        );
+        event.insert("event".to_string(), name.into());
+        event.append(fields);
+        event.insert("downloaded".to_string(), self.tile_dl.position().into());
+        event.insert(
+            "downloaded_bytes".to_string(),
+            self.tile_dl_bytes.position().into(),
+        );
+        event.insert("written".to_string(), self.tile_written.position().into());
+        event.insert(
+            "total".to_string(),
+            self.tile_written.length().unwrap_or(0).into(),
+        );
+        event.insert(
+            "tiles_per_sec".to_string(),
+            json!(self.tile_written.per_sec()),
+        );
+        event.insert(
+            "eta_secs".to_string(),
+            self.tile_written.eta().as_secs().into(),
+        );
+        self.out.println(Value::Object(event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buffer that can be read while a clone of it is written to.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn prints_json_events() {
+        let buffer = Buffer::default();
+        let progress =
+            Progress::new(2, ProgressMode::Json).with_output(StatusOut::new(buffer.clone()));
+        let (tx, rx) = flume::unbounded();
+        tx.send(ProgressMsg::Downloaded(Tile::new(1, 0, 0), 100))
+            .unwrap();
+        tx.send(ProgressMsg::RequestStarted()).unwrap();
+        tx.send(ProgressMsg::Written(Tile::new(1, 0, 0))).unwrap();
+        tx.send(ProgressMsg::Skipped(Tile::new(1, 1, 0))).unwrap();
+        tx.send(ProgressMsg::Finished()).unwrap();
+        progress.run(rx).unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events = output
+            .lines()
+            .map(|l| serde_json::from_str::<Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        let names = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["downloaded", "written", "skipped", "finished"]);
+        assert_eq!(events[0]["tile"], "1/0/0");
+        assert_eq!(events[0]["bytes"], 100);
+        for event in &events {
+            assert!(chrono::DateTime::parse_from_rfc3339(event["time"].as_str().unwrap()).is_ok());
+            assert!(event["eta_secs"].is_u64());
+            assert!(event["tiles_per_sec"].is_number());
+        }
+        let last = &events[3];
+        assert_eq!(last["written"], 1);
+        assert_eq!(last["total"], 1);
+    }
 }
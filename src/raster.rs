@@ -0,0 +1,388 @@
+//! Decoding, compositing and encoding of raster tiles.
+
+use std::io::Cursor;
+
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use image::{DynamicImage, ImageFormat, RgbaImage, codecs::jpeg::JpegEncoder, imageops};
+use pmtiles::TileType;
+use serde::Serialize;
+
+use crate::{
+    progress::{ProgressMsg, ProgressSender},
+    tile::Tile,
+    tile_format::sniff_raster,
+    tile_processor::TileProcessor,
+};
+
+/// The resampling filter used when resizing tiles.
+#[derive(Debug, Clone, Copy, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resampling {
+    Nearest,
+    Bilinear,
+    Bicubic,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<Resampling> for imageops::FilterType {
+    fn from(r: Resampling) -> Self {
+        match r {
+            Resampling::Nearest => imageops::FilterType::Nearest,
+            Resampling::Bilinear => imageops::FilterType::Triangle,
+            Resampling::Bicubic => imageops::FilterType::CatmullRom,
+            Resampling::Gaussian => imageops::FilterType::Gaussian,
+            Resampling::Lanczos3 => imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+/// The output format for `--transcode`.
+#[derive(Debug, Clone, Copy, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TranscodeFormat {
+    Webp,
+    Jpeg,
+    Png,
+}
+
+impl From<TranscodeFormat> for TileType {
+    fn from(f: TranscodeFormat) -> Self {
+        match f {
+            TranscodeFormat::Webp => TileType::Webp,
+            TranscodeFormat::Jpeg => TileType::Jpeg,
+            TranscodeFormat::Png => TileType::Png,
+        }
+    }
+}
+
+/// Re-encodes raster tiles in another format.
+pub struct Transcoder {
+    tile_type: TileType,
+    /// JPEG quality, 1-100
+    quality: u8,
+}
+
+impl Transcoder {
+    pub fn new(format: TranscodeFormat, quality: u8) -> Self {
+        Self {
+            tile_type: format.into(),
+            quality,
+        }
+    }
+
+    pub fn tile_type(&self) -> TileType {
+        self.tile_type
+    }
+
+    pub fn transcode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let img = decode(data)?;
+        if self.tile_type == TileType::Jpeg {
+            let mut out = Vec::new();
+            JpegEncoder::new_with_quality(&mut out, self.quality)
+                .encode_image(&DynamicImage::ImageRgb8(img.to_rgb8()))?;
+            return Ok(out);
+        }
+        encode(&img, self.tile_type)
+    }
+}
+
+impl TileProcessor for Transcoder {
+    fn process(
+        &self,
+        _tile: &Tile,
+        data: Vec<u8>,
+        progress_tx: &ProgressSender,
+    ) -> Result<Vec<u8>> {
+        let transcoded = self.transcode(&data)?;
+        progress_tx.send(ProgressMsg::Transcoded(data.len(), transcoded.len()))?;
+        Ok(transcoded)
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<DynamicImage> {
+    Ok(image::load_from_memory(data)?)
+}
+
+/// Returns true if the data is a fully transparent raster tile. Anything that can't be decoded as
+/// an image isn't transparent.
+pub fn is_transparent(data: &[u8]) -> bool {
+    if sniff_raster(data).is_none() {
+        return false;
+    }
+    let Ok(img) = decode(data) else {
+        return false;
+    };
+    img.to_rgba8().pixels().all(|p| p[3] == 0)
+}
+
+/// Encodes an image as the given tile type.
+pub fn encode(img: &DynamicImage, tile_type: TileType) -> Result<Vec<u8>> {
+    let format = match tile_type {
+        TileType::Png => ImageFormat::Png,
+        TileType::Webp => ImageFormat::WebP,
+        TileType::Jpeg => {
+            // JPEG has no alpha channel
+            let mut out = Cursor::new(Vec::new());
+            DynamicImage::ImageRgb8(img.to_rgb8()).write_to(&mut out, ImageFormat::Jpeg)?;
+            return Ok(out.into_inner());
+        }
+        other => bail!("Cannot encode raster tiles as {:?}", other),
+    };
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, format)?;
+    Ok(out.into_inner())
+}
+
+/// Stitches four tiles (top-left, top-right, bottom-left, bottom-right) into one image twice
+/// their size. Missing tiles are left transparent. Returns None if all tiles are missing.
+pub fn mosaic(tiles: &[Option<DynamicImage>; 4]) -> Option<RgbaImage> {
+    let first = tiles.iter().flatten().next()?;
+    let (w, h) = (first.width(), first.height());
+    let mut out = RgbaImage::new(w * 2, h * 2);
+    for (i, tile) in tiles.iter().enumerate() {
+        let Some(tile) = tile else {
+            continue;
+        };
+        let tile = if tile.width() == w && tile.height() == h {
+            tile.to_rgba8()
+        } else {
+            imageops::resize(&tile.to_rgba8(), w, h, imageops::FilterType::Triangle)
+        };
+        let (x, y) = ((i as u32 % 2) * w, (i as u32 / 2) * h);
+        imageops::replace(&mut out, &tile, x as i64, y as i64);
+    }
+    Some(out)
+}
+
+/// Decodes the four children of `parent` and stitches them together, returning the mosaic and the
+/// children's format.
+fn stitch(parent: &Tile, children: [Option<Vec<u8>>; 4]) -> Result<Option<(RgbaImage, TileType)>> {
+    let Some(tile_type) = children.iter().flatten().find_map(|c| sniff_raster(c)) else {
+        return Ok(None);
+    };
+    let mut decoded: [Option<DynamicImage>; 4] = Default::default();
+    for ((image, data), child) in decoded.iter_mut().zip(children).zip(parent.children()) {
+        if let Some(data) = data {
+            *image =
+                Some(decode(&data).with_context(|| format!("Failed to decode tile {}", child))?);
+        }
+    }
+    Ok(mosaic(&decoded).map(|mosaic| (mosaic, tile_type)))
+}
+
+/// Builds a parent tile from the encoded data of its four children, keeping the children's format.
+/// Returns None if all children are missing.
+pub fn compose_parent(
+    parent: &Tile,
+    children: [Option<Vec<u8>>; 4],
+    filter: Resampling,
+) -> Result<Option<Vec<u8>>> {
+    let Some((mosaic, tile_type)) = stitch(parent, children)? else {
+        return Ok(None);
+    };
+    let (w, h) = (mosaic.width() / 2, mosaic.height() / 2);
+    let parent = imageops::resize(&mosaic, w, h, filter.into());
+    Ok(Some(encode(&DynamicImage::ImageRgba8(parent), tile_type)?))
+}
+
+/// Builds a tile twice the size of its four children (e.g. 512px from 256px tiles), keeping the
+/// children's format. Returns None if all children are missing.
+pub fn assemble(tile: &Tile, children: [Option<Vec<u8>>; 4]) -> Result<Option<Vec<u8>>> {
+    let Some((mosaic, tile_type)) = stitch(tile, children)? else {
+        return Ok(None);
+    };
+    Ok(Some(encode(&DynamicImage::ImageRgba8(mosaic), tile_type)?))
+}
+
+/// Alpha-blends layers on top of each other, bottom first, each with an opacity from 0 to 1.
+/// Layers are scaled to the size of the first one present. Returns None if all layers are missing.
+pub fn composite(
+    layers: Vec<(Option<Vec<u8>>, f32)>,
+    tile_type: TileType,
+) -> Result<Option<Vec<u8>>> {
+    let mut canvas: Option<RgbaImage> = None;
+    for (data, opacity) in layers {
+        let Some(data) = data else {
+            continue;
+        };
+        let mut layer = decode(&data)?.to_rgba8();
+        if opacity < 1.0 {
+            for pixel in layer.pixels_mut() {
+                pixel[3] = (pixel[3] as f32 * opacity).round() as u8;
+            }
+        }
+        match &mut canvas {
+            None => {
+                let mut base = RgbaImage::new(layer.width(), layer.height());
+                imageops::overlay(&mut base, &layer, 0, 0);
+                canvas = Some(base);
+            }
+            Some(canvas) => {
+                if layer.dimensions() != canvas.dimensions() {
+                    layer = imageops::resize(
+                        &layer,
+                        canvas.width(),
+                        canvas.height(),
+                        imageops::FilterType::Triangle,
+                    );
+                }
+                imageops::overlay(canvas, &layer, 0, 0);
+            }
+        }
+    }
+    let Some(canvas) = canvas else {
+        return Ok(None);
+    };
+    Ok(Some(encode(&DynamicImage::ImageRgba8(canvas), tile_type)?))
+}
+
+/// Crops the part of an ancestor tile covering a tile `dz` zoom levels below it, at offset
+/// (`dx`, `dy`) in units of that tile, and scales it up to the ancestor's size.
+pub fn overzoom(data: &[u8], dz: u8, (dx, dy): (u32, u32), filter: Resampling) -> Result<Vec<u8>> {
+    let Some(tile_type) = sniff_raster(data) else {
+        bail!("Unrecognised raster tile format");
+    };
+    let img = decode(data)?.to_rgba8();
+    let (w, h) = img.dimensions();
+    let scale = 1u32 << dz;
+    let (cw, ch) = ((w / scale).max(1), (h / scale).max(1));
+    let (x, y) = ((dx * w / scale).min(w - cw), (dy * h / scale).min(h - ch));
+    let cropped = imageops::crop_imm(&img, x, y, cw, ch).to_image();
+    let scaled = imageops::resize(&cropped, w, h, filter.into());
+    encode(&DynamicImage::ImageRgba8(scaled), tile_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn solid(color: [u8; 4]) -> Vec<u8> {
+        let img = RgbaImage::from_pixel(4, 4, Rgba(color));
+        encode(&DynamicImage::ImageRgba8(img), TileType::Png).unwrap()
+    }
+
+    #[test]
+    fn detects_transparent_tiles() {
+        assert!(is_transparent(&solid([0, 0, 0, 0])));
+        assert!(is_transparent(&solid([255, 255, 255, 0])));
+        // Open ocean is a single colour, but not blank
+        assert!(!is_transparent(&solid([170, 211, 223, 255])));
+        let mut img = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 0, 0]));
+        img.put_pixel(1, 2, Rgba([255, 0, 0, 255]));
+        assert!(!is_transparent(
+            &encode(&DynamicImage::ImageRgba8(img), TileType::Png).unwrap()
+        ));
+        assert!(!is_transparent(b"not an image"));
+    }
+
+    #[test]
+    fn composes_parent_from_children() {
+        let red = solid([255, 0, 0, 255]);
+        let blue = solid([0, 0, 255, 255]);
+        let parent = compose_parent(
+            &Tile::new(0, 0, 0),
+            [Some(red.clone()), Some(blue), None, Some(red)],
+            Resampling::Nearest,
+        )
+        .unwrap()
+        .expect("should produce a parent tile");
+        assert_eq!(sniff_raster(&parent), Some(TileType::Png));
+
+        let img = decode(&parent).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (4, 4));
+        assert_eq!(img.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+        assert_eq!(img.get_pixel(3, 0), &Rgba([0, 0, 255, 255]));
+        // The missing child stays transparent
+        assert_eq!(img.get_pixel(0, 3)[3], 0);
+    }
+
+    #[test]
+    fn overzooms_quadrant() {
+        let mut img = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]));
+        for y in 2..4 {
+            for x in 2..4 {
+                img.put_pixel(x, y, Rgba([0, 255, 0, 255]));
+            }
+        }
+        let data = encode(&DynamicImage::ImageRgba8(img), TileType::Png).unwrap();
+
+        let child = overzoom(&data, 1, (1, 1), Resampling::Nearest).unwrap();
+        let child = decode(&child).unwrap().to_rgba8();
+        assert_eq!(child.dimensions(), (4, 4));
+        assert!(child.pixels().all(|p| p == &Rgba([0, 255, 0, 255])));
+    }
+
+    #[test]
+    fn transcodes_png_to_jpeg() {
+        let png = solid([10, 20, 30, 255]);
+        let jpeg = Transcoder::new(TranscodeFormat::Jpeg, 90)
+            .transcode(&png)
+            .unwrap();
+        assert_eq!(sniff_raster(&jpeg), Some(TileType::Jpeg));
+        assert_eq!(decode(&jpeg).unwrap().width(), 4);
+    }
+
+    #[test]
+    fn assembles_double_size_tile() {
+        let red = solid([255, 0, 0, 255]);
+        let tile = assemble(
+            &Tile::new(0, 0, 0),
+            [Some(red.clone()), None, None, Some(red)],
+        )
+        .unwrap()
+        .expect("should produce a tile");
+        let img = decode(&tile).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (8, 8));
+        assert_eq!(img.get_pixel(7, 7), &Rgba([255, 0, 0, 255]));
+        assert_eq!(img.get_pixel(7, 0)[3], 0);
+    }
+
+    #[test]
+    fn composites_layers_with_opacity() {
+        let base = solid([0, 0, 0, 255]);
+        let overlay = solid([255, 255, 255, 255]);
+        let tile = composite(
+            vec![(Some(base), 1.0), (None, 1.0), (Some(overlay), 0.5)],
+            TileType::Png,
+        )
+        .unwrap()
+        .expect("should produce a tile");
+        let pixel = *decode(&tile).unwrap().to_rgba8().get_pixel(0, 0);
+        assert!((126..=129).contains(&pixel[0]), "{:?}", pixel);
+        assert_eq!(pixel[3], 255);
+
+        assert!(
+            composite(vec![(None, 1.0)], TileType::Png)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn skips_parent_without_children() {
+        let parent = compose_parent(
+            &Tile::new(0, 0, 0),
+            [None, None, None, None],
+            Resampling::Bilinear,
+        )
+        .unwrap();
+        assert!(parent.is_none());
+    }
+
+    #[test]
+    fn names_the_child_that_fails_to_decode() {
+        let red = solid([255, 0, 0, 255]);
+        let mut corrupt = red.clone();
+        corrupt.truncate(12);
+        let err = compose_parent(
+            &Tile::new(1, 1, 0),
+            [Some(red), Some(corrupt), None, None],
+            Resampling::Nearest,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Failed to decode tile 2/3/0");
+    }
+}
@@ -0,0 +1,204 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use anyhow::Result;
+
+use crate::{
+    append_reader::AppendReader,
+    tile::Tile,
+    tile_format::same_tile_data,
+    validators::{SourceStore, ValidatorStore, Validators},
+};
+
+/// What happened to a tile when refreshing an existing archive.
+pub enum RefreshOutcome {
+    /// The server responded with 304 Not Modified, or sent identical data
+    Unchanged,
+    Changed,
+    /// The tile was not in the previous archive
+    Added,
+    /// The tile was in the previous archive, but the server no longer has it
+    Removed,
+}
+
+#[derive(Default)]
+struct ZoomCounts {
+    unchanged: u64,
+    changed: u64,
+    added: u64,
+    removed: u64,
+}
+
+/// The previous version of an archive being refreshed, and the per-zoom tally of changes.
+pub struct Refresh {
+    reader: AppendReader,
+    validators: ValidatorStore,
+    sources: SourceStore,
+    counts: Mutex<BTreeMap<u8, ZoomCounts>>,
+}
+
+impl Refresh {
+    pub fn new(reader: AppendReader, validators: ValidatorStore, sources: SourceStore) -> Self {
+        Self {
+            reader,
+            validators,
+            sources,
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The validators of a tile, if it was downloaded from the given source last time. Other
+    /// sources don't know them.
+    pub fn validators(&self, tile: &Tile, source: usize) -> Option<&Validators> {
+        if self.sources.get(tile).unwrap_or(0) != source {
+            return None;
+        }
+        self.validators.get(tile)
+    }
+
+    /// Returns the tile data stored in the previous archive.
+    pub async fn previous(&self, tile: &Tile) -> Result<Option<Vec<u8>>> {
+        self.reader.get_tile(tile).await
+    }
+
+    /// Records the newly downloaded data (or lack of it) for a tile, comparing it with the previous archive.
+    /// The archive stores tiles decompressed, so gzip compression from the source is ignored.
+    pub async fn compare(&self, tile: &Tile, data: Option<&[u8]>) -> Result<()> {
+        let previous = self.previous(tile).await?;
+        let outcome = match (previous.as_deref(), data) {
+            (None, None) => return Ok(()),
+            (None, Some(_)) => RefreshOutcome::Added,
+            (Some(_), None) => RefreshOutcome::Removed,
+            (Some(old), Some(new)) if same_tile_data(old, new) => RefreshOutcome::Unchanged,
+            (Some(_), Some(_)) => RefreshOutcome::Changed,
+        };
+        self.record(tile, outcome);
+        Ok(())
+    }
+
+    pub fn record(&self, tile: &Tile, outcome: RefreshOutcome) {
+        let mut counts = self.counts.lock().unwrap();
+        let zoom = counts.entry(tile.z()).or_default();
+        match outcome {
+            RefreshOutcome::Unchanged => zoom.unchanged += 1,
+            RefreshOutcome::Changed => zoom.changed += 1,
+            RefreshOutcome::Added => zoom.added += 1,
+            RefreshOutcome::Removed => zoom.removed += 1,
+        }
+    }
+
+    /// A per-zoom table of how many tiles changed.
+    pub fn summary(&self) -> String {
+        let counts = self.counts.lock().unwrap();
+        let mut out = format!(
+            "{:>4} {:>11} {:>11} {:>11} {:>11}\n",
+            "Zoom", "Unchanged", "Changed", "Added", "Removed"
+        );
+        for (z, c) in counts.iter() {
+            out.push_str(&format!(
+                "{:>4} {:>11} {:>11} {:>11} {:>11}\n",
+                z, c.unchanged, c.changed, c.added, c.removed
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::write_tiles;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn counts_reused_and_changed_tiles_per_zoom() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.pmtiles");
+        let tiles = vec![
+            (Tile::new(0, 0, 0), b"root".to_vec()),
+            (Tile::new(1, 0, 0), b"a".to_vec()),
+            (Tile::new(1, 0, 1), b"b".to_vec()),
+            (Tile::new(1, 1, 1), b"c".to_vec()),
+        ];
+        write_tiles(&output, "png", tiles).unwrap();
+        let refresh = Refresh::new(
+            AppendReader::new(&output).await.unwrap(),
+            ValidatorStore::default(),
+            SourceStore::default(),
+        );
+
+        // Answered with 304 Not Modified
+        refresh.record(&Tile::new(0, 0, 0), RefreshOutcome::Unchanged);
+        // Gzipped by the server, but the same tile
+        refresh
+            .compare(&Tile::new(1, 0, 0), Some(&gzip(b"a")))
+            .await
+            .unwrap();
+        refresh
+            .compare(&Tile::new(1, 0, 1), Some(b"new"))
+            .await
+            .unwrap();
+        refresh.compare(&Tile::new(1, 1, 1), None).await.unwrap();
+        refresh
+            .compare(&Tile::new(1, 1, 0), Some(b"d"))
+            .await
+            .unwrap();
+        // Neither in the archive nor at the source
+        refresh.compare(&Tile::new(2, 0, 0), None).await.unwrap();
+
+        let summary = refresh.summary();
+        let rows: Vec<Vec<_>> = summary
+            .lines()
+            .map(|l| l.split_whitespace().collect())
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                vec!["Zoom", "Unchanged", "Changed", "Added", "Removed"],
+                vec!["0", "1", "0", "0", "0"],
+                vec!["1", "1", "1", "1", "1"],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_validators_only_to_the_source_of_the_tile() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.pmtiles");
+        let tiles = vec![
+            (Tile::new(1, 0, 0), b"a".to_vec()),
+            (Tile::new(1, 0, 1), b"b".to_vec()),
+        ];
+        write_tiles(&output, "png", tiles).unwrap();
+        std::fs::write(
+            crate::validators::sidecar_path(&output),
+            "1/0/0\t\"a\"\t\n1/0/1\t\"b\"\t\n",
+        )
+        .unwrap();
+        std::fs::write(
+            crate::validators::sources_sidecar_path(&output),
+            "1/0/1\t1\n",
+        )
+        .unwrap();
+        let refresh = Refresh::new(
+            AppendReader::new(&output).await.unwrap(),
+            ValidatorStore::load(&output).unwrap(),
+            SourceStore::load(&output).unwrap(),
+        );
+
+        let etag = |tile, source| {
+            refresh
+                .validators(&tile, source)
+                .and_then(|v| v.etag.clone())
+        };
+        assert_eq!(etag(Tile::new(1, 0, 0), 0).as_deref(), Some("\"a\""));
+        assert_eq!(etag(Tile::new(1, 0, 0), 1), None);
+        assert_eq!(etag(Tile::new(1, 0, 1), 0), None);
+        assert_eq!(etag(Tile::new(1, 0, 1), 1).as_deref(), Some("\"b\""));
+    }
+}
@@ -0,0 +1,211 @@
+//! A JSON summary of a download run, written with `--report`.
+
+use std::{
+    collections::BTreeMap,
+    ops::AddAssign,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::{
+    cli::DownloadArgs,
+    progress::{ProgressListener, ProgressMsg},
+    tile::Tile,
+    tile_urls::redact_url,
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+struct ZoomStats {
+    downloaded: u64,
+    /// Bytes downloaded, before any processing
+    bytes: u64,
+    /// Tiles the source didn't have
+    empty: u64,
+    filled_from_parent: u64,
+    not_modified: u64,
+    failed: u64,
+    written: u64,
+    /// Written tiles with the same content as an earlier tile
+    deduplicated: u64,
+}
+
+impl AddAssign for ZoomStats {
+    fn add_assign(&mut self, other: Self) {
+        self.downloaded += other.downloaded;
+        self.bytes += other.bytes;
+        self.empty += other.empty;
+        self.filled_from_parent += other.filled_from_parent;
+        self.not_modified += other.not_modified;
+        self.failed += other.failed;
+        self.written += other.written;
+        self.deduplicated += other.deduplicated;
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    zooms: BTreeMap<u8, ZoomStats>,
+    /// Completed requests by status code; None for requests that got no response
+    statuses: BTreeMap<Option<u16>, u64>,
+    retries: u64,
+    total_latency: Duration,
+}
+
+impl Counts {
+    fn zoom(&mut self, tile: &Tile) -> &mut ZoomStats {
+        self.zooms.entry(tile.z()).or_default()
+    }
+}
+
+pub struct Report {
+    path: PathBuf,
+    started: Instant,
+    started_at: DateTime<Utc>,
+    counts: Mutex<Counts>,
+}
+
+impl Report {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            started: Instant::now(),
+            started_at: Utc::now(),
+            counts: Mutex::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the report. `error` is the error the run failed with, if any.
+    pub fn write(
+        &self,
+        cli: &DownloadArgs,
+        error: Option<&anyhow::Error>,
+        cancelled: bool,
+    ) -> Result<()> {
+        let report = self.to_json(effective_options(cli)?, error, cancelled);
+        write_json(&self.path, &report)
+            .with_context(|| format!("Failed to write the report to {}", self.path.display()))
+    }
+
+    fn to_json(&self, options: Value, error: Option<&anyhow::Error>, cancelled: bool) -> Value {
+        let counts = self.counts.lock().unwrap();
+        let mut totals = ZoomStats::default();
+        for stats in counts.zooms.values() {
+            totals += *stats;
+        }
+        let requests = counts.statuses.values().sum::<u64>();
+        let by_status = counts
+            .statuses
+            .iter()
+            .map(|(status, count)| {
+                let status = status.map_or("error".to_string(), |s| s.to_string());
+                (status, json!(count))
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let status = match (error, cancelled) {
+            (Some(_), _) => "failed",
+            (None, true) => "cancelled",
+            (None, false) => "completed",
+        };
+
+        json!({
+            "status": status,
+            "error": error.map(|e| format!("{:#}", e)),
+            "started_at": self.started_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "finished_at": Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            "wall_time_secs": self.started.elapsed().as_secs_f64(),
+            "options": options,
+            "totals": totals,
+            "zooms": counts.zooms,
+            "requests": {
+                "total": requests,
+                "by_status": by_status,
+                "retries": counts.retries,
+                "average_latency_ms": (requests > 0)
+                    .then(|| counts.total_latency.as_secs_f64() * 1000.0 / requests as f64),
+            },
+        })
+    }
+}
+
+impl ProgressListener for Report {
+    fn record(&self, msg: &ProgressMsg) {
+        let mut counts = self.counts.lock().unwrap();
+        match msg {
+            ProgressMsg::Downloaded(tile, size) => {
+                let stats = counts.zoom(tile);
+                stats.downloaded += 1;
+                stats.bytes += *size as u64;
+            }
+            ProgressMsg::Skipped(tile) => counts.zoom(tile).empty += 1,
+            ProgressMsg::FilledFromParent(tile) => counts.zoom(tile).filled_from_parent += 1,
+            ProgressMsg::NotModified(tile) => counts.zoom(tile).not_modified += 1,
+            ProgressMsg::Failed(tile, _) => counts.zoom(tile).failed += 1,
+            ProgressMsg::Written(tile) => counts.zoom(tile).written += 1,
+            ProgressMsg::Deduplicated(tile) => counts.zoom(tile).deduplicated += 1,
+            ProgressMsg::RequestFinished(status, latency) => {
+                *counts.statuses.entry(*status).or_default() += 1;
+                counts.total_latency += *latency;
+            }
+            ProgressMsg::Retry() => counts.retries += 1,
+            _ => {}
+        }
+    }
+}
+
+/// The options of the run, after defaults and implied options are applied, with secrets redacted.
+fn effective_options(cli: &DownloadArgs) -> Result<Value> {
+    let mut options = serde_json::to_value(cli)?;
+    options["urls"] = json!(cli.urls.iter().map(|u| redact_url(u)).collect::<Vec<_>>());
+    Ok(options)
+}
+
+fn write_json(path: &Path, value: &Value) -> Result<()> {
+    let mut out = serde_json::to_string_pretty(value)?;
+    out.push('\n');
+    std::fs::write(path, out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_events_per_zoom() {
+        let report = Report::new(PathBuf::from("report.json"));
+        let tile = Tile::new(3, 1, 2);
+        for msg in [
+            ProgressMsg::RequestFinished(Some(503), Duration::from_millis(30)),
+            ProgressMsg::Retry(),
+            ProgressMsg::RequestFinished(Some(200), Duration::from_millis(10)),
+            ProgressMsg::Downloaded(tile.clone(), 100),
+            ProgressMsg::Written(tile.clone()),
+            ProgressMsg::Deduplicated(tile.clone()),
+            ProgressMsg::Skipped(Tile::new(4, 0, 0)),
+        ] {
+            report.record(&msg);
+        }
+
+        let json = report.to_json(json!({}), None, false);
+        assert_eq!(json["status"], "completed");
+        assert_eq!(json["zooms"]["3"]["downloaded"], 1);
+        assert_eq!(json["zooms"]["3"]["bytes"], 100);
+        assert_eq!(json["zooms"]["3"]["deduplicated"], 1);
+        assert_eq!(json["zooms"]["4"]["empty"], 1);
+        assert_eq!(json["totals"]["written"], 1);
+        assert_eq!(json["requests"]["total"], 2);
+        assert_eq!(json["requests"]["by_status"]["503"], 1);
+        assert_eq!(json["requests"]["retries"], 1);
+        assert_eq!(json["requests"]["average_latency_ms"], 20.0);
+    }
+}
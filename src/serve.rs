@@ -0,0 +1,371 @@
+//! The `serve` subcommand: a local tile server with a map viewer, for previewing archives.
+
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Map, Value, json};
+use tokio::net::TcpListener;
+use tracing::warn;
+
+use crate::{
+    append_reader::AppendReader,
+    cli::ServeArgs,
+    http_server::{self, Request, Response},
+    progress::StatusOut,
+    tile::Tile,
+    tile_source::format_from_extension,
+    writer::tile_type_to_str,
+};
+
+const VIEWER: &str = include_str!("viewer.html");
+
+struct Tileset {
+    name: String,
+    /// The path the tileset is served under, empty when only one archive is served
+    prefix: String,
+    reader: AppendReader,
+    metadata: Map<String, Value>,
+    /// The extension of the tiles, from the tile type in the header
+    ext: &'static str,
+}
+
+pub async fn run(args: ServeArgs) -> Result<()> {
+    let tilesets = load_tilesets(&args.inputs).await?;
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    let addr = listener.local_addr()?;
+    for (input, tileset) in args.inputs.iter().zip(&tilesets) {
+        StatusOut::stdout().println(format_args!(
+            "Serving {} at http://{}{}/",
+            input.display(),
+            addr,
+            tileset.prefix
+        ));
+    }
+
+    let _server = serve_tilesets(listener, tilesets)?;
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Opens the archives, each served under its file name when there is more than one.
+async fn load_tilesets(inputs: &[PathBuf]) -> Result<Vec<Tileset>> {
+    let mut tilesets: Vec<Tileset> = Vec::new();
+    for input in inputs {
+        let name = input
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if tilesets.iter().any(|t| t.name == name) {
+            bail!("More than one archive is named {}", name);
+        }
+        let reader = AppendReader::new(input).await?;
+        let metadata = reader.metadata_json().await?;
+        let ext = tile_type_to_str(reader.header().tile_type)
+            .with_context(|| format!("Cannot serve {}", input.display()))?;
+        let prefix = match inputs.len() {
+            1 => String::new(),
+            _ => format!("/{}", name),
+        };
+        tilesets.push(Tileset {
+            name,
+            prefix,
+            reader,
+            metadata,
+            ext,
+        });
+    }
+    Ok(tilesets)
+}
+
+fn serve_tilesets(listener: TcpListener, tilesets: Vec<Tileset>) -> Result<http_server::Server> {
+    let addr = listener.local_addr()?;
+    let tilesets = Arc::new(tilesets);
+    Ok(http_server::serve(listener, move |request: Request| {
+        let tilesets = tilesets.clone();
+        async move {
+            match handle(&tilesets, &request, addr).await {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("{} {}: {:#}", request.method, request.path, e);
+                    Response::new(500).with_body(format!("{:#}\n", e))
+                }
+            }
+            .with_header("Access-Control-Allow-Origin", "*")
+        }
+    }))
+}
+
+async fn handle(tilesets: &[Tileset], request: &Request, addr: SocketAddr) -> Result<Response> {
+    if !matches!(request.method.as_str(), "GET" | "HEAD") {
+        return Ok(Response::new(405));
+    }
+    if tilesets.len() > 1 && request.path == "/" {
+        return Ok(index(tilesets));
+    }
+    // Tile URLs in the TileJSON point at the host the client used to reach us
+    let host = request
+        .header("Host")
+        .map_or_else(|| addr.to_string(), str::to_string);
+
+    for tileset in tilesets {
+        let Some(path) = request.path.strip_prefix(&tileset.prefix) else {
+            continue;
+        };
+        if !path.is_empty() && !path.starts_with('/') {
+            continue;
+        }
+        return match path {
+            "" | "/" => Ok(viewer(tileset)),
+            "/tiles.json" => Ok(Response::new(200)
+                .with_header("Content-Type", "application/json")
+                .with_body(tilejson(tileset, &host).to_string())),
+            _ => match parse_tile_path(path) {
+                Some((tile, ext)) => tile_response(tileset, &tile, ext).await,
+                None => Ok(Response::not_found()),
+            },
+        };
+    }
+    Ok(Response::not_found())
+}
+
+async fn tile_response(tileset: &Tileset, tile: &Tile, ext: &str) -> Result<Response> {
+    let header = tileset.reader.header();
+    if format_from_extension(ext) != tileset.ext {
+        return Ok(Response::not_found());
+    }
+    let Some(data) = tileset.reader.get_raw_tile(tile).await? else {
+        return Ok(Response::new(204));
+    };
+    // Tiles are sent as stored; the client decompresses them
+    let mut response = Response::new(200)
+        .with_header("Content-Type", header.tile_type.content_type())
+        .with_body(data.to_vec());
+    if let Some(encoding) = header.tile_compression.content_encoding() {
+        response = response.with_header("Content-Encoding", encoding);
+    }
+    Ok(response)
+}
+
+/// A tile from a `/{z}/{x}/{y}.{ext}` path, and its extension.
+pub fn parse_tile_path(path: &str) -> Option<(Tile, &str)> {
+    let mut parts = path.strip_prefix('/')?.split('/');
+    let (z, x, y) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let (y, ext) = y.split_once('.')?;
+    let (z, x, y) = (
+        z.parse::<u8>().ok()?,
+        x.parse::<u32>().ok()?,
+        y.parse::<u32>().ok()?,
+    );
+    if z > 31 || x >= 1 << z || y >= 1 << z {
+        return None;
+    }
+    Some((Tile::new(z, x, y), ext))
+}
+
+fn tilejson(tileset: &Tileset, host: &str) -> Value {
+    let header = tileset.reader.header();
+    let mut tilejson = json!({
+        "tilejson": "3.0.0",
+        "name": tileset.name,
+        "scheme": "xyz",
+        "tiles": [format!(
+            "http://{}{}/{{z}}/{{x}}/{{y}}.{}",
+            host,
+            tileset.prefix,
+            tileset.ext
+        )],
+        "minzoom": header.min_zoom,
+        "maxzoom": header.max_zoom,
+        "bounds": [
+            header.min_longitude,
+            header.min_latitude,
+            header.max_longitude,
+            header.max_latitude,
+        ],
+        "center": [header.center_longitude, header.center_latitude, header.center_zoom],
+    });
+    for key in [
+        "name",
+        "description",
+        "attribution",
+        "version",
+        "vector_layers",
+        "tileSize",
+        "encoding",
+    ] {
+        if let Some(value) = tileset.metadata.get(key) {
+            tilejson[key] = value.clone();
+        }
+    }
+    tilejson
+}
+
+fn viewer(tileset: &Tileset) -> Response {
+    let html = VIEWER
+        .replace("{{NAME}}", &escape_html(&tileset.name))
+        .replace("{{TILEJSON}}", &format!("{}/tiles.json", tileset.prefix));
+    Response::new(200)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_body(html)
+}
+
+fn index(tilesets: &[Tileset]) -> Response {
+    let mut html = String::from("<!DOCTYPE html>\n<meta charset=\"utf-8\">\n<ul>\n");
+    for tileset in tilesets {
+        let name = escape_html(&tileset.name);
+        html.push_str(&format!(
+            "<li><a href=\"{}/\">{}</a></li>\n",
+            tileset.prefix, name
+        ));
+    }
+    html.push_str("</ul>\n");
+    Response::new(200)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_body(html)
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, path::Path};
+
+    use flate2::read::GzDecoder;
+    use pmtiles::TileType;
+
+    use super::*;
+    use crate::{
+        mvt,
+        writer::{write_tiles, write_tiles_with_metadata},
+    };
+
+    /// Serves the archives on a free port, and returns the server and its URL.
+    async fn serve_archives(inputs: &[PathBuf]) -> (http_server::Server, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let tilesets = load_tilesets(inputs).await.unwrap();
+        (serve_tilesets(listener, tilesets).unwrap(), url)
+    }
+
+    async fn get(url: &str) -> reqwest::Response {
+        reqwest::get(url).await.unwrap()
+    }
+
+    async fn get_json(url: &str) -> Value {
+        serde_json::from_slice(&get(url).await.bytes().await.unwrap()).unwrap()
+    }
+
+    fn header<'a>(response: &'a reqwest::Response, name: &str) -> Option<&'a str> {
+        response.headers().get(name).map(|v| v.to_str().unwrap())
+    }
+
+    fn write_png_archive(path: &Path) {
+        let metadata = json!({"attribution": "© Example", "description": "Test tiles"});
+        write_tiles_with_metadata(
+            path,
+            "png",
+            metadata.as_object().unwrap().clone(),
+            vec![(Tile::new(0, 0, 0), vec![1, 2, 3])],
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_tiles_and_tilejson() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("tiles.pmtiles");
+        write_png_archive(&input);
+        let (_server, url) = serve_archives(std::slice::from_ref(&input)).await;
+
+        let response = get(&format!("{}/0/0/0.png", url)).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some(TileType::Png.content_type())
+        );
+        assert_eq!(header(&response, "Content-Encoding"), None);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(response.bytes().await.unwrap().as_ref(), [1, 2, 3]);
+
+        assert_eq!(get(&format!("{}/1/1/1.png", url)).await.status(), 204);
+        assert_eq!(get(&format!("{}/0/0/0.jpg", url)).await.status(), 404);
+        assert_eq!(get(&format!("{}/0/0/0", url)).await.status(), 404);
+
+        let tilejson = get_json(&format!("{}/tiles.json", url)).await;
+        assert_eq!(
+            tilejson["tiles"][0],
+            format!("{}/{{z}}/{{x}}/{{y}}.png", url)
+        );
+        assert_eq!(tilejson["name"], "tiles");
+        assert_eq!(tilejson["attribution"], "© Example");
+        assert_eq!(tilejson["description"], "Test tiles");
+        assert_eq!(tilejson["maxzoom"], 0);
+    }
+
+    #[tokio::test]
+    async fn routes_several_archives_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let raster = dir.path().join("raster.pmtiles");
+        write_png_archive(&raster);
+        let vector = dir.path().join("vector.pmtiles");
+        let tile = mvt::encode(&mvt::VectorTile::default(), false).unwrap();
+        write_tiles(&vector, "mvt", vec![(Tile::new(0, 0, 0), tile.clone())]).unwrap();
+        let (_server, url) = serve_archives(&[raster, vector]).await;
+
+        let index = get(&format!("{}/", url)).await.text().await.unwrap();
+        assert!(index.contains("<a href=\"/raster/\">raster</a>"));
+        assert!(index.contains("<a href=\"/vector/\">vector</a>"));
+
+        let response = get(&format!("{}/raster/0/0/0.png", url)).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.bytes().await.unwrap().as_ref(), [1, 2, 3]);
+
+        // Vector tiles are sent compressed, as stored
+        let response = get(&format!("{}/vector/0/0/0.mvt", url)).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some(TileType::Mvt.content_type())
+        );
+        assert_eq!(header(&response, "Content-Encoding"), Some("gzip"));
+        let mut data = Vec::new();
+        GzDecoder::new(response.bytes().await.unwrap().as_ref())
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, tile);
+
+        let tilejson = get_json(&format!("{}/vector/tiles.json", url)).await;
+        assert_eq!(
+            tilejson["tiles"][0],
+            format!("{}/vector/{{z}}/{{x}}/{{y}}.mvt", url)
+        );
+
+        assert_eq!(get(&format!("{}/0/0/0.png", url)).await.status(), 404);
+        assert_eq!(get(&format!("{}/other/0/0/0.png", url)).await.status(), 404);
+        assert_eq!(
+            get(&format!("{}/rasters/0/0/0.png", url)).await.status(),
+            404
+        );
+    }
+
+    #[test]
+    fn parses_tile_paths() {
+        let (tile, ext) = parse_tile_path("/3/1/2.pbf").unwrap();
+        assert!(tile == Tile::new(3, 1, 2));
+        assert_eq!(ext, "pbf");
+        assert!(parse_tile_path("/3/8/2.png").is_none());
+        assert!(parse_tile_path("/3/1/2").is_none());
+        assert!(parse_tile_path("/tiles.json").is_none());
+        assert!(parse_tile_path("/a/3/1/2.png").is_none());
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use anyhow::Result;
+
+use crate::tile::Tile;
+
+/// Tile data held in a single temporary file until it can be written to an archive. The file is
+/// removed when the spill is dropped.
+pub struct TileSpill {
+    inner: Mutex<SpillFile>,
+}
+
+struct SpillFile {
+    file: File,
+    /// Offset and length of every tile's data. A tile inserted again points at its newest data.
+    entries: HashMap<Tile, (u64, u32)>,
+    end: u64,
+}
+
+impl TileSpill {
+    /// Creates the spill file in `dir`, which should be on the same disk as the output.
+    pub fn new_in(dir: &Path) -> Result<Self> {
+        Ok(Self {
+            inner: Mutex::new(SpillFile {
+                file: tempfile::tempfile_in(dir)?,
+                entries: HashMap::new(),
+                end: 0,
+            }),
+        })
+    }
+
+    pub fn insert(&self, tile: Tile, data: &[u8]) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let offset = inner.end;
+        inner.file.seek(SeekFrom::Start(offset))?;
+        inner.file.write_all(data)?;
+        inner.end += data.len() as u64;
+        inner.entries.insert(tile, (offset, data.len().try_into()?));
+        Ok(())
+    }
+
+    pub fn get(&self, tile: &Tile) -> Result<Option<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(&(offset, length)) = inner.entries.get(tile) else {
+            return Ok(None);
+        };
+        let mut data = vec![0; length as usize];
+        inner.file.seek(SeekFrom::Start(offset))?;
+        inner.file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_the_newest_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = TileSpill::new_in(dir.path()).unwrap();
+        spill.insert(Tile::new(1, 0, 0), b"first").unwrap();
+        spill.insert(Tile::new(1, 1, 0), b"second").unwrap();
+        spill.insert(Tile::new(1, 0, 0), b"third").unwrap();
+
+        assert_eq!(spill.get(&Tile::new(1, 0, 0)).unwrap().unwrap(), b"third");
+        assert_eq!(spill.get(&Tile::new(1, 1, 0)).unwrap().unwrap(), b"second");
+        assert!(spill.get(&Tile::new(1, 1, 1)).unwrap().is_none());
+        // Only the spill file, which has no name
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -20,6 +20,22 @@ impl Tile {
         self.0.into()
     }
 
+    /// The tile one zoom level up that contains this tile, or None at zoom 0.
+    pub fn parent(&self) -> Option<Tile> {
+        (self.z() > 0).then(|| Tile::new(self.z() - 1, self.x() / 2, self.y() / 2))
+    }
+
+    /// The four tiles one zoom level down, in top-left, top-right, bottom-left, bottom-right order.
+    pub fn children(&self) -> [Tile; 4] {
+        let (z, x, y) = (self.z() + 1, self.x() * 2, self.y() * 2);
+        [
+            Tile::new(z, x, y),
+            Tile::new(z, x + 1, y),
+            Tile::new(z, x, y + 1),
+            Tile::new(z, x + 1, y + 1),
+        ]
+    }
+
     pub fn bounds(&self) -> (f32, f32, f32, f32) {
         let x = self.x() as f32;
         let y = self.y() as f32;
@@ -0,0 +1,51 @@
+use pmtiles::TileType;
+
+use crate::{mvt, raster};
+
+/// Detects the image format of raster tile data from its magic bytes.
+/// Returns `None` for anything that isn't a known raster format, including vector tiles.
+pub fn sniff_raster(data: &[u8]) -> Option<TileType> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(TileType::Png)
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some(TileType::Jpeg)
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some(TileType::Webp)
+    } else if data.len() >= 12
+        && &data[4..8] == b"ftyp"
+        && matches!(&data[8..12], b"avif" | b"avis")
+    {
+        Some(TileType::Avif)
+    } else {
+        None
+    }
+}
+
+/// Returns true if a tile has nothing to show: a fully transparent raster tile, or a vector tile
+/// without any features.
+pub fn is_blank(data: &[u8]) -> bool {
+    match sniff_raster(data) {
+        Some(_) => raster::is_transparent(data),
+        None => mvt::decode(data).is_ok_and(|t| t.layers.iter().all(|l| l.features.is_empty())),
+    }
+}
+
+/// Returns true if the data can be read as a tile of the given type.
+pub fn is_readable_as(tile_type: TileType, data: &[u8]) -> bool {
+    match tile_type {
+        TileType::Mvt => mvt::decode(data).is_ok(),
+        TileType::Png | TileType::Jpeg | TileType::Webp | TileType::Avif => {
+            sniff_raster(data) == Some(tile_type)
+        }
+        _ => !data.is_empty(),
+    }
+}
+
+/// Returns true if two tiles have the same content, ignoring any gzip compression applied by the
+/// source or the archive.
+pub fn same_tile_data(a: &[u8], b: &[u8]) -> bool {
+    match (mvt::decompress(a), mvt::decompress(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
@@ -5,9 +5,12 @@ use std::{
     str::FromStr,
 };
 
-use crate::{tile::Tile, tile_list_format::compile_tile_format};
+use crate::{
+    cli::TileSelectionArgs, polygon::Polygon, tile::Tile, tile_list_format::compile_tile_format,
+};
 use anyhow::Result;
 
+#[derive(Clone)]
 pub struct TileListMeta {
     pub min_zoom: u8,
     pub max_zoom: u8,
@@ -53,6 +56,32 @@ pub struct TileList {
 }
 
 impl TileList {
+    /// Builds the list of tiles selected by the zoom, tile list and bbox options.
+    pub fn from_selection(selection: &TileSelectionArgs) -> Result<Self> {
+        let mut tile_list = if let Some(tile_list_path) = &selection.tile_list {
+            let mut tile_list =
+                TileList::parse_from_file(tile_list_path, &selection.tile_list_format)?;
+            tile_list.filter_zooms(selection.minimum_zoom, selection.maximum_zoom);
+            tile_list
+        } else {
+            TileList::from_zoom_range(selection.minimum_zoom, selection.maximum_zoom)
+        };
+        if let Some(bbox_str) = &selection.bbox {
+            tile_list.filter_bbox(bbox_str.parse()?);
+        }
+        Ok(tile_list)
+    }
+
+    /// Builds a tile list from arbitrary tiles, sorting them in tile ID order.
+    pub fn from_tiles(mut tiles: Vec<Tile>) -> Self {
+        tiles.sort_by_key(|a| a.to_id());
+        let (min_zoom, max_zoom) = tiles.iter().fold((32u8, 0u8), |(min_z, max_z), t| {
+            (min_z.min(t.z()), max_z.max(t.z()))
+        });
+        let meta = TileListMeta::new(min_zoom, max_zoom, &tiles);
+        Self { tiles, meta }
+    }
+
     pub fn parse_from_file(path: &str, format: &str) -> Result<Self> {
         let format_re = compile_tile_format(format)?;
         let reader = BufReader::new(File::open(path)?);
@@ -89,6 +118,23 @@ impl TileList {
         Self { tiles, meta }
     }
 
+    /// Replaces every tile with its parent, for output tiles assembled from four source tiles.
+    /// Tiles at zoom 0 have no parent and are dropped.
+    pub fn shift_to_parents(&mut self) {
+        let parents = self
+            .tiles
+            .iter()
+            .filter_map(Tile::parent)
+            .collect::<HashSet<_>>();
+        self.tiles = parents.into_iter().collect();
+        self.tiles.sort_by_key(|a| a.to_id());
+        self.meta = TileListMeta::new(
+            self.meta.min_zoom.saturating_sub(1),
+            self.meta.max_zoom.saturating_sub(1),
+            &self.tiles,
+        );
+    }
+
     pub fn filter_zooms(&mut self, min: u8, max: u8) {
         self.tiles.retain(|tile| tile.z() >= min && tile.z() <= max);
         self.meta = TileListMeta::new(min, max, &self.tiles);
@@ -102,11 +148,23 @@ impl TileList {
                 || tile_max_lat < bbox.min_y()
                 || tile_min_lat > bbox.max_y())
         });
+        self.clip_bounds((bbox.min_x(), bbox.min_y(), bbox.max_x(), bbox.max_y()));
+    }
+
+    /// Keeps only the tiles that overlap the polygon.
+    pub fn filter_polygon(&mut self, polygon: &Polygon) {
+        self.tiles
+            .retain(|tile| polygon.intersects_rect(tile.bounds()));
+        self.clip_bounds(polygon.bounds());
+    }
+
+    /// Shrinks the metadata bounds (and center) to the given (min_lon, min_lat, max_lon, max_lat).
+    fn clip_bounds(&mut self, (min_x, min_y, max_x, max_y): (f32, f32, f32, f32)) {
         if let Some((min_lon, min_lat, max_lon, max_lat)) = self.meta.bounds {
-            let new_min_lon = min_lon.max(bbox.min_x());
-            let new_min_lat = min_lat.max(bbox.min_y());
-            let new_max_lon = max_lon.min(bbox.max_x());
-            let new_max_lat = max_lat.min(bbox.max_y());
+            let new_min_lon = min_lon.max(min_x);
+            let new_min_lat = min_lat.max(min_y);
+            let new_max_lon = max_lon.min(max_x);
+            let new_max_lat = max_lat.min(max_y);
             self.meta.bounds = Some((new_min_lon, new_min_lat, new_max_lon, new_max_lat));
             self.meta.center = Some((
                 (new_min_lon + new_max_lon) / 2.0,
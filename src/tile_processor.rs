@@ -0,0 +1,32 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{progress::ProgressSender, tile::Tile};
+
+/// A step applied to every downloaded tile before it is written, e.g. re-encoding it.
+/// Processors run on a blocking thread, so they may do CPU-heavy work.
+pub trait TileProcessor: Send + Sync {
+    fn process(&self, tile: &Tile, data: Vec<u8>, progress_tx: &ProgressSender) -> Result<Vec<u8>>;
+}
+
+/// Runs the processors on a tile, in order.
+pub async fn process_tile(
+    processors: &[Arc<dyn TileProcessor>],
+    tile: &Tile,
+    data: Vec<u8>,
+    progress_tx: &ProgressSender,
+) -> Result<Vec<u8>> {
+    if processors.is_empty() {
+        return Ok(data);
+    }
+    let processors = processors.to_vec();
+    let tile = tile.clone();
+    let progress_tx = progress_tx.clone();
+    tokio::task::spawn_blocking(move || {
+        processors
+            .iter()
+            .try_fold(data, |data, p| p.process(&tile, data, &progress_tx))
+    })
+    .await?
+}
@@ -0,0 +1,380 @@
+//! Where tiles come from: HTTP URL templates, local directories, MBTiles and PMTiles archives.
+//! The downloader drives any [`TileSource`], so new kinds of sources don't need to know about
+//! concurrency or the order tiles are written in.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use bytes::Bytes;
+use reqwest::Client;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+
+use crate::{
+    append_reader::AppendReader,
+    downloader::{TileResponse, build_client, download_tile, fetch_tile},
+    progress::ProgressSender,
+    tile::Tile,
+    tile_urls::{TileUrl, infer_tile_format, redact_url},
+    validators::Validators,
+    writer::tile_type_to_str,
+};
+
+/// What a source knows about its tiles. Every field is optional.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceHints {
+    /// The tile format, as an extension like `png` or `mvt`
+    pub format: Option<String>,
+    pub minimum_zoom: Option<u8>,
+    pub maximum_zoom: Option<u8>,
+}
+
+#[async_trait]
+pub trait TileSource: Send + Sync {
+    /// Describes the source in logs and metadata, with secrets redacted.
+    fn name(&self) -> String;
+
+    fn hints(&self) -> SourceHints {
+        SourceHints::default()
+    }
+
+    /// The data of a tile, or None if the source doesn't have it.
+    async fn fetch(&self, tile: &Tile) -> Result<Option<Bytes>>;
+
+    /// Fetches a tile unless it hasn't changed since `validators` were recorded. Sources that
+    /// can't tell always return the tile.
+    async fn fetch_if_modified(
+        &self,
+        tile: &Tile,
+        _validators: Option<&Validators>,
+    ) -> Result<TileResponse> {
+        Ok(match self.fetch(tile).await? {
+            Some(data) => TileResponse::Data(data.to_vec(), Validators::default()),
+            None => TileResponse::Empty,
+        })
+    }
+}
+
+/// Opens the source given on the command line: an `http(s)://` URL template, an `.mbtiles` or
+/// `.pmtiles` file, or a local directory (`tiles/` or `tiles/{z}/{x}/{y}.png`). HTTP requests are
+/// reported to `progress_tx`, if given.
+pub async fn open_source(
+    spec: &str,
+    progress_tx: Option<&ProgressSender>,
+) -> Result<Arc<dyn TileSource>> {
+    if spec.starts_with("http://") || spec.starts_with("https://") {
+        let mut source = HttpSource::new(spec);
+        if let Some(progress_tx) = progress_tx {
+            source = source.with_progress(progress_tx.clone());
+        }
+        return Ok(Arc::new(source));
+    }
+    let path = Path::new(spec.strip_prefix("file://").unwrap_or(spec));
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("mbtiles") => Ok(Arc::new(MbTilesSource::open(path)?)),
+        Some("pmtiles") => Ok(Arc::new(PmTilesSource::open(path).await?)),
+        _ => Ok(Arc::new(DirectorySource::open(path)?)),
+    }
+}
+
+/// A tile format from a file extension or MBTiles `format`, e.g. `pbf` is `mvt`.
+pub fn format_from_extension(ext: &str) -> String {
+    match ext.to_ascii_lowercase().as_str() {
+        "pbf" => "mvt".to_string(),
+        "jpeg" => "jpg".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Tiles served over HTTP at a URL template, downloaded with retries.
+pub struct HttpSource {
+    url_template: String,
+    client: Client,
+    progress_tx: Option<ProgressSender>,
+}
+
+impl HttpSource {
+    pub fn new(url_template: &str) -> Self {
+        Self {
+            url_template: url_template.to_string(),
+            client: build_client(),
+            progress_tx: None,
+        }
+    }
+
+    /// Reports every request and retry to `progress_tx`.
+    pub fn with_progress(mut self, progress_tx: ProgressSender) -> Self {
+        self.progress_tx = Some(progress_tx);
+        self
+    }
+
+    fn tile_url(&self, tile: &Tile) -> TileUrl {
+        TileUrl::from_template(&self.url_template, tile.clone())
+    }
+}
+
+#[async_trait]
+impl TileSource for HttpSource {
+    fn name(&self) -> String {
+        redact_url(&self.url_template)
+    }
+
+    fn hints(&self) -> SourceHints {
+        SourceHints {
+            format: Some(infer_tile_format(&self.url_template)),
+            ..Default::default()
+        }
+    }
+
+    async fn fetch(&self, tile: &Tile) -> Result<Option<Bytes>> {
+        let data = download_tile(&self.client, self.tile_url(tile), self.progress_tx.as_ref());
+        Ok(data.await?.map(Bytes::from))
+    }
+
+    async fn fetch_if_modified(
+        &self,
+        tile: &Tile,
+        validators: Option<&Validators>,
+    ) -> Result<TileResponse> {
+        let tile_url = self.tile_url(tile);
+        fetch_tile(
+            &self.client,
+            tile_url,
+            validators,
+            self.progress_tx.as_ref(),
+        )
+        .await
+    }
+}
+
+/// Tiles stored as files in a local directory, e.g. `tiles/{z}/{x}/{y}.png`.
+pub struct DirectorySource {
+    path_template: String,
+}
+
+impl DirectorySource {
+    /// Opens a path template, or a directory laid out as `{z}/{x}/{y}.<ext>`. The extension of a
+    /// directory is taken from the first tile found in it.
+    pub fn open(path: &Path) -> Result<Self> {
+        let path_template = path.to_string_lossy().to_string();
+        if path_template.contains("{z}") {
+            return Ok(Self { path_template });
+        }
+        if !path.is_dir() {
+            bail!(
+                "{} is not a URL, a tile directory, or an .mbtiles or .pmtiles file",
+                path.display()
+            );
+        }
+        let Some(ext) = find_tile_extension(path)? else {
+            bail!("No tiles found in {}", path.display());
+        };
+        let template = path.join("{z}").join("{x}").join(format!("{{y}}.{}", ext));
+        Ok(Self {
+            path_template: template.to_string_lossy().to_string(),
+        })
+    }
+
+    fn tile_path(&self, tile: &Tile) -> PathBuf {
+        PathBuf::from(TileUrl::from_template(&self.path_template, tile.clone()).url())
+    }
+}
+
+/// The extension of the first `{z}/{x}/{y}.<ext>` file in a directory.
+fn find_tile_extension(root: &Path) -> Result<Option<String>> {
+    for z in std::fs::read_dir(root)? {
+        let z = z?.path();
+        if !z.is_dir() {
+            continue;
+        }
+        for x in std::fs::read_dir(&z)? {
+            let x = x?.path();
+            if !x.is_dir() {
+                continue;
+            }
+            for y in std::fs::read_dir(&x)? {
+                if let Some(ext) = y?.path().extension().and_then(|e| e.to_str()) {
+                    return Ok(Some(ext.to_string()));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[async_trait]
+impl TileSource for DirectorySource {
+    fn name(&self) -> String {
+        self.path_template.clone()
+    }
+
+    fn hints(&self) -> SourceHints {
+        let ext = Path::new(&self.path_template)
+            .extension()
+            .and_then(|e| e.to_str());
+        SourceHints {
+            format: ext.map(format_from_extension),
+            ..Default::default()
+        }
+    }
+
+    async fn fetch(&self, tile: &Tile) -> Result<Option<Bytes>> {
+        let path = self.tile_path(tile);
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+}
+
+/// Tiles in an MBTiles (SQLite) file. Rows are numbered from the bottom (TMS), unlike XYZ tiles.
+pub struct MbTilesSource {
+    path: PathBuf,
+    connection: Arc<Mutex<Connection>>,
+    hints: SourceHints,
+}
+
+impl MbTilesSource {
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Failed to open MBTiles file {}", path.display()))?;
+        let metadata = |name: &str| -> Result<Option<String>> {
+            let value = connection
+                .query_row(
+                    "SELECT value FROM metadata WHERE name = ?1",
+                    [name],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(value)
+        };
+        let hints = SourceHints {
+            format: metadata("format")?.map(|f| format_from_extension(&f)),
+            minimum_zoom: metadata("minzoom")?.and_then(|z| z.parse().ok()),
+            maximum_zoom: metadata("maxzoom")?.and_then(|z| z.parse().ok()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            connection: Arc::new(Mutex::new(connection)),
+            hints,
+        })
+    }
+}
+
+#[async_trait]
+impl TileSource for MbTilesSource {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn hints(&self) -> SourceHints {
+        self.hints.clone()
+    }
+
+    async fn fetch(&self, tile: &Tile) -> Result<Option<Bytes>> {
+        let connection = self.connection.clone();
+        let (z, x, y) = (tile.z(), tile.x(), tile.y());
+        let row = (1u32 << z) - 1 - y;
+        let data = tokio::task::spawn_blocking(move || {
+            connection
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                    (z, x, row),
+                    |row| row.get::<_, Vec<u8>>(0),
+                )
+                .optional()
+        })
+        .await??;
+        Ok(data.map(Bytes::from))
+    }
+}
+
+/// Tiles in another PMTiles archive. Tiles are decompressed.
+pub struct PmTilesSource {
+    path: PathBuf,
+    reader: AppendReader,
+}
+
+impl PmTilesSource {
+    pub async fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader: AppendReader::new(path).await?,
+        })
+    }
+}
+
+#[async_trait]
+impl TileSource for PmTilesSource {
+    fn name(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn hints(&self) -> SourceHints {
+        let header = self.reader.header();
+        SourceHints {
+            format: tile_type_to_str(header.tile_type).ok().map(str::to_string),
+            minimum_zoom: Some(header.min_zoom),
+            maximum_zoom: Some(header.max_zoom),
+        }
+    }
+
+    async fn fetch(&self, tile: &Tile) -> Result<Option<Bytes>> {
+        Ok(self.reader.get_tile(tile).await?.map(Bytes::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("3/1")).unwrap();
+        std::fs::write(dir.path().join("3/1/2.pbf"), b"tile").unwrap();
+
+        let source = open_source(dir.path().to_str().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(source.hints().format.as_deref(), Some("mvt"));
+        let data = source.fetch(&Tile::new(3, 1, 2)).await.unwrap();
+        assert_eq!(data.as_deref(), Some(&b"tile"[..]));
+        assert!(source.fetch(&Tile::new(3, 1, 3)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_mbtiles_rows_from_the_bottom() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiles.mbtiles");
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE metadata (name TEXT, value TEXT);
+                 CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+                 INSERT INTO metadata VALUES ('format', 'png'), ('minzoom', '0'), ('maxzoom', '5');
+                 INSERT INTO tiles VALUES (2, 1, 3, x'01');",
+            )
+            .unwrap();
+        drop(connection);
+
+        let source = open_source(path.to_str().unwrap(), None).await.unwrap();
+        assert_eq!(
+            source.hints(),
+            SourceHints {
+                format: Some("png".to_string()),
+                minimum_zoom: Some(0),
+                maximum_zoom: Some(5),
+            }
+        );
+        let data = source.fetch(&Tile::new(2, 1, 0)).await.unwrap();
+        assert_eq!(data.as_deref(), Some(&[1u8][..]));
+        assert!(source.fetch(&Tile::new(2, 1, 3)).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    str::Split,
+};
+
+use anyhow::Result;
+
+use crate::{tile::Tile, tile_list_format::compile_tile_format};
+
+/// HTTP cache validators returned with a tile, used for conditional requests when refreshing.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Returns these validators with any values present in `newer` replacing the old ones.
+    pub fn updated(self, newer: Validators) -> Self {
+        Self {
+            etag: newer.etag.or(self.etag),
+            last_modified: newer.last_modified.or(self.last_modified),
+        }
+    }
+
+    /// Writes a `z/x/y<TAB>etag<TAB>last-modified` line to the sidecar file.
+    pub fn write_line(&self, out: &mut impl Write, tile: &Tile) -> Result<()> {
+        writeln!(
+            out,
+            "{}\t{}\t{}",
+            tile,
+            self.etag.as_deref().unwrap_or(""),
+            self.last_modified.as_deref().unwrap_or("")
+        )?;
+        Ok(())
+    }
+}
+
+/// The validators of every tile in an archive, stored in a sidecar file next to it.
+#[derive(Default)]
+pub struct ValidatorStore(HashMap<Tile, Validators>);
+
+impl ValidatorStore {
+    /// Loads the sidecar file for the given archive. Returns an empty store if there is none.
+    pub fn load(archive: &Path) -> Result<Self> {
+        let non_empty = |s: Option<&str>| s.filter(|s| !s.is_empty()).map(str::to_string);
+        let store = read_sidecar(&sidecar_path(archive), |mut fields| {
+            Some(Validators {
+                etag: non_empty(fields.next()),
+                last_modified: non_empty(fields.next()),
+            })
+        })?;
+        Ok(Self(store))
+    }
+
+    pub fn get(&self, tile: &Tile) -> Option<&Validators> {
+        self.0.get(tile)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// The path of the validators sidecar file for an archive (`<archive>.validators.tsv`).
+pub fn sidecar_path(archive: &Path) -> PathBuf {
+    let mut path = OsString::from(archive.as_os_str());
+    path.push(".validators.tsv");
+    PathBuf::from(path)
+}
+
+/// The index of the source each tile of an archive was downloaded from, read from its sources
+/// sidecar file. Tiles that aren't listed came from the first source.
+#[derive(Default)]
+pub struct SourceStore(HashMap<Tile, usize>);
+
+impl SourceStore {
+    /// Loads the sidecar file for the given archive. Returns an empty store if there is none.
+    pub fn load(archive: &Path) -> Result<Self> {
+        let store = read_sidecar(&sources_sidecar_path(archive), |mut fields| {
+            fields.next()?.parse().ok()
+        })?;
+        Ok(Self(store))
+    }
+
+    pub fn get(&self, tile: &Tile) -> Option<usize> {
+        self.0.get(tile).copied()
+    }
+}
+
+/// The path of the sources sidecar file for an archive (`<archive>.sources.tsv`).
+pub fn sources_sidecar_path(archive: &Path) -> PathBuf {
+    let mut path = OsString::from(archive.as_os_str());
+    path.push(".sources.tsv");
+    PathBuf::from(path)
+}
+
+/// The path of the skipped tiles sidecar file for an archive (`<archive>.skipped.txt`). It lists
+/// the tiles the source didn't have, one per line.
+pub fn skipped_sidecar_path(archive: &Path) -> PathBuf {
+    let mut path = OsString::from(archive.as_os_str());
+    path.push(".skipped.txt");
+    PathBuf::from(path)
+}
+
+/// Loads the tiles listed in the skipped tiles sidecar file of an archive. Returns an empty set if
+/// there is none.
+pub fn load_skipped(archive: &Path) -> Result<HashSet<Tile>> {
+    let skipped = read_sidecar(&skipped_sidecar_path(archive), |_| Some(()))?;
+    Ok(skipped.into_keys().collect())
+}
+
+/// Reads a sidecar file of lines starting with a tile, followed by tab-separated fields that
+/// `parse` turns into a value. Lines that can't be parsed are skipped. Returns an empty map if
+/// there is no such file.
+fn read_sidecar<T>(
+    path: &Path,
+    mut parse: impl FnMut(Split<'_, char>) -> Option<T>,
+) -> Result<HashMap<Tile, T>> {
+    let mut store = HashMap::new();
+    if !path.exists() {
+        return Ok(store);
+    }
+
+    let tile_re = compile_tile_format("z/x/y")?;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        let Some(caps) = fields.next().and_then(|t| tile_re.captures(t)) else {
+            continue;
+        };
+        if let Some(value) = parse(fields) {
+            store.insert(Tile::from_captures(&caps), value);
+        }
+    }
+    Ok(store)
+}
@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+
+use crate::{
+    mvt::{self, Layer},
+    progress::ProgressSender,
+    tile::Tile,
+    tile_processor::TileProcessor,
+};
+
+/// Removes unwanted layers and properties from vector tiles.
+#[derive(Default)]
+pub struct VectorFilter {
+    /// If set, only these layers are kept
+    keep_layers: Option<HashSet<String>>,
+    drop_layers: HashSet<String>,
+    /// Properties dropped from every layer
+    drop_properties: HashSet<String>,
+    /// Properties dropped from a single layer, by layer name
+    drop_layer_properties: HashMap<String, HashSet<String>>,
+}
+
+impl VectorFilter {
+    /// `drop_properties` are either `key`, dropped from every layer, or `layer/key`.
+    pub fn new(keep_layers: &[String], drop_layers: &[String], drop_properties: &[String]) -> Self {
+        let mut filter = Self {
+            keep_layers: (!keep_layers.is_empty()).then(|| keep_layers.iter().cloned().collect()),
+            drop_layers: drop_layers.iter().cloned().collect(),
+            ..Default::default()
+        };
+        for property in drop_properties {
+            match property.split_once('/') {
+                Some((layer, key)) => {
+                    filter
+                        .drop_layer_properties
+                        .entry(layer.to_string())
+                        .or_default()
+                        .insert(key.to_string());
+                }
+                None => {
+                    filter.drop_properties.insert(property.clone());
+                }
+            }
+        }
+        filter
+    }
+
+    fn keeps_layer(&self, name: &str) -> bool {
+        !self.drop_layers.contains(name)
+            && self.keep_layers.as_ref().is_none_or(|k| k.contains(name))
+    }
+
+    fn drops_property(&self, layer: &str, key: &str) -> bool {
+        self.drop_properties.contains(key)
+            || self
+                .drop_layer_properties
+                .get(layer)
+                .is_some_and(|keys| keys.contains(key))
+    }
+
+    /// Removes the dropped properties from a layer, compacting its keys and values tables.
+    fn filter_layer(&self, layer: &mut Layer) {
+        let dropped = layer
+            .keys
+            .iter()
+            .map(|key| self.drops_property(&layer.name, key))
+            .collect::<Vec<_>>();
+        if !dropped.contains(&true) {
+            return;
+        }
+
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        let mut key_map = HashMap::new();
+        let mut value_map = HashMap::new();
+        for feature in &mut layer.features {
+            let mut tags = Vec::with_capacity(feature.tags.len());
+            for pair in feature.tags.chunks_exact(2) {
+                let (key, value) = (pair[0] as usize, pair[1] as usize);
+                if dropped.get(key).copied().unwrap_or(true) || value >= layer.values.len() {
+                    continue;
+                }
+                let key = *key_map.entry(key).or_insert_with(|| {
+                    keys.push(layer.keys[key].clone());
+                    keys.len() as u32 - 1
+                });
+                let value = *value_map.entry(value).or_insert_with(|| {
+                    values.push(layer.values[value].clone());
+                    values.len() as u32 - 1
+                });
+                tags.extend([key, value]);
+            }
+            feature.tags = tags;
+        }
+        layer.keys = keys;
+        layer.values = values;
+    }
+
+    pub fn filter(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut tile = mvt::decode(data)?;
+        tile.layers.retain(|layer| self.keeps_layer(&layer.name));
+        for layer in &mut tile.layers {
+            self.filter_layer(layer);
+        }
+        mvt::encode(&tile, mvt::is_gzipped(data))
+    }
+}
+
+impl TileProcessor for VectorFilter {
+    fn process(
+        &self,
+        _tile: &Tile,
+        data: Vec<u8>,
+        _progress_tx: &ProgressSender,
+    ) -> Result<Vec<u8>> {
+        self.filter(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mvt::{Feature, Value, VectorTile};
+
+    fn string_value(s: &str) -> Value {
+        Value {
+            string_value: Some(s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn layer(name: &str) -> Layer {
+        Layer {
+            version: 2,
+            name: name.to_string(),
+            features: vec![Feature {
+                id: Some(1),
+                tags: vec![0, 0, 1, 1, 2, 2],
+                r#type: Some(1),
+                geometry: vec![9, 2, 2],
+            }],
+            keys: vec!["class".into(), "name".into(), "name:en".into()],
+            values: vec![
+                string_value("park"),
+                string_value("上野"),
+                string_value("Ueno"),
+            ],
+            extent: Some(4096),
+        }
+    }
+
+    #[test]
+    fn drops_layers_and_properties() {
+        let tile = VectorTile {
+            layers: vec![layer("poi"), layer("landuse"), layer("debug")],
+        };
+        let filter = VectorFilter::new(
+            &[],
+            &["debug".to_string()],
+            &["name:en".to_string(), "poi/class".to_string()],
+        );
+        let filtered =
+            mvt::decode(&filter.filter(&mvt::encode(&tile, true).unwrap()).unwrap()).unwrap();
+
+        assert_eq!(filtered.layers.len(), 2);
+        let poi = &filtered.layers[0];
+        assert_eq!(poi.keys, vec!["name".to_string()]);
+        assert_eq!(poi.values, vec![string_value("上野")]);
+        assert_eq!(poi.features[0].tags, vec![0, 0]);
+        let landuse = &filtered.layers[1];
+        assert_eq!(landuse.keys, vec!["class".to_string(), "name".to_string()]);
+        assert_eq!(landuse.features[0].tags, vec![0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn keeps_only_listed_layers() {
+        let tile = VectorTile {
+            layers: vec![layer("poi"), layer("landuse")],
+        };
+        let filter = VectorFilter::new(&["landuse".to_string()], &[], &[]);
+        let filtered =
+            mvt::decode(&filter.filter(&mvt::encode(&tile, false).unwrap()).unwrap()).unwrap();
+        assert_eq!(filtered.layers.len(), 1);
+        assert_eq!(filtered.layers[0], tile.layers[1]);
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    mvt::{self, GeomType},
+    tile::Tile,
+};
+
+/// The maximum number of distinct values tracked per attribute for tilestats.
+const MAX_TILESTATS_VALUES: usize = 100;
+
+/// A TileJSON `vector_layers` entry.
+#[derive(Serialize)]
+pub struct VectorLayer {
+    pub id: String,
+    pub fields: BTreeMap<String, String>,
+    pub minzoom: u8,
+    pub maxzoom: u8,
+}
+
+/// The `tilestats` block, as produced by mapbox-geostats / tippecanoe.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TileStats {
+    pub layer_count: usize,
+    pub layers: Vec<LayerStats>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerStats {
+    pub layer: String,
+    pub count: u64,
+    pub geometry: &'static str,
+    pub attribute_count: usize,
+    pub attributes: Vec<AttributeStats>,
+}
+
+#[derive(Serialize)]
+pub struct AttributeStats {
+    pub attribute: String,
+    pub count: usize,
+    #[serde(rename = "type")]
+    pub attr_type: &'static str,
+    pub values: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FieldType {
+    String,
+    Number,
+    Boolean,
+    Mixed,
+}
+
+impl FieldType {
+    fn merge(self, other: FieldType) -> FieldType {
+        if self == other {
+            self
+        } else {
+            FieldType::Mixed
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::String => "String",
+            FieldType::Number => "Number",
+            FieldType::Boolean => "Boolean",
+            FieldType::Mixed => "Mixed",
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldAccumulator {
+    field_type: Option<FieldType>,
+    values: BTreeSet<String>,
+    json_values: Vec<serde_json::Value>,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl FieldAccumulator {
+    fn add(&mut self, value: &mvt::Value) {
+        let (field_type, json) = value_to_json(value);
+        self.field_type = Some(match self.field_type {
+            Some(t) => t.merge(field_type),
+            None => field_type,
+        });
+        if let Some(n) = json.as_f64() {
+            self.min = Some(self.min.map_or(n, |m| m.min(n)));
+            self.max = Some(self.max.map_or(n, |m| m.max(n)));
+        }
+        if self.values.len() < MAX_TILESTATS_VALUES && self.values.insert(json.to_string()) {
+            self.json_values.push(json);
+        }
+    }
+}
+
+#[derive(Default)]
+struct LayerAccumulator {
+    min_zoom: u8,
+    max_zoom: u8,
+    feature_count: u64,
+    geometry: BTreeSet<GeomType>,
+    fields: BTreeMap<String, FieldAccumulator>,
+}
+
+/// Accumulates layer, field and zoom information from MVT tiles as they are written.
+#[derive(Default)]
+pub struct VectorLayerCollector {
+    layers: BTreeMap<String, LayerAccumulator>,
+}
+
+impl VectorLayerCollector {
+    pub fn add_tile(&mut self, tile: &Tile, data: &[u8]) -> Result<()> {
+        let vt = mvt::decode(data)?;
+        let z = tile.z();
+        for layer in vt.layers {
+            let acc = self
+                .layers
+                .entry(layer.name.clone())
+                .or_insert_with(|| LayerAccumulator {
+                    min_zoom: z,
+                    max_zoom: z,
+                    ..Default::default()
+                });
+            acc.min_zoom = acc.min_zoom.min(z);
+            acc.max_zoom = acc.max_zoom.max(z);
+            acc.feature_count += layer.features.len() as u64;
+            for feature in &layer.features {
+                acc.geometry.insert(feature.geom_type());
+                for pair in feature.tags.chunks_exact(2) {
+                    let (Some(key), Some(value)) = (
+                        layer.keys.get(pair[0] as usize),
+                        layer.values.get(pair[1] as usize),
+                    ) else {
+                        continue;
+                    };
+                    acc.fields.entry(key.clone()).or_default().add(value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    pub fn vector_layers(&self) -> Vec<VectorLayer> {
+        self.layers
+            .iter()
+            .map(|(name, acc)| VectorLayer {
+                id: name.clone(),
+                fields: acc
+                    .fields
+                    .iter()
+                    .map(|(key, field)| {
+                        let t = field.field_type.unwrap_or(FieldType::String);
+                        (key.clone(), t.as_str().to_string())
+                    })
+                    .collect(),
+                minzoom: acc.min_zoom,
+                maxzoom: acc.max_zoom,
+            })
+            .collect()
+    }
+
+    pub fn tilestats(&self) -> TileStats {
+        let layers = self
+            .layers
+            .iter()
+            .map(|(name, acc)| LayerStats {
+                layer: name.clone(),
+                count: acc.feature_count,
+                geometry: geometry_name(&acc.geometry),
+                attribute_count: acc.fields.len(),
+                attributes: acc
+                    .fields
+                    .iter()
+                    .map(|(key, field)| AttributeStats {
+                        attribute: key.clone(),
+                        count: field.values.len(),
+                        attr_type: match field.field_type {
+                            Some(FieldType::Number) => "number",
+                            Some(FieldType::Boolean) => "boolean",
+                            Some(FieldType::Mixed) => "mixed",
+                            _ => "string",
+                        },
+                        values: field.json_values.clone(),
+                        min: field.min,
+                        max: field.max,
+                    })
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        TileStats {
+            layer_count: layers.len(),
+            layers,
+        }
+    }
+}
+
+fn geometry_name(types: &BTreeSet<GeomType>) -> &'static str {
+    let known = types
+        .iter()
+        .filter(|t| **t != GeomType::Unknown)
+        .collect::<Vec<_>>();
+    match known.as_slice() {
+        [GeomType::Point] => "Point",
+        [GeomType::LineString] => "LineString",
+        [GeomType::Polygon] => "Polygon",
+        [] => "Unknown",
+        _ => "Mixed",
+    }
+}
+
+fn value_to_json(value: &mvt::Value) -> (FieldType, serde_json::Value) {
+    if let Some(s) = &value.string_value {
+        (FieldType::String, s.clone().into())
+    } else if let Some(b) = value.bool_value {
+        (FieldType::Boolean, b.into())
+    } else if let Some(n) = value.int_value.or(value.sint_value) {
+        (FieldType::Number, n.into())
+    } else if let Some(n) = value.uint_value {
+        (FieldType::Number, n.into())
+    } else if let Some(n) = value.double_value.or(value.float_value.map(|f| f as f64)) {
+        (FieldType::Number, n.into())
+    } else {
+        (FieldType::String, serde_json::Value::Null)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mvt::{Feature, Layer, Value, VectorTile};
+    use prost::Message;
+
+    fn tile_with(name: &str, key: &str, value: Value) -> Vec<u8> {
+        let vt = VectorTile {
+            layers: vec![Layer {
+                version: 2,
+                name: name.to_string(),
+                features: vec![Feature {
+                    id: None,
+                    tags: vec![0, 0],
+                    r#type: Some(3),
+                    geometry: vec![],
+                }],
+                keys: vec![key.to_string()],
+                values: vec![value],
+                extent: None,
+            }],
+        };
+        vt.encode_to_vec()
+    }
+
+    #[test]
+    fn tracks_zoom_range_and_field_types() {
+        let mut collector = VectorLayerCollector::default();
+        let string_value = Value {
+            string_value: Some("a".to_string()),
+            ..Default::default()
+        };
+        let number_value = Value {
+            int_value: Some(3),
+            ..Default::default()
+        };
+        collector
+            .add_tile(
+                &Tile::new(3, 0, 0),
+                &tile_with("water", "kind", string_value),
+            )
+            .unwrap();
+        collector
+            .add_tile(
+                &Tile::new(5, 0, 0),
+                &tile_with("water", "kind", number_value),
+            )
+            .unwrap();
+
+        let layers = collector.vector_layers();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].id, "water");
+        assert_eq!(layers[0].minzoom, 3);
+        assert_eq!(layers[0].maxzoom, 5);
+        assert_eq!(layers[0].fields["kind"], "Mixed");
+
+        let stats = collector.tilestats();
+        assert_eq!(stats.layers[0].count, 2);
+        assert_eq!(stats.layers[0].geometry, "Polygon");
+    }
+}
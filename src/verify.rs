@@ -0,0 +1,240 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use anyhow::{Result, bail};
+use serde::Serialize;
+
+use crate::{
+    append_reader::AppendReader,
+    cli::VerifyArgs,
+    tile::Tile,
+    tile_format::{is_readable_as, same_tile_data},
+    tile_list::TileList,
+    tile_source::open_source,
+    validators::load_skipped,
+};
+
+#[derive(Serialize)]
+struct VerifyReport {
+    expected: usize,
+    present: usize,
+    /// Missing tile counts per zoom
+    missing: BTreeMap<u8, usize>,
+    /// Expected tiles the source didn't have when the archive was downloaded
+    skipped: usize,
+    unexpected: Vec<String>,
+    unreadable: Vec<String>,
+    sampled: usize,
+    mismatched: Vec<String>,
+}
+
+impl VerifyReport {
+    fn missing_total(&self) -> usize {
+        self.missing.values().sum()
+    }
+
+    fn is_ok(&self) -> bool {
+        self.missing_total() == 0
+            && self.unexpected.is_empty()
+            && self.unreadable.is_empty()
+            && self.mismatched.is_empty()
+    }
+}
+
+pub async fn run(args: VerifyArgs) -> Result<()> {
+    let (report, missing_tiles) = verify(&args).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report, &missing_tiles);
+    }
+
+    if !report.is_ok() {
+        bail!("Verification of {} failed.", args.input.display());
+    }
+    Ok(())
+}
+
+/// Checks the archive against the selection. Returns the report and the missing tiles.
+async fn verify(args: &VerifyArgs) -> Result<(VerifyReport, Vec<Tile>)> {
+    let reader = AppendReader::new(&args.input).await?;
+    let tile_type = reader.header().tile_type;
+
+    let expected_tiles = TileList::from_selection(&args.selection)?.tiles;
+    let expected_set = expected_tiles.iter().cloned().collect::<HashSet<_>>();
+    let existing = reader.get_tiles().await?;
+    let skipped = load_skipped(&args.input)?;
+
+    let (skipped_tiles, missing_tiles): (Vec<_>, Vec<_>) = expected_tiles
+        .iter()
+        .filter(|t| !existing.contains(t))
+        .cloned()
+        .partition(|t| skipped.contains(t));
+    let mut missing = BTreeMap::new();
+    for tile in &missing_tiles {
+        *missing.entry(tile.z()).or_insert(0) += 1;
+    }
+
+    let mut existing_sorted = existing.iter().collect::<Vec<_>>();
+    existing_sorted.sort_by_key(|t| t.to_id());
+    let unexpected = existing_sorted
+        .iter()
+        .filter(|t| !expected_set.contains(t))
+        .map(|t| t.to_string())
+        .collect();
+
+    let mut unreadable = Vec::new();
+    for tile in &existing_sorted {
+        let readable = match reader.get_tile(tile).await {
+            Ok(Some(data)) => is_readable_as(tile_type, &data),
+            _ => false,
+        };
+        if !readable {
+            unreadable.push(tile.to_string());
+        }
+    }
+
+    let mut mismatched = Vec::new();
+    let sample = fastrand::choose_multiple(existing_sorted.iter(), args.sample);
+    if let Some(url) = &args.url
+        && !sample.is_empty()
+    {
+        let source = open_source(url, None).await?;
+        for tile in &sample {
+            let fetched = source.fetch(tile).await?;
+            let stored = reader.get_tile(tile).await?;
+            if !same_tile(fetched.as_deref(), stored.as_deref()) {
+                mismatched.push(tile.to_string());
+            }
+        }
+    }
+
+    if let Some(path) = &args.missing_list {
+        let mut out = BufWriter::new(File::create(path)?);
+        for tile in &missing_tiles {
+            writeln!(out, "{}", tile)?;
+        }
+        out.flush()?;
+    }
+
+    let report = VerifyReport {
+        expected: expected_tiles.len(),
+        present: existing.len(),
+        missing,
+        skipped: skipped_tiles.len(),
+        unexpected,
+        unreadable,
+        sampled: sample.len(),
+        mismatched,
+    };
+    Ok((report, missing_tiles))
+}
+
+/// Compares tile data, ignoring any gzip compression applied by the source or the archive.
+fn same_tile(a: Option<&[u8]>, b: Option<&[u8]>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => same_tile_data(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn print_report(report: &VerifyReport, missing_tiles: &[Tile]) {
+    /// Print at most this many tiles per category
+    const MAX_LISTED: usize = 20;
+
+    println!("Expected tiles:   {}", report.expected);
+    println!("Present tiles:    {}", report.present);
+    println!("Missing tiles:    {}", report.missing_total());
+    for (z, count) in &report.missing {
+        println!("  zoom {:>2}: {}", z, count);
+    }
+    for tile in missing_tiles.iter().take(MAX_LISTED) {
+        println!("  {}", tile);
+    }
+    if report.skipped > 0 {
+        println!("Skipped tiles:    {}", report.skipped);
+    }
+    println!("Unexpected tiles: {}", report.unexpected.len());
+    for tile in report.unexpected.iter().take(MAX_LISTED) {
+        println!("  {}", tile);
+    }
+    println!("Unreadable tiles: {}", report.unreadable.len());
+    for tile in report.unreadable.iter().take(MAX_LISTED) {
+        println!("  {}", tile);
+    }
+    if report.sampled > 0 {
+        println!(
+            "Sampled tiles:    {} ({} mismatched)",
+            report.sampled,
+            report.mismatched.len()
+        );
+        for tile in report.mismatched.iter().take(MAX_LISTED) {
+            println!("  {}", tile);
+        }
+    }
+    if report.is_ok() {
+        println!("OK");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli::TileSelectionArgs, validators::skipped_sidecar_path, writer::write_tiles};
+
+    /// Bytes that are recognized as a PNG tile, different for every `n`.
+    fn png(n: u8) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.push(n);
+        data
+    }
+
+    #[tokio::test]
+    async fn reports_missing_and_mismatched_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        for (tile, n) in [((0, 0, 0), 0), ((1, 0, 0), 1), ((1, 1, 1), 2)] {
+            let (z, x, y) = tile;
+            std::fs::create_dir_all(source.join(format!("{}/{}", z, x))).unwrap();
+            std::fs::write(source.join(format!("{}/{}/{}.png", z, x, y)), png(n)).unwrap();
+        }
+        let input = dir.path().join("in.pmtiles");
+        let tiles = vec![
+            (Tile::new(0, 0, 0), png(0)),
+            // Changed since it was downloaded
+            (Tile::new(1, 0, 0), png(9)),
+            (Tile::new(1, 1, 1), png(2)),
+        ];
+        write_tiles(&input, "png", tiles).unwrap();
+        std::fs::write(skipped_sidecar_path(&input), "1/1/0\n").unwrap();
+        let missing_list = dir.path().join("missing.txt");
+
+        let (report, missing_tiles) = verify(&VerifyArgs {
+            input,
+            selection: TileSelectionArgs::zoom_range(0, 1),
+            url: Some(source.to_str().unwrap().to_string()),
+            sample: 10,
+            missing_list: Some(missing_list.clone()),
+            json: false,
+        })
+        .await
+        .unwrap();
+
+        assert!(!report.is_ok());
+        assert_eq!(report.expected, 5);
+        assert_eq!(report.present, 3);
+        assert_eq!(report.missing, BTreeMap::from([(1, 1)]));
+        assert_eq!(report.skipped, 1);
+        let missing_tiles: Vec<_> = missing_tiles.iter().map(|t| t.to_string()).collect();
+        assert_eq!(missing_tiles, ["1/0/1"]);
+        assert_eq!(std::fs::read_to_string(missing_list).unwrap(), "1/0/1\n");
+        assert!(report.unexpected.is_empty());
+        assert!(report.unreadable.is_empty());
+        assert_eq!(report.sampled, 3);
+        assert_eq!(report.mismatched, vec!["1/0/0"]);
+    }
+}
@@ -1,34 +1,112 @@
-use std::{collections::BTreeMap, fs::File, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Context, Result};
-use flume::Receiver;
+use anyhow::{Context, Result, bail};
+use flume::{Receiver, Sender};
 use pmtiles::{PmTilesStreamWriter, PmTilesWriter, TileType};
 use tempfile::NamedTempFile;
+use tracing::{info, warn};
 
 use crate::{
-    metadata::Metadata,
-    progress::{self, ProgressSender},
+    metadata::{Metadata, TileCounts},
+    progress::{self, Progress, ProgressSender},
+    spill::TileSpill,
     tile::Tile,
     tile_list::TileListMeta,
+    validators::{self, Validators},
+    vector_layers::VectorLayerCollector,
 };
 
-fn str_to_tile_type(s: &str) -> TileType {
-    match s {
+pub fn str_to_tile_type(s: &str) -> Result<TileType> {
+    Ok(match s {
         "png" => TileType::Png,
         "jpg" | "jpeg" => TileType::Jpeg,
         "webp" => TileType::Webp,
+        "avif" => TileType::Avif,
         "mvt" => TileType::Mvt,
-        _ => TileType::Png, // default to png
-    }
+        _ => bail!("Unknown tile format \"{}\"", s),
+    })
+}
+
+/// The extension accepted by `Writer::new` for a tile type.
+pub fn tile_type_to_str(tile_type: TileType) -> Result<&'static str> {
+    Ok(match tile_type {
+        TileType::Png => "png",
+        TileType::Jpeg => "jpg",
+        TileType::Webp => "webp",
+        TileType::Avif => "avif",
+        TileType::Mvt => "mvt",
+        TileType::Unknown => bail!("Unknown tile type"),
+    })
 }
 
 pub struct Writer {
     force: bool,
     output: PathBuf,
 
-    out_pmt: PmTilesStreamWriter<File>,
+    tile_type: TileType,
+    tile_list_meta: TileListMeta,
     out_pmt_f: NamedTempFile,
     progress_tx: ProgressSender,
+
+    metadata: Metadata,
+    /// Only set for vector tilesets; used to fill in `vector_layers` once every tile is received
+    vector_layers: Option<VectorLayerCollector>,
+    tilestats: bool,
+    /// Created when the first tile with cache validators is written
+    validators_out: Option<Sidecar>,
+    /// Created when the first tile with a source index is written
+    sources_out: Option<Sidecar>,
+    /// Created when the first tile without data is received
+    skipped_out: Option<Sidecar>,
+    /// Hashes of the tiles written so far, when duplicates are reported
+    seen_hashes: Option<HashSet<u64>>,
+    /// Reads the download counts for the provenance once every tile is written
+    tile_counts: Option<Box<dyn Fn() -> TileCounts + Send>>,
+}
+
+/// Where tiles are written as they are received.
+enum TileOut {
+    Archive(PmTilesStreamWriter<File>),
+    /// Tiles in the order they were received, held until the metadata they determine is known
+    Spill(TileSpill, Vec<Tile>),
+}
+
+/// A text file written next to the output while tiles are written, and persisted alongside it.
+struct Sidecar {
+    file: NamedTempFile,
+    out: BufWriter<File>,
+}
+
+impl Sidecar {
+    /// Returns the sidecar in `slot`, creating it first if needed.
+    fn get_or_create<'a>(
+        slot: &'a mut Option<Sidecar>,
+        output: &Path,
+    ) -> Result<&'a mut BufWriter<File>> {
+        if slot.is_none() {
+            let file = NamedTempFile::new_in(
+                output
+                    .parent()
+                    .context("Output path must have a parent directory")?,
+            )?;
+            let out = BufWriter::new(file.reopen()?);
+            *slot = Some(Sidecar { file, out });
+        }
+        Ok(&mut slot.as_mut().unwrap().out)
+    }
+
+    fn persist(mut self, path: PathBuf) -> Result<()> {
+        self.out.flush()?;
+        drop(self.out);
+        self.file.persist(path)?;
+        Ok(())
+    }
 }
 
 pub struct WriteTileMsg {
@@ -36,6 +114,10 @@ pub struct WriteTileMsg {
     pub tile: Tile,
     /// None if the tile was not found / no data
     pub data: Option<Vec<u8>>,
+    /// HTTP cache validators, recorded in a sidecar file for `--refresh`
+    pub validators: Option<Validators>,
+    /// The index of the source the tile was downloaded from, when there are several
+    pub source: Option<usize>,
 }
 
 impl Writer {
@@ -45,9 +127,10 @@ impl Writer {
         ext: &str,
         metadata: Metadata,
         tile_list_meta: TileListMeta,
+        tilestats: bool,
         progress_tx: ProgressSender,
     ) -> Result<Self> {
-        let tile_type = str_to_tile_type(ext);
+        let tile_type = str_to_tile_type(ext)?;
 
         if !force && output.exists() {
             return Err(anyhow::anyhow!(
@@ -62,63 +145,353 @@ impl Writer {
                 .context("Output path must have a parent directory")?,
         )?;
 
-        let mut out_pmt = PmTilesWriter::new(tile_type)
-            .metadata(serde_json::to_string(&metadata)?.as_str())
-            .min_zoom(tile_list_meta.min_zoom)
-            .max_zoom(tile_list_meta.max_zoom);
-        if let Some((lon, lat)) = tile_list_meta.center {
-            out_pmt = out_pmt.center(lon, lat);
-        }
-        if let Some((west, south, east, north)) = tile_list_meta.bounds {
-            out_pmt = out_pmt.bounds(west, south, east, north);
-        }
-        let out_pmt = out_pmt.create(out_pmt_f.reopen()?)?;
+        let vector_layers = (tile_type == TileType::Mvt).then(VectorLayerCollector::default);
 
         Ok(Self {
             force,
             output,
-            out_pmt,
+            tile_type,
+            tile_list_meta,
             out_pmt_f,
             progress_tx,
+            metadata,
+            vector_layers,
+            tilestats,
+            validators_out: None,
+            sources_out: None,
+            skipped_out: None,
+            seen_hashes: None,
+            tile_counts: None,
         })
     }
 
+    /// Report tiles with the same content as an earlier tile. This keeps a hash of every tile in memory.
+    pub fn with_duplicate_detection(mut self) -> Self {
+        self.seen_hashes = Some(HashSet::new());
+        self
+    }
+
+    /// Record tile counts in the provenance when finalizing. `tile_counts` is called after the
+    /// last tile is received; the number of written tiles is counted by the writer.
+    pub fn with_tile_counts(
+        mut self,
+        tile_counts: impl Fn() -> TileCounts + Send + 'static,
+    ) -> Self {
+        self.tile_counts = Some(Box::new(tile_counts));
+        self
+    }
+
+    /// Creates the archive. Its metadata is written before any tiles.
+    fn create_archive(&self) -> Result<PmTilesStreamWriter<File>> {
+        Ok(
+            pmtiles_writer(self.tile_type, &self.metadata, &self.tile_list_meta)?
+                .create(self.out_pmt_f.reopen()?)?,
+        )
+    }
+
     pub fn write(mut self, tile_rx: Receiver<WriteTileMsg>) -> Result<()> {
+        // When the metadata depends on the tiles, they are spilled to disk until the last one is
+        // received, so the archive can be written once with the final metadata.
+        let mut out = if self.vector_layers.is_some()
+            || (self.metadata.provenance.is_some() && self.tile_counts.is_some())
+        {
+            let dir = self
+                .output
+                .parent()
+                .context("Output path must have a parent directory")?;
+            TileOut::Spill(TileSpill::new_in(dir)?, Vec::new())
+        } else {
+            TileOut::Archive(self.create_archive()?)
+        };
         let mut next = 0usize;
+        let mut written = 0u64;
         // reorder buffer
         // TODO: use a more efficient structure
         let mut buf = BTreeMap::new();
         for msg in tile_rx {
-            let WriteTileMsg { index, tile, data } = msg;
-            buf.insert(index, (tile, data));
-            while let Some((tile, data)) = buf.remove(&next) {
+            let WriteTileMsg {
+                index,
+                tile,
+                data,
+                validators,
+                source,
+            } = msg;
+            buf.insert(index, (tile, data, validators, source));
+            while let Some((tile, data, validators, source)) = buf.remove(&next) {
                 if let Some(data) = data {
-                    self.out_pmt.add_tile(*tile, &data)?;
+                    if let Some(validators) = validators.filter(|v| !v.is_empty()) {
+                        let out = Sidecar::get_or_create(&mut self.validators_out, &self.output)?;
+                        validators.write_line(out, &tile)?;
+                    }
+                    if let Some(source) = source {
+                        let out = Sidecar::get_or_create(&mut self.sources_out, &self.output)?;
+                        writeln!(out, "{}\t{}", tile, source)?;
+                    }
+                    if let Some(collector) = &mut self.vector_layers
+                        && let Err(e) = collector.add_tile(&tile, &data)
+                    {
+                        warn!(%tile, "Failed to inspect vector tile: {}", e);
+                    }
+                    if let Some(seen_hashes) = &mut self.seen_hashes {
+                        let mut hasher = DefaultHasher::new();
+                        data.hash(&mut hasher);
+                        if !seen_hashes.insert(hasher.finish()) {
+                            self.progress_tx
+                                .send(progress::ProgressMsg::Deduplicated(tile.clone()))?;
+                        }
+                    }
+                    match &mut out {
+                        TileOut::Archive(out_pmt) => out_pmt.add_tile(*tile, &data)?,
+                        TileOut::Spill(spill, tiles) => {
+                            spill.insert(tile.clone(), &data)?;
+                            tiles.push(tile.clone());
+                        }
+                    }
+                    written += 1;
                     self.progress_tx
                         .send(progress::ProgressMsg::Written(tile))?;
+                } else {
+                    let out = Sidecar::get_or_create(&mut self.skipped_out, &self.output)?;
+                    writeln!(out, "{}", tile)?;
                 }
                 next += 1;
             }
+            self.progress_tx
+                .send(progress::ProgressMsg::ReorderBuffer(buf.len()))?;
         }
 
-        self.progress_tx.send(progress::ProgressMsg::Log(
-            "Finished writing tiles, finalizing archive...".to_string(),
-        ))?;
-        self.out_pmt.finalize()?;
+        info!("Finished writing tiles, finalizing archive...");
+        let out_pmt = match out {
+            TileOut::Archive(out_pmt) => out_pmt,
+            TileOut::Spill(spill, tiles) => {
+                if let Some(collector) = self.vector_layers.take().filter(|c| !c.is_empty()) {
+                    self.metadata.extra.remove("vector_layers");
+                    self.metadata.vector_layers = Some(collector.vector_layers());
+                    if self.tilestats {
+                        self.metadata.extra.remove("tilestats");
+                        self.metadata.tilestats = Some(collector.tilestats());
+                    }
+                }
+                if let (Some(provenance), Some(tile_counts)) =
+                    (&mut self.metadata.provenance, &self.tile_counts)
+                {
+                    provenance.tiles = Some(TileCounts {
+                        written_tiles: written,
+                        ..tile_counts()
+                    });
+                }
+                let mut out_pmt = self.create_archive()?;
+                for tile in tiles {
+                    let data = spill
+                        .get(&tile)?
+                        .context("Tile missing from the spill file")?;
+                    out_pmt.add_tile(*tile, &data)?;
+                }
+                out_pmt
+            }
+        };
+        out_pmt.finalize()?;
+
+        let out_pmt_f = self.out_pmt_f;
         if self.force {
-            self.out_pmt_f.persist(&self.output)?;
+            out_pmt_f.persist(&self.output)?;
         } else {
-            self.out_pmt_f.persist_noclobber(&self.output)?;
+            out_pmt_f.persist_noclobber(&self.output)?;
+        }
+        match self.validators_out {
+            Some(sidecar) => sidecar.persist(validators::sidecar_path(&self.output))?,
+            // Validators left over from the previous archive would no longer match its tiles
+            None => remove_if_exists(&validators::sidecar_path(&self.output))?,
+        }
+        match self.sources_out {
+            Some(sidecar) => sidecar.persist(validators::sources_sidecar_path(&self.output))?,
+            // Tiles are only listed when they came from one of several sources
+            None => remove_if_exists(&validators::sources_sidecar_path(&self.output))?,
+        }
+        match self.skipped_out {
+            Some(sidecar) => sidecar.persist(validators::skipped_sidecar_path(&self.output))?,
+            None => remove_if_exists(&validators::skipped_sidecar_path(&self.output))?,
         }
 
-        self.progress_tx.send(progress::ProgressMsg::Log(format!(
+        info!(
             "Finished writing {} tiles to {}.",
-            next + 1,
+            written,
             self.output.display()
-        )))?;
+        );
 
         self.progress_tx.send(progress::ProgressMsg::Finished())?;
 
         Ok(())
     }
 }
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Writes tiles sent by `produce` to a new archive created by `make_writer`, showing progress.
+/// Used by subcommands that build an archive from local data rather than downloading it.
+pub async fn write_archive<W, P, Fut>(expected_tiles: u64, make_writer: W, produce: P) -> Result<()>
+where
+    W: FnOnce(ProgressSender) -> Result<Writer>,
+    P: FnOnce(Sender<WriteTileMsg>, ProgressSender) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let (tile_tx, tile_rx) = flume::bounded(4096);
+    let (progress_tx, progress_rx) = flume::bounded(4096);
+    let writer = make_writer(progress_tx.clone())?;
+    let progress = Progress::new_write_only(expected_tiles);
+
+    let writer_task = tokio::task::spawn_blocking(move || writer.write(tile_rx));
+    let progress_task = tokio::task::spawn_blocking(move || progress.run(progress_rx));
+
+    // The writer finishes once every sender is dropped, so produce() must not outlive this call
+    let produced = produce(tile_tx, progress_tx).await;
+    let written = writer_task.await?;
+    progress_task.await??;
+    produced?;
+    written
+}
+
+fn pmtiles_writer(
+    tile_type: TileType,
+    metadata: &Metadata,
+    tile_list_meta: &TileListMeta,
+) -> Result<PmTilesWriter> {
+    let mut out_pmt = PmTilesWriter::new(tile_type)
+        .metadata(serde_json::to_string(metadata)?.as_str())
+        .min_zoom(tile_list_meta.min_zoom)
+        .max_zoom(tile_list_meta.max_zoom);
+    if let Some((lon, lat)) = tile_list_meta.center {
+        out_pmt = out_pmt.center(lon, lat);
+    }
+    if let Some((west, south, east, north)) = tile_list_meta.bounds {
+        out_pmt = out_pmt.bounds(west, south, east, north);
+    }
+    Ok(out_pmt)
+}
+
+/// Writes `tiles` to a new archive at `output`, for tests.
+#[cfg(test)]
+pub fn write_tiles(output: &Path, ext: &str, tiles: Vec<(Tile, Vec<u8>)>) -> Result<()> {
+    write_tiles_with_metadata(output, ext, serde_json::Map::new(), tiles)
+}
+
+/// Writes `tiles` and `metadata` to a new archive at `output`, for tests.
+#[cfg(test)]
+pub fn write_tiles_with_metadata(
+    output: &Path,
+    ext: &str,
+    metadata: serde_json::Map<String, serde_json::Value>,
+    tiles: Vec<(Tile, Vec<u8>)>,
+) -> Result<()> {
+    let tile_list: Vec<Tile> = tiles.iter().map(|(tile, _)| tile.clone()).collect();
+    let min_zoom = tile_list.iter().map(|t| t.z()).min().unwrap_or(0);
+    let max_zoom = tile_list.iter().map(|t| t.z()).max().unwrap_or(0);
+    let (tile_tx, tile_rx) = flume::unbounded();
+    let (progress_tx, _progress_rx) = flume::unbounded();
+    let writer = Writer::new(
+        output.to_path_buf(),
+        true,
+        ext,
+        Metadata::from_json(metadata),
+        TileListMeta::new(min_zoom, max_zoom, &tile_list),
+        false,
+        progress_tx,
+    )?;
+    for (index, (tile, data)) in tiles.into_iter().enumerate() {
+        tile_tx.send(WriteTileMsg {
+            index,
+            tile,
+            data: Some(data),
+            validators: None,
+            source: None,
+        })?;
+    }
+    drop(tile_tx);
+    writer.write(tile_rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{append_reader::AppendReader, mvt};
+
+    fn vector_tile(layer: &str) -> Vec<u8> {
+        let tile = mvt::VectorTile {
+            layers: vec![mvt::Layer {
+                version: 2,
+                name: layer.to_string(),
+                features: vec![mvt::Feature {
+                    id: Some(1),
+                    tags: vec![],
+                    r#type: Some(1),
+                    geometry: vec![9, 4096, 4096],
+                }],
+                keys: vec![],
+                values: vec![],
+                extent: Some(4096),
+            }],
+        };
+        mvt::encode(&tile, false).unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_vector_layers_collected_from_the_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.pmtiles");
+        let tiles = vec![
+            (Tile::new(0, 0, 0), vector_tile("water")),
+            (Tile::new(1, 0, 0), vector_tile("roads")),
+        ];
+        write_tiles(&output, "mvt", tiles).unwrap();
+
+        let reader = AppendReader::new(&output).await.unwrap();
+        let metadata = reader.metadata_json().await.unwrap();
+        let layers = metadata["vector_layers"].as_array().unwrap();
+        let ids: Vec<_> = layers.iter().map(|l| l["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["roads", "water"]);
+        assert_eq!(
+            reader.get_tile(&Tile::new(1, 0, 0)).await.unwrap(),
+            Some(vector_tile("roads"))
+        );
+    }
+
+    #[test]
+    fn removes_stale_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.pmtiles");
+        let sidecar = validators::sidecar_path(&output);
+        std::fs::write(&sidecar, "0/0/0\t\"old\"\t\n").unwrap();
+        let sources = validators::sources_sidecar_path(&output);
+        std::fs::write(&sources, "0/0/0\t1\n").unwrap();
+        let skipped = validators::skipped_sidecar_path(&output);
+        std::fs::write(&skipped, "0/0/0\n").unwrap();
+        write_tiles(&output, "png", vec![(Tile::new(0, 0, 0), vec![1])]).unwrap();
+        assert!(output.exists());
+        assert!(!sidecar.exists());
+        assert!(!sources.exists());
+        assert!(!skipped.exists());
+    }
+
+    #[test]
+    fn converts_tile_types() {
+        for tile_type in [
+            TileType::Png,
+            TileType::Jpeg,
+            TileType::Webp,
+            TileType::Avif,
+            TileType::Mvt,
+        ] {
+            let ext = tile_type_to_str(tile_type).unwrap();
+            assert_eq!(str_to_tile_type(ext).unwrap(), tile_type);
+        }
+        assert!(tile_type_to_str(TileType::Unknown).is_err());
+        assert!(str_to_tile_type("php").is_err());
+    }
+}